@@ -6,33 +6,720 @@
 #include <stdint.h>
 #include <stdlib.h>
 
+/**
+ * 4GB worth of points. If needed, can safely be increased.
+ */
+#define AOLDAQ_DEFAULT_FIFO_CAPACITY (4 * 268435456)
+
+/**
+ * "AOLD", little endian.
+ */
+#define AOLDAQ_SHM_MAGIC 1145851713
+
+#define AOLDAQ_SHM_VERSION 1
+
+/**
+ * Status returned by devices for operations they don't support.
+ */
+#define AOLDAQ_UNSUPPORTED -1
+
+/**
+ * How the frames of a channel are combined before they are returned.
+ */
+typedef enum {
+  /**
+   * Every frame is returned as is.
+   */
+  AOLDAQ_AVERAGE_NONE,
+  /**
+   * Mean of every `count` frames.
+   */
+  AOLDAQ_AVERAGE_MEAN,
+  /**
+   * Sum of every `count` frames, saturating at `u32::MAX`.
+   */
+  AOLDAQ_AVERAGE_SUM,
+  /**
+   * Maximum of every pixel over `count` frames.
+   */
+  AOLDAQ_AVERAGE_MAX,
+  /**
+   * Exponential moving average, weighting each new frame by `1 / count`.
+   * Returns a frame for every frame acquired.
+   */
+  AOLDAQ_AVERAGE_ROLLING,
+} aoldaq_average;
+
+/**
+ * How the samples of a bin become a pixel.
+ */
+typedef enum {
+  /**
+   * Every sample is a pixel.
+   */
+  AOLDAQ_BIN_MODE_NONE,
+  /**
+   * Sum of the samples, saturating at `u32::MAX`.
+   */
+  AOLDAQ_BIN_MODE_SUM,
+  /**
+   * Mean of the samples, rounded to the nearest integer.
+   */
+  AOLDAQ_BIN_MODE_MEAN,
+  AOLDAQ_BIN_MODE_MAX,
+  /**
+   * Keeps the first sample of each bin.
+   */
+  AOLDAQ_BIN_MODE_DECIMATE,
+} aoldaq_bin_mode;
+
+/**
+ * Events delivered to the registered callbacks.
+ */
+typedef enum {
+  /**
+   * The software FIFO of the channel crossed the callback threshold. The
+   * value is the amount of points in the FIFO.
+   */
+  AOLDAQ_EVENT_DATA_READY,
+  AOLDAQ_EVENT_STARTED,
+  AOLDAQ_EVENT_STOPPED,
+  AOLDAQ_EVENT_TRIGGERED,
+  /**
+   * The finite acquisition delivered all of its points.
+   */
+  AOLDAQ_EVENT_DONE,
+  /**
+   * A device operation failed on the channel. The value is the device status.
+   */
+  AOLDAQ_EVENT_ERROR,
+  /**
+   * A point with marker bits reached the FIFO of the channel. The value is
+   * its marker bits.
+   */
+  AOLDAQ_EVENT_MARKER,
+} aoldaq_event;
+
 typedef enum {
   AOLDAQ_MODE_NI_FPGA,
   AOLDAQ_MODE_RANDOM,
+  /**
+   * Device supplied by the user, through `Aoldaq::with_device` or a vtable.
+   */
+  AOLDAQ_MODE_CUSTOM,
+  /**
+   * Running count on every channel, to check the data with `CounterVerifier`.
+   */
+  AOLDAQ_MODE_COUNTER,
 } aoldaq_mode;
 
+/**
+ * What the producer does when the FIFO of a reader is full.
+ */
+typedef enum {
+  /**
+   * Waits for the reader, like for the FIFO of the channel, but for at most
+   * 100 ms (`STALL_TIMEOUT`) at a time, so a stuck reader can't overflow the
+   * device FIFOs. Past that, the reader drops points until a whole block fits
+   * again. A slow reader still stalls the acquisition, so keep it for the
+   * readers that can't lose data.
+   */
+  AOLDAQ_OVERFLOW_BLOCK,
+  /**
+   * Drops the points that don't fit, and goes on. The reader sees the gap
+   * in `AoldaqBlockInfo::first_point`.
+   */
+  AOLDAQ_OVERFLOW_DROP,
+} aoldaq_overflow;
+
+/**
+ * How an acquisition started with `aoldaq_start` decides where its data begins.
+ * Except with markers, every channel begins at the same word of the device
+ * FIFOs, which are assumed to be sampled together.
+ */
+typedef enum {
+  /**
+   * Data flows to the FIFOs as soon as the acquisition is started.
+   */
+  AOLDAQ_TRIGGER_MODE_IMMEDIATE,
+  /**
+   * Data flows after `aoldaq_trigger` is called.
+   */
+  AOLDAQ_TRIGGER_MODE_SOFTWARE,
+  /**
+   * Data flows after the device reports its hardware trigger (register or IRQ).
+   * The trigger is polled between reads of the first device FIFO, so data
+   * starts on the block boundary after it fired, up to a block late. Use
+   * `pre_trigger` to keep the points before.
+   */
+  AOLDAQ_TRIGGER_MODE_FPGA,
+  /**
+   * Each channel starts on its first sample for which `sample & mask == value`.
+   */
+  AOLDAQ_TRIGGER_MODE_MARKER,
+} aoldaq_trigger_mode;
+
 typedef struct aoldaq_t aoldaq_t;
 
+/**
+ * Extra consumer of a channel, created with `Aoldaq::add_reader`. It has its
+ * own FIFO, so it can be read from any thread, and the channel stops feeding
+ * it once dropped.
+ */
+typedef struct aoldaq_reader_t aoldaq_reader_t;
+
+/**
+ * Reading end of a shared-memory ring, attached from any process with
+ * `AoldaqShmReader::open` or `aoldaq_shm_open`.
+ */
+typedef struct aoldaq_shm_reader_t aoldaq_shm_reader_t;
+
+/**
+ * Checks that a stream of points counts up by one, wrapping around at
+ * `u32::MAX`. The stream may be fed in chunks of any size. The first point
+ * sets where the count starts.
+ */
+typedef struct aoldaq_verifier_t aoldaq_verifier_t;
+
 typedef struct {
+  /**
+   * `sizeof` the struct, as seen by the caller. Structs from another version
+   * of aoldaq.h are rejected instead of misread.
+   */
+  uintptr_t struct_size;
   const char *bitfile;
   const char *signature;
   const char *resource;
   uint32_t attribute;
+  /**
+   * Address of each device FIFO. If null, FIFO `i` is at address `i`.
+   */
   const uint32_t *addrs;
+  /**
+   * IRQ number asserted by the bitfile when the acquisition should start.
+   * Only used with `AoldaqTriggerMode::Fpga` and a null `trigger_register`.
+   */
+  uint32_t trigger_irq;
+  /**
+   * Optional address of a register that becomes non-zero when the acquisition
+   * should start. Takes precedence over `trigger_irq`.
+   */
+  const uint32_t *trigger_register;
+  /**
+   * Optional address of a U64 indicator with the FPGA tick count, read after
+   * every block to timestamp it.
+   */
+  const uint32_t *tick_counter;
+  /**
+   * Depth of the host side of the DMA FIFOs, in points. Zero keeps the
+   * NiFpga default.
+   */
+  uintptr_t fifo_depth;
+  /**
+   * The DMA FIFOs hold U64 words, like photon events, each read as two
+   * points, low half first.
+   */
+  bool u64_fifos;
 } NiFpgaArgs;
 
 typedef struct {
+  aoldaq_trigger_mode mode;
+  /**
+   * Amount of points per channel from before the trigger that are kept and
+   * delivered ahead of the triggered data. Zero discards everything.
+   */
+  uintptr_t pre_trigger;
+  uint32_t marker_value;
+  uint32_t marker_mask;
+} aoldaq_trigger_args_t;
+
+/**
+ * Device implemented in C. Every function is called with `user_data`.
+ *
+ * The functions are called concurrently from the acquisition threads, one per
+ * channel, so they must be thread-safe.
+ */
+typedef struct {
+  void *user_data;
+  /**
+   * Fills `buf` with up to `n` points of `channel`, blocking as needed, and
+   * stores the amount of points read in `read`. Returns 0 on success, or a
+   * device status reported through the `Error` event otherwise. With binning,
+   * short reads should hold whole bins, as the samples of an incomplete bin
+   * are dropped, and in photon counting, whole events.
+   */
+  int32_t (*read_into)(void *user_data, uintptr_t channel, uint32_t *buf, uintptr_t n, uintptr_t *read);
+  /**
+   * Optional. Stores the amount of points available in the hardware buffer of
+   * `channel` in `available`, returning false if unknown.
+   */
+  bool (*poll)(void *user_data, uintptr_t channel, uintptr_t *available);
+  /**
+   * Optional. Stores whether the hardware trigger fired in `fired`, returning
+   * 0 on success. If null, the trigger fires immediately.
+   */
+  int32_t (*poll_trigger)(void *user_data, bool *fired);
+  /**
+   * Optional. Stores the device tick count in `ticks`, returning false if
+   * there is none.
+   */
+  bool (*ticks)(void *user_data, uint64_t *ticks);
+  /**
+   * Optional. Called once when the instance is destroyed.
+   */
+  void (*destroy)(void *user_data);
+  /**
+   * Optional. Discards the points in the hardware buffer of `channel`, storing
+   * how many were discarded in `flushed`. Returns 0 on success.
+   */
+  int32_t (*flush)(void *user_data, uintptr_t channel, uintptr_t *flushed);
+} aoldaq_device_t;
+
+typedef struct {
+  /**
+   * Lowest bit and width of the channel in the U64 event words, sent as
+   * two points, low half first.
+   */
+  uint32_t channel_shift;
+  uint32_t channel_bits;
+  /**
+   * Lowest bit and width of the timestamp, which wraps around.
+   */
+  uint32_t time_shift;
+  uint32_t time_bits;
+  /**
+   * Optional channel of the pixel clock. If null, pixels last `pixel_time`
+   * from the start of their line.
+   */
+  const uint32_t *pixel_channel;
+  uint64_t pixel_time;
+  uint32_t line_channel;
+  /**
+   * Optional channel of the frame clock. If null, frames end after `height` lines.
+   */
+  const uint32_t *frame_channel;
+  /**
+   * Optional channel of the laser sync, needed by the lifetime histograms.
+   */
+  const uint32_t *sync_channel;
+  /**
+   * Bins of the lifetime histogram of each pixel, read with
+   * `aoldaq_get_lifetime`. Zero doesn't make them.
+   */
+  uintptr_t lifetime_bins;
+  /**
+   * Width of the lifetime bins, in timestamp units.
+   */
+  uint64_t lifetime_bin_width;
+} aoldaq_photon_args_t;
+
+typedef struct {
+  /**
+   * `sizeof` the struct, as seen by the caller. Structs from another version
+   * of aoldaq.h are rejected instead of misread.
+   */
+  uintptr_t struct_size;
+  /**
+   * Pixels per line.
+   */
+  uintptr_t width;
+  /**
+   * Lines per frame.
+   */
+  uintptr_t height;
+  /**
+   * Odd lines are scanned backwards, so they are reversed.
+   */
+  bool bidirectional;
+  /**
+   * Shift of the reversed lines, in pixels. Fractions of a pixel are
+   * interpolated. Positive values move them to the right.
+   */
+  float phase;
+  /**
+   * Estimates the phase from the frames themselves, starting from `phase`.
+   */
+  bool auto_phase;
+  aoldaq_average average;
+  /**
+   * Amount of frames averaged. Zero counts as one.
+   */
+  uintptr_t average_count;
+  /**
+   * Optional folder where the raw frames of channel `i` are appended to
+   * `channel<i>.bin`, as little endian `uint32_t`, before any correction.
+   */
+  const char *record;
+  /**
+   * Frames start on the points for which `point & sync_mask == sync_value`,
+   * instead of every `width * height` points. Zero doesn't sync.
+   */
+  uint32_t sync_mask;
+  uint32_t sync_value;
+  /**
+   * The marked point is a sentinel word ahead of the frame, and is dropped.
+   */
+  bool sync_sentinel;
+  /**
+   * Matches the marker bits of unpacked channels instead of the points.
+   */
+  bool sync_markers;
+  /**
+   * Optional photon counting settings. If not null, the points are
+   * time-tagged events, counted into the pixels of the frames.
+   */
+  const aoldaq_photon_args_t *photon;
+} aoldaq_frame_args_t;
+
+/**
+ * Binning of a channel.
+ */
+typedef struct {
+  aoldaq_bin_mode mode;
+  /**
+   * Samples per pixel. Must divide the block size.
+   */
+  uintptr_t factor;
+} aoldaq_binning_t;
+
+/**
+ * Bit field of the words of a device FIFO, delivered as a logical channel.
+ */
+typedef struct {
+  /**
+   * Device FIFO the words come from.
+   */
+  uintptr_t fifo;
+  /**
+   * Lowest bit of the field.
+   */
+  uint32_t shift;
+  /**
+   * Width of the field, from 1 to 32 bits.
+   */
+  uint32_t bits;
+} aoldaq_field_t;
+
+/**
+ * Bins and window of the histogram of every channel.
+ */
+typedef struct {
+  uintptr_t bins;
+  /**
+   * Range of the bins. Points below `min` count in the first bin, and
+   * points from `max` up in the last.
+   */
+  uint64_t min;
+  uint64_t max;
+  /**
+   * Points in the sliding window, rounded up to whole blocks.
+   */
+  uintptr_t window;
+  /**
+   * Points from this value up are saturated.
+   */
+  uint32_t saturation;
+} aoldaq_histogram_t;
+
+/**
+ * Settings of `aoldaq_create_instance`. Since `struct_size` was added in
+ * front, structs laid out for older versions are rejected: set it to
+ * `sizeof(aoldaq_args_t)`, and likewise in the nested structs that have one.
+ */
+typedef struct {
+  /**
+   * `sizeof` the struct, as seen by the caller. Structs from another version
+   * of aoldaq.h are rejected instead of misread.
+   */
+  uintptr_t struct_size;
   uintptr_t block_size;
   uintptr_t n_channels;
   aoldaq_mode mode;
   const NiFpgaArgs *nifpga;
+  /**
+   * Optional trigger configuration. If null, the acquisition starts immediately.
+   */
+  const aoldaq_trigger_args_t *trigger;
+  /**
+   * Capacity of the software FIFO of each channel, in points. Zero keeps the
+   * default of 1G points.
+   */
+  uintptr_t fifo_capacity;
+  /**
+   * Device of `AOLDAQ_MODE_CUSTOM`. The vtable is copied, and its `destroy`
+   * function is called when the instance is destroyed, or right away if the
+   * instance couldn't be created, unless `struct_size` was wrong.
+   */
+  const aoldaq_device_t *device;
+  /**
+   * Optional frame settings, needed by `aoldaq_get_frame`.
+   */
+  const aoldaq_frame_args_t *frame;
+  /**
+   * Binning of each channel, or a single one for all of them. Null or zero
+   * `n_binning` doesn't bin.
+   */
+  const aoldaq_binning_t *binning;
+  uintptr_t n_binning;
+  /**
+   * Optional bit field of each channel, `n_channels` of them, to feed
+   * several channels from one device FIFO. If null, each channel is the
+   * whole words of the device FIFO with its index.
+   */
+  const aoldaq_field_t *fields;
+  /**
+   * Optional mask of the marker bits of each device FIFO, `n_markers` of
+   * them. Only used with `fields`.
+   */
+  const uint32_t *markers;
+  uintptr_t n_markers;
+  /**
+   * Optional histogram settings, needed by `aoldaq_get_histogram`.
+   */
+  const aoldaq_histogram_t *histogram;
+  /**
+   * Latest points of each channel kept for `aoldaq_get_preview`. Zero
+   * keeps none.
+   */
+  uintptr_t preview;
+  /**
+   * Optional name of the shared-memory rings that other processes can read
+   * the channels from, like `/aoldaq`, with `aoldaq_shm_open`. Channel `i`
+   * goes to `<shm_name>.<i>`. Null doesn't write any.
+   */
+  const char *shm_name;
+  /**
+   * Points in the ring of each channel. Zero keeps the default of 4M points.
+   */
+  uintptr_t shm_capacity;
 } aoldaq_args_t;
 
 /**
- * Creates an AOLDAQ instance.
+ * Describes a block of points pushed by a producer thread, as returned by the
+ * block-oriented read API.
+ */
+typedef struct {
+  /**
+   * Monotonic number of the device read the points came from. Lost
+   * information is reported as `u64::MAX`.
+   */
+  uint64_t sequence;
+  /**
+   * Index, in the stream of every point read from the device on this
+   * channel, of the first returned point. A jump between two blocks means
+   * points were dropped in between.
+   */
+  uint64_t first_point;
+  /**
+   * Amount of returned points.
+   */
+  uintptr_t len;
+  /**
+   * Host time at which the device read finished, in nanoseconds since the
+   * Unix epoch.
+   */
+  uint64_t timestamp_ns;
+  /**
+   * FPGA tick count read right after the block, if the device provides one.
+   */
+  uint64_t fpga_ticks;
+  bool has_fpga_ticks;
+} aoldaq_block_info_t;
+
+/**
+ * Describes a frame returned by `Aoldaq::get_frame_into`.
+ */
+typedef struct {
+  /**
+   * Number of the frame on its channel, counting from zero.
+   */
+  uint64_t number;
+  /**
+   * Shift applied to the reversed lines, in pixels.
+   */
+  float phase;
+  /**
+   * Amount of acquired frames combined into this one.
+   */
+  uint32_t averaged;
+  /**
+   * Times the frames of the channel were re-aligned on their markers after
+   * lost or extra points, so far.
+   */
+  uint64_t resyncs;
+} aoldaq_frame_info_t;
+
+/**
+ * Statistics of the points in the window of a histogram.
+ */
+typedef struct {
+  /**
+   * Points in the window. The others are zero when there's none.
+   */
+  uint64_t points;
+  uint32_t min;
+  uint32_t max;
+  double mean;
+  /**
+   * Points at or above the saturation value.
+   */
+  uint64_t saturated;
+} aoldaq_histogram_stats_t;
+
+/**
+ * Marker found in the points of a channel.
+ */
+typedef struct {
+  /**
+   * Index of the point in the stream of the channel, like
+   * `AoldaqBlockInfo::first_point`.
+   */
+  uint64_t point;
+  /**
+   * Marker bits set in the word of the point.
+   */
+  uint32_t flags;
+} aoldaq_marker_t;
+
+/**
+ * Points copied out of the preview of a channel.
+ */
+typedef struct {
+  /**
+   * Index of the first point copied in the stream of the channel, like
+   * `AoldaqBlockInfo::first_point`.
+   */
+  uint64_t first_point;
+  uintptr_t len;
+} aoldaq_preview_t;
+
+/**
+ * Counters of a channel since the instance was created.
+ */
+typedef struct {
+  /**
+   * Points read from the device.
+   */
+  uint64_t acquired;
+  /**
+   * Points pushed to the software FIFO. Points read before the trigger or
+   * past the end of a finite acquisition are not delivered.
+   */
+  uint64_t delivered;
+  /**
+   * Device reads.
+   */
+  uint64_t blocks;
+  /**
+   * Times the software FIFO was full and the producer had to wait for the
+   * consumer. While it waits, the hardware buffer fills up.
+   */
+  uint64_t overflows;
+  /**
+   * Failed device operations.
+   */
+  uint64_t errors;
+  /**
+   * Status of the last failed device operation.
+   */
+  int32_t last_error;
+  /**
+   * Points currently in the software FIFO.
+   */
+  uintptr_t fifo_fill;
+} aoldaq_stats_t;
+
+/**
+ * Callback signature of the C API. Called from the dispatcher thread with the
+ * `user_data` given at registration.
+ */
+typedef void (*aoldaq_callback_t)(void *user_data, uintptr_t channel, aoldaq_event event, int64_t value);
+
+/**
+ * What `CounterVerifier` found in the points it checked.
+ */
+typedef struct {
+  /**
+   * Points checked.
+   */
+  uint64_t checked;
+  /**
+   * Times the count jumped forward.
+   */
+  uint64_t gaps;
+  /**
+   * Points skipped by the forward jumps, less the ones that turned up late.
+   */
+  uint64_t missing;
+  /**
+   * Points that came behind the highest one so far, late or repeated.
+   */
+  uint64_t reorders;
+  /**
+   * Whether a gap or reordering was found, in which case the `first_` fields
+   * describe the first one.
+   */
+  bool has_gap;
+  /**
+   * Index, among the checked points, of the first unexpected point.
+   */
+  uint64_t first_gap;
+  /**
+   * Value expected at `first_gap`.
+   */
+  uint32_t first_gap_expected;
+  /**
+   * Value found at `first_gap`.
+   */
+  uint32_t first_gap_found;
+} aoldaq_verify_report_t;
+
+/**
+ * Header at the start of a shared-memory ring.
+ */
+typedef struct {
+  /**
+   * `SHM_MAGIC`, written last.
+   */
+  uint32_t magic;
+  /**
+   * `SHM_VERSION`.
+   */
+  uint32_t version;
+  /**
+   * Bytes before the first point.
+   */
+  uint32_t header_size;
+  uint32_t channel;
+  /**
+   * Points in the ring.
+   */
+  uint64_t capacity;
+  /**
+   * Points written so far.
+   */
+  uint64_t written;
+  /**
+   * Points being written, from `written` up to here.
+   */
+  uint64_t reserved;
+  /**
+   * Non-zero once the acquisition is gone.
+   */
+  uint32_t closed;
+} aoldaq_shm_header_t;
+
+/**
+ * Creates an AOLDAQ instance. Returns null if it couldn't be created.
  */
 aoldaq_t *aoldaq_create_instance(const aoldaq_args_t *args);
 
+/**
+ * Creates an AOLDAQ instance from the config file at `path`. Returns null if
+ * it couldn't be created.
+ */
+aoldaq_t *aoldaq_create_instance_from_config(const char *path);
+
 /**
  * Destroys an AOLDAQ instance, stopping the threads and dropping everything.
  */
@@ -43,12 +730,36 @@ void aoldaq_destroy_instance(aoldaq_t *instance);
  */
 void aoldaq_flush_fifo(aoldaq_t *instance, uintptr_t channel);
 
+/**
+ * Copies at most `n` points of the oldest block of `channel` into `buf`, and
+ * describes them in `info`: sequence number, position in the stream and
+ * acquisition time. Never mixes points of two blocks. Returns the amount of
+ * points copied, 0 if there was no data.
+ * Assumes that `buf` is a preallocated buffer capable of receiving `n` points.
+ */
+uintptr_t aoldaq_get_block(aoldaq_t *instance,
+                           uintptr_t channel,
+                           uintptr_t n,
+                           uint32_t *buf,
+                           aoldaq_block_info_t *info);
+
 /**
  * Tries to return `n` `uint32_t`s of data, returning 0 if unsuccesufl.
  * Assumes that `buf` is a preallocated buffer capable of receiving all the data.
  */
 uintptr_t aoldaq_get_data(aoldaq_t *instance, uintptr_t channel, uintptr_t n, uint32_t *buf);
 
+/**
+ * Waits for at most `timeout` milliseconds until every channel has `n` points,
+ * then copies exactly `n` points of each channel into `bufs[channel]`.
+ * Returns `n`, or 0 if the timeout expired, in which case nothing is consumed.
+ * Assumes that `bufs` holds one preallocated buffer per channel.
+ */
+uintptr_t aoldaq_get_data_all(aoldaq_t *instance,
+                              uintptr_t n,
+                              uint32_t *const *bufs,
+                              uint64_t timeout);
+
 /**
  * Tries to return `n` `uint32_t`s of data, blocking for at most `timeout` milliseconds
  * if there's not enough data. Returns 0 if unsuccessful.
@@ -60,19 +771,271 @@ uintptr_t aoldaq_get_data_blocking(aoldaq_t *instance,
                                    uint32_t *buf,
                                    uint64_t timeout);
 
+/**
+ * Same as `aoldaq_get_data_all`, but interleaves the channels into a single
+ * buffer: `buf[i * n_channels + channel]` is point `i` of `channel`.
+ * Assumes that `buf` is a preallocated buffer of `n * n_channels` points.
+ */
+uintptr_t aoldaq_get_data_interleaved(aoldaq_t *instance,
+                                      uintptr_t n,
+                                      uint32_t *buf,
+                                      uint64_t timeout);
+
+/**
+ * Waits for at most `timeout` milliseconds for the next frame of `channel`,
+ * corrected and averaged, then copies it to `buf`, which must hold
+ * `width * height` points, and fills
+ * `info` if it isn't null. Returns false if the timeout expired or if the
+ * instance was created without frame settings.
+ */
+bool aoldaq_get_frame(aoldaq_t *instance,
+                      uintptr_t channel,
+                      uint32_t *buf,
+                      uint64_t timeout,
+                      aoldaq_frame_info_t *info);
+
+/**
+ * Copies to `bins`, which holds `n` counts, the histogram of the latest points
+ * read from `channel`, and fills `stats` if it isn't null, without consuming
+ * any data. Returns false if the instance was created without histogram
+ * settings.
+ */
+bool aoldaq_get_histogram(aoldaq_t *instance,
+                          uintptr_t channel,
+                          uint64_t *bins,
+                          uintptr_t n,
+                          aoldaq_histogram_stats_t *stats);
+
+/**
+ * Copies to `buf`, which holds `n` counts, the lifetime histograms of the last
+ * frame of `channel` returned by `aoldaq_get_frame`, `lifetime_bins` for each
+ * pixel. Returns the amount of counts copied, zero without photon counting.
+ */
+uintptr_t aoldaq_get_lifetime(aoldaq_t *instance, uintptr_t channel, uint32_t *buf, uintptr_t n);
+
+/**
+ * Copies to `buf`, which holds `n` markers, the markers of the points of
+ * `channel` still in its FIFO, oldest first, without removing them. Returns the
+ * amount of markers copied.
+ */
+uintptr_t aoldaq_get_markers(aoldaq_t *instance,
+                             uintptr_t channel,
+                             aoldaq_marker_t *buf,
+                             uintptr_t n);
+
 /**
  * Returns the underlying NiFPGA session object.
  */
 uint32_t aoldaq_get_nifpga_session(aoldaq_t *instance);
 
+/**
+ * Returns the shift of the reversed lines of `channel`, in pixels.
+ */
+float aoldaq_get_phase(aoldaq_t *instance, uintptr_t channel);
+
+/**
+ * Copies to `buf`, which holds `n` points, the latest points read from
+ * `channel`, without taking them from its FIFO. Never blocks the acquisition:
+ * the preview is lossy, and is empty if the instance keeps none.
+ */
+aoldaq_preview_t aoldaq_get_preview(aoldaq_t *instance,
+                                    uintptr_t channel,
+                                    uint32_t *buf,
+                                    uintptr_t n);
+
+/**
+ * Copies to `buf`, which must hold `width * height` points, the latest whole
+ * raw frame in the preview of `channel`, and stores its number in `number` if
+ * it isn't null. Returns false if there's no whole frame in the preview.
+ */
+bool aoldaq_get_preview_frame(aoldaq_t *instance,
+                              uintptr_t channel,
+                              uint32_t *buf,
+                              uint64_t *number);
+
+/**
+ * Returns the counters of `channel` since the instance was created.
+ */
+aoldaq_stats_t aoldaq_get_stats(aoldaq_t *instance, uintptr_t channel);
+
+/**
+ * Returns whether the current finite acquisition is done.
+ */
+bool aoldaq_is_done(aoldaq_t *instance);
+
+/**
+ * Returns whether the trigger of the current acquisition has fired.
+ */
+bool aoldaq_is_triggered(aoldaq_t *instance);
+
+/**
+ * Reads the U32 register at `address` into `value`. Returns 0 on success, the
+ * device status otherwise, or `AOLDAQ_UNSUPPORTED` if the device has no registers.
+ */
+int32_t aoldaq_read_register(aoldaq_t *instance, uint32_t address, uint32_t *value);
+
+/**
+ * Adds a consumer of `channel` with its own FIFO of `capacity` points, which
+ * gets a copy of every point delivered to the channel from now on, and can be
+ * read from any thread. With `AOLDAQ_OVERFLOW_DROP`, it loses points instead of
+ * stalling the acquisition when it falls behind. With `AOLDAQ_OVERFLOW_BLOCK`,
+ * it stalls it for at most 100 ms at a time, then loses points until it
+ * catches up. Returns null if `channel` doesn't exist. Must be destroyed with `aoldaq_reader_destroy`.
+ */
+aoldaq_reader_t *aoldaq_reader_create(aoldaq_t *instance,
+                                      uintptr_t channel,
+                                      uintptr_t capacity,
+                                      aoldaq_overflow overflow);
+
+void aoldaq_reader_destroy(aoldaq_reader_t *reader);
+
+/**
+ * Like `aoldaq_get_block`, from the FIFO of the reader.
+ */
+uintptr_t aoldaq_reader_get_block(aoldaq_reader_t *reader,
+                                  uintptr_t n,
+                                  uint32_t *buf,
+                                  aoldaq_block_info_t *info);
+
+/**
+ * Copies at most `n` points from the FIFO of the reader into `buf`, waiting
+ * at most `timeout` milliseconds for `n` points. Returns the amount copied.
+ */
+uintptr_t aoldaq_reader_get_data(aoldaq_reader_t *reader,
+                                 uintptr_t n,
+                                 uint32_t *buf,
+                                 uint64_t timeout);
+
+/**
+ * Returns the amount of points the reader lost because its FIFO was full.
+ */
+uint64_t aoldaq_reader_get_dropped(aoldaq_reader_t *reader);
+
+uintptr_t aoldaq_reader_get_fifo_size(aoldaq_reader_t *reader);
+
+/**
+ * Registers `callback` to be called from a dispatcher thread whenever the
+ * software FIFO of `channel` crosses `threshold` points, on state changes,
+ * and on the errors and markers of `channel`. Returns an id for
+ * `aoldaq_unregister_callback`.
+ * Callbacks can register and unregister callbacks, including themselves, but
+ * must not destroy the instance.
+ */
+uintptr_t aoldaq_register_callback(aoldaq_t *instance,
+                                   uintptr_t channel,
+                                   uintptr_t threshold,
+                                   aoldaq_callback_t callback,
+                                   void *user_data);
+
+/**
+ * Sets the shift of the reversed lines of bidirectional frames, in pixels.
+ */
+void aoldaq_set_phase(aoldaq_t *instance, float phase);
+
+void aoldaq_shm_close(aoldaq_shm_reader_t *reader);
+
+/**
+ * Returns the amount of points waiting to be read.
+ */
+uintptr_t aoldaq_shm_get_available(aoldaq_shm_reader_t *reader);
+
+/**
+ * Returns the amount of points overwritten before being read.
+ */
+uint64_t aoldaq_shm_get_lost(aoldaq_shm_reader_t *reader);
+
+/**
+ * Returns the index, in the stream of the ring, of the next point to read.
+ */
+uint64_t aoldaq_shm_get_position(aoldaq_shm_reader_t *reader);
+
+/**
+ * Returns whether the instance that wrote the ring is gone.
+ */
+bool aoldaq_shm_is_closed(aoldaq_shm_reader_t *reader);
+
+/**
+ * Attaches to the shared-memory ring named `name`, like `/aoldaq.0`, written
+ * by an instance in another process. Doesn't need an instance of its own.
+ * Returns null if there's no such ring. Must be closed with `aoldaq_shm_close`.
+ */
+aoldaq_shm_reader_t *aoldaq_shm_open(const char *name);
+
+/**
+ * Copies the next points of the ring, at most `n`, into `buf`, and returns
+ * how many. Never waits. Points overwritten before being read are skipped.
+ */
+uintptr_t aoldaq_shm_read(aoldaq_shm_reader_t *reader, uintptr_t n, uint32_t *buf);
+
 /**
  * Unparks the threads and starts the acquisition.
  */
 void aoldaq_start(aoldaq_t *instance);
 
+/**
+ * Starts a finite acquisition of `n` points per channel. The threads stop on
+ * their own once every channel delivered its points.
+ */
+void aoldaq_start_finite(aoldaq_t *instance, uintptr_t n);
+
+/**
+ * Starts a finite acquisition of `n` frames per channel, as in
+ * `Aoldaq::start_finite_frames`. Returns false, without starting, if the
+ * instance has no frame settings.
+ */
+bool aoldaq_start_finite_frames(aoldaq_t *instance, uintptr_t n);
+
 /**
  * Parks the threads, pausing the acquisition.
  */
 void aoldaq_stop(aoldaq_t *instance);
 
+/**
+ * Fires the trigger by software, letting the acquisition data reach the FIFOs.
+ */
+void aoldaq_trigger(aoldaq_t *instance);
+
+/**
+ * Unregisters a callback. Once this returns, the callback will not be called
+ * again and is not running, unless this is called from within a callback:
+ * then it doesn't wait, as the running callback could be the caller. Returns
+ * false if the id is unknown.
+ */
+bool aoldaq_unregister_callback(aoldaq_t *instance, uintptr_t id);
+
+/**
+ * Checks the next `n` points of the stream, from `data`.
+ */
+void aoldaq_verifier_check(aoldaq_verifier_t *verifier, const uint32_t *data, uintptr_t n);
+
+/**
+ * Creates a verifier for the data of `AOLDAQ_MODE_COUNTER`, or of any other
+ * source that counts up by one on every point.
+ */
+aoldaq_verifier_t *aoldaq_verifier_create(void);
+
+void aoldaq_verifier_destroy(aoldaq_verifier_t *verifier);
+
+/**
+ * Returns what the verifier found so far.
+ */
+aoldaq_verify_report_t aoldaq_verifier_get_report(aoldaq_verifier_t *verifier);
+
+/**
+ * Forgets everything checked so far.
+ */
+void aoldaq_verifier_reset(aoldaq_verifier_t *verifier);
+
+/**
+ * Blocks for at most `timeout` milliseconds until the current finite acquisition
+ * is done. Returns whether it is done.
+ */
+bool aoldaq_wait_done(aoldaq_t *instance, uint64_t timeout);
+
+/**
+ * Writes `value` to the U32 register at `address`. Returns 0 on success, the
+ * device status otherwise, or `AOLDAQ_UNSUPPORTED` if the device has no registers.
+ */
+int32_t aoldaq_write_register(aoldaq_t *instance, uint32_t address, uint32_t value);
+
 #endif /* AOLDAQ_H */
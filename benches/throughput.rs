@@ -0,0 +1,21 @@
+//! Runs the default sweep of `aoldaq bench`, printing the results as JSON.
+//! Build with `--features mock-nifpga` to include the mock NiFpga runtime.
+
+#[path = "../src/bin/aoldaq/bench.rs"]
+mod bench;
+
+use simplelog::{ Config, LevelFilter, TermLogger, TerminalMode };
+
+fn main() {
+    // Keeps the library logs out of the JSON
+    TermLogger::init(LevelFilter::Warn, Config::default(), TerminalMode::Stderr).unwrap_or(());
+
+    let sweep = bench::Sweep::default();
+
+    let reports = sweep.cases()
+        .iter()
+        .map(|case| bench::run(case, sweep.seconds, None).expect("Benchmark failed"))
+        .collect::<Vec<_>>();
+
+    println!("{}", bench::to_json(&reports));
+}
@@ -22,6 +22,35 @@ fn main() {
         .rename_item("Aoldaq", "aoldaq_t")
         .rename_item("AoldaqArgs", "aoldaq_args_t")
         .rename_item("AoldaqMode", "aoldaq_mode")
+        .rename_item("AoldaqTriggerMode", "aoldaq_trigger_mode")
+        .rename_item("AoldaqTriggerArgs", "aoldaq_trigger_args_t")
+        .rename_item("AoldaqBlockInfo", "aoldaq_block_info_t")
+        .rename_item("AoldaqEvent", "aoldaq_event")
+        .rename_item("AoldaqCallback", "aoldaq_callback_t")
+        .rename_item("AoldaqDeviceVtable", "aoldaq_device_t")
+        .rename_item("AoldaqStats", "aoldaq_stats_t")
+        .rename_item("AoldaqFrameArgs", "aoldaq_frame_args_t")
+        .rename_item("AoldaqFrameInfo", "aoldaq_frame_info_t")
+        .rename_item("AoldaqPhotonArgs", "aoldaq_photon_args_t")
+        .rename_item("AoldaqAverage", "aoldaq_average")
+        .rename_item("AoldaqBinMode", "aoldaq_bin_mode")
+        .rename_item("AoldaqBinning", "aoldaq_binning_t")
+        .rename_item("AoldaqField", "aoldaq_field_t")
+        .rename_item("AoldaqMarker", "aoldaq_marker_t")
+        .rename_item("AoldaqHistogram", "aoldaq_histogram_t")
+        .rename_item("AoldaqHistogramStats", "aoldaq_histogram_stats_t")
+        .rename_item("AoldaqPreview", "aoldaq_preview_t")
+        .rename_item("AoldaqOverflow", "aoldaq_overflow")
+        .rename_item("AoldaqReader", "aoldaq_reader_t")
+        .rename_item("AoldaqShmReader", "aoldaq_shm_reader_t")
+        .rename_item("AoldaqShmHeader", "aoldaq_shm_header_t")
+        .rename_item("SHM_MAGIC", "AOLDAQ_SHM_MAGIC")
+        .rename_item("SHM_VERSION", "AOLDAQ_SHM_VERSION")
+        .include_item("AoldaqShmHeader")
+        .rename_item("CounterVerifier", "aoldaq_verifier_t")
+        .rename_item("AoldaqVerifyReport", "aoldaq_verify_report_t")
+        .rename_item("DEFAULT_FIFO_CAPACITY", "AOLDAQ_DEFAULT_FIFO_CAPACITY")
+        .rename_item("UNSUPPORTED", "AOLDAQ_UNSUPPORTED")
         .generate()
         .expect("Failed to generate public C API")
         .write_to_file("aoldaq.h");
@@ -8,6 +8,7 @@
 int main(int argc, char* argv[]) {
     uint32_t addrs[2] = { 1, 2 };
     NiFpgaArgs nifpga = {
+        .struct_size = sizeof(NiFpgaArgs),
         .bitfile = "dark matter",
         .signature = "is so",
         .resource = "divine",
@@ -16,6 +17,7 @@ int main(int argc, char* argv[]) {
     };
 
     const aoldaq_args_t args = {
+        .struct_size = sizeof(aoldaq_args_t),
         .n_channels = 2,
         .block_size = 4000,
         .mode = AOLDAQ_MODE_NI_FPGA,
@@ -23,6 +25,10 @@ int main(int argc, char* argv[]) {
     };
 
     aoldaq_t *instance = aoldaq_create_instance(&args);
+    if (instance == NULL) {
+        fprintf(stderr, "Failed to create instance\n");
+        return 1;
+    }
 
     uint32_t session = aoldaq_get_nifpga_session(instance);
     printf("NiFpga Session: %d\n", session);
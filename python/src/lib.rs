@@ -0,0 +1,646 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use numpy::{ PyArray1, PyArray2, PyArray3, PyArrayMethods };
+use pyo3::exceptions::{ PyNotImplementedError, PyRuntimeError, PyTimeoutError, PyValueError };
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+use aoldaq::{ AoldaqAverage, AoldaqBuilder, AoldaqHistogram, AoldaqOverflow, AoldaqError, AoldaqMode, AoldaqTriggerMode, FrameOptions, NiFpgaOptions };
+
+fn to_py_err(e: AoldaqError) -> PyErr {
+    match e {
+        AoldaqError::InvalidArgument(_) | AoldaqError::MissingOptions(_) => PyValueError::new_err(e.to_string()),
+        AoldaqError::Unsupported(_) => PyNotImplementedError::new_err(e.to_string()),
+        _ => PyRuntimeError::new_err(e.to_string()),
+    }
+}
+
+fn duration(seconds: f64) -> PyResult<Duration> {
+    Duration::try_from_secs_f64(seconds).map_err(|_| PyValueError::new_err("Timeout must be a non-negative number of seconds"))
+}
+
+/// An acquisition instance. Use it as a context manager, so the acquisition
+/// threads and the device are released on exit:
+///
+///     with aoldaq.Aoldaq(mode="random", channels=2) as daq:
+///         daq.start()
+///         data = daq.read(0, 4000, timeout=1.0)
+#[pyclass(module = "aoldaq")]
+struct Aoldaq {
+    inner: Mutex<Option<aoldaq::Aoldaq>>,
+    /// Height and width of the frames, if set.
+    frame: Option<(usize, usize)>,
+    /// Bins of the histograms, if set.
+    histogram_bins: Option<usize>,
+    /// Points in the preview of each channel.
+    preview: usize,
+}
+
+impl Aoldaq {
+    /// Runs `f` on the instance without holding the GIL, so a thread blocked
+    /// on the instance never keeps the others from running Python code.
+    fn with<R, F>(&self, py: Python<'_>, f: F) -> PyResult<R>
+        where R: Send, F: FnOnce(&mut aoldaq::Aoldaq) -> R + Send
+    {
+        py.detach(|| self.inner.lock().unwrap().as_mut().map(f))
+            .ok_or_else(|| PyRuntimeError::new_err("The instance is closed"))
+    }
+
+    fn check_channel(&self, py: Python<'_>, channel: usize) -> PyResult<()> {
+        if channel >= self.channels(py)? {
+            return Err(PyValueError::new_err(format!("Channel {} out of range", channel)));
+        }
+
+        Ok(())
+    }
+}
+
+#[pymethods]
+impl Aoldaq {
+    #[new]
+    #[pyo3(signature = (
+        mode = "random",
+        channels = 1,
+        block_size = 4000,
+        fifo_capacity = None,
+        trigger = "immediate",
+        pre_trigger = 0,
+        marker_value = 0,
+        marker_mask = 0,
+        binning = None,
+        fields = None,
+        markers = Vec::new(),
+        bitfile = None,
+        signature = String::new(),
+        resource = String::new(),
+        addrs = Vec::new(),
+        u64_fifos = false,
+        frame_width = 0,
+        frame_height = 0,
+        bidirectional = false,
+        phase = 0.0,
+        auto_phase = false,
+        average = "none",
+        average_count = 1,
+        record = None,
+        sync_mask = 0,
+        sync_value = 0,
+        sync_sentinel = false,
+        sync_markers = false,
+        photon = None,
+        histogram = None,
+        preview = 0,
+        shm = None,
+        shm_capacity = None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        mode: &str,
+        channels: usize,
+        block_size: usize,
+        fifo_capacity: Option<usize>,
+        trigger: &str,
+        pre_trigger: usize,
+        marker_value: u32,
+        marker_mask: u32,
+        binning: Option<&str>,
+        fields: Option<&str>,
+        markers: Vec<u32>,
+        bitfile: Option<std::path::PathBuf>,
+        signature: String,
+        resource: String,
+        addrs: Vec<u32>,
+        u64_fifos: bool,
+        frame_width: usize,
+        frame_height: usize,
+        bidirectional: bool,
+        phase: f32,
+        auto_phase: bool,
+        average: &str,
+        average_count: usize,
+        record: Option<std::path::PathBuf>,
+        sync_mask: u32,
+        sync_value: u32,
+        sync_sentinel: bool,
+        sync_markers: bool,
+        photon: Option<&Bound<'_, PyDict>>,
+        histogram: Option<&Bound<'_, PyDict>>,
+        preview: usize,
+        shm: Option<&str>,
+        shm_capacity: Option<usize>,
+    ) -> PyResult<Self> {
+        let mut builder = AoldaqBuilder::new(mode.parse::<AoldaqMode>().map_err(to_py_err)?)
+            .channels(channels)
+            .block_size(block_size)
+            .trigger(trigger.parse::<AoldaqTriggerMode>().map_err(to_py_err)?)
+            .pre_trigger(pre_trigger)
+            .marker(marker_value, marker_mask);
+
+        if let Some(binning) = binning {
+            builder = builder.set("binning", binning).map_err(to_py_err)?;
+        }
+
+        if let Some(fields) = fields {
+            builder = builder.set("unpack.fields", fields).map_err(to_py_err)?;
+        }
+
+        if !markers.is_empty() {
+            let markers = markers.iter().map(|mask| mask.to_string()).collect::<Vec<_>>().join(",");
+            builder = builder.set("unpack.markers", &markers).map_err(to_py_err)?;
+        }
+
+        if let Some(fifo_capacity) = fifo_capacity {
+            builder = builder.fifo_capacity(fifo_capacity);
+        }
+
+        if let Some(bitfile) = bitfile {
+            builder = builder.nifpga(NiFpgaOptions {
+                bitfile,
+                signature,
+                resource,
+                addrs,
+                u64_fifos,
+                ..NiFpgaOptions::default()
+            });
+        }
+
+        let frame = if frame_width > 0 || frame_height > 0 {
+            builder = builder.frame(FrameOptions {
+                width: frame_width,
+                height: frame_height,
+                bidirectional,
+                phase,
+                auto_phase,
+                average: average.parse::<AoldaqAverage>().map_err(to_py_err)?,
+                average_count,
+                record,
+                sync_mask,
+                sync_value,
+                sync_sentinel,
+                sync_markers,
+                photon: None,
+            });
+
+            Some((frame_height, frame_width))
+        } else {
+            None
+        };
+
+        // Photon options, like {"line_channel": 1, "lifetime_bins": 64}, on
+        // top of the frame ones
+        if let Some(photon) = photon {
+            for (key, value) in photon.iter() {
+                let key = format!("photon.{}", key.str()?);
+                builder = builder.set(&key, &value.str()?.to_string()).map_err(to_py_err)?;
+            }
+        }
+
+        // Histogram options, like {"bins": 128, "max": 4096}, on top of the
+        // defaults
+        if let Some(histogram) = histogram {
+            builder = builder.histogram(AoldaqHistogram::default());
+
+            for (key, value) in histogram.iter() {
+                let key = format!("histogram.{}", key.str()?);
+                builder = builder.set(&key, &value.str()?.to_string()).map_err(to_py_err)?;
+            }
+        }
+
+        // Shared-memory rings of the channels, like "/aoldaq"
+        if let Some(shm) = shm {
+            builder = builder.set("shm.name", shm).map_err(to_py_err)?;
+        }
+
+        if let Some(shm_capacity) = shm_capacity {
+            builder = builder.set("shm.capacity", &shm_capacity.to_string()).map_err(to_py_err)?;
+        }
+
+        let histogram_bins = builder.get_options().histogram.map(|histogram| histogram.bins);
+        let instance = builder.preview(preview).build().map_err(to_py_err)?;
+
+        Ok(Aoldaq {
+            inner: Mutex::new(Some(instance)),
+            frame,
+            histogram_bins,
+            preview,
+        })
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    #[pyo3(signature = (*_args))]
+    fn __exit__(&self, py: Python<'_>, _args: &Bound<'_, pyo3::types::PyTuple>) -> bool {
+        self.close(py);
+        false
+    }
+
+    /// Stops the acquisition and releases the device. Does nothing if the
+    /// instance is already closed.
+    fn close(&self, py: Python<'_>) {
+        py.detach(|| drop(self.inner.lock().unwrap().take()));
+    }
+
+    #[getter]
+    fn channels(&self, py: Python<'_>) -> PyResult<usize> {
+        self.with(py, |instance| instance.get_n_channels())
+    }
+
+    #[getter]
+    fn block_size(&self, py: Python<'_>) -> PyResult<usize> {
+        self.with(py, |instance| instance.get_block_size())
+    }
+
+    /// Starts the acquisition. If `n` is given, it stops on its own after `n`
+    /// points per channel, or if `frames` is given, after that many frames.
+    #[pyo3(signature = (n = None, frames = None))]
+    fn start(&self, py: Python<'_>, n: Option<usize>, frames: Option<usize>) -> PyResult<()> {
+        if n.is_some() && frames.is_some() {
+            return Err(PyValueError::new_err("Give either n or frames, not both"));
+        }
+
+        self.with(py, |instance| match (n, frames) {
+            (Some(n), _) => {
+                instance.start_finite(n);
+                Ok(())
+            }
+            (None, Some(frames)) => instance.start_finite_frames(frames),
+            (None, None) => {
+                instance.start();
+                Ok(())
+            }
+        })?.map_err(to_py_err)
+    }
+
+    fn stop(&self, py: Python<'_>) -> PyResult<()> {
+        self.with(py, |instance| instance.stop())
+    }
+
+    fn trigger(&self, py: Python<'_>) -> PyResult<()> {
+        self.with(py, |instance| instance.trigger())
+    }
+
+    fn is_done(&self, py: Python<'_>) -> PyResult<bool> {
+        self.with(py, |instance| instance.is_done())
+    }
+
+    /// Waits for at most `timeout` seconds for the finite acquisition to end.
+    fn wait_done(&self, py: Python<'_>, timeout: f64) -> PyResult<bool> {
+        let timeout = duration(timeout)?;
+        self.with(py, |instance| instance.wait_done(timeout))
+    }
+
+    /// Amount of points waiting in the FIFO of `channel`.
+    fn fifo_size(&self, py: Python<'_>, channel: usize) -> PyResult<usize> {
+        self.check_channel(py, channel)?;
+        self.with(py, |instance| instance.get_fifo_size(channel))
+    }
+
+    /// Reads up to `n` points of `channel` into a new `uint32` array, waiting
+    /// for at most `timeout` seconds for them. The data is popped straight into
+    /// the array, which is shorter than `n` if the timeout expired.
+    #[pyo3(signature = (channel, n, timeout = 0.0))]
+    fn read<'py>(&self, py: Python<'py>, channel: usize, n: usize, timeout: f64) -> PyResult<Bound<'py, PyArray1<u32>>> {
+        let timeout = duration(timeout)?;
+        self.check_channel(py, channel)?;
+
+        let array = PyArray1::<u32>::zeros(py, n, false);
+        let buf = unsafe { array.as_slice_mut() }.unwrap();
+
+        let read = self.with(py, |instance| instance.get_data_into_blocking(channel, buf, timeout))?;
+
+        if read < n {
+            unsafe { array.resize(read)? };
+        }
+
+        Ok(array)
+    }
+
+    /// Waits for at most `timeout` seconds until every channel has `n` points,
+    /// then reads exactly `n` points of each into a `(channels, n)` array.
+    /// Raises `TimeoutError` without consuming anything if the timeout expires.
+    #[pyo3(signature = (n, timeout = 0.0))]
+    fn read_all<'py>(&self, py: Python<'py>, n: usize, timeout: f64) -> PyResult<Bound<'py, PyArray2<u32>>> {
+        let timeout = duration(timeout)?;
+        let channels = self.channels(py)?;
+
+        let array = PyArray2::<u32>::zeros(py, [channels, n], false);
+        let buf = unsafe { array.as_slice_mut() }.unwrap();
+
+        let read = self.with(py, |instance| {
+            let mut bufs = buf.chunks_mut(n.max(1)).collect::<Vec<_>>();
+            bufs.resize_with(channels, Default::default);
+            instance.get_data_all(&mut bufs[..], n, timeout)
+        })?;
+
+        if read < n {
+            return Err(PyTimeoutError::new_err(format!("Not every channel got {} points in time", n)));
+        }
+
+        Ok(array)
+    }
+
+    /// Waits for at most `timeout` seconds for the next frame of `channel`, and
+    /// returns it as a `(frame_height, frame_width)` array, with the backward
+    /// lines of bidirectional scans already reversed and shifted into place,
+    /// and averaged as set by `average` ("none", "mean", "sum", "max" or
+    /// "rolling") and `average_count`. Raises `TimeoutError` if the timeout
+    /// expires.
+    #[pyo3(signature = (channel, timeout = 0.0))]
+    fn read_frame<'py>(&self, py: Python<'py>, channel: usize, timeout: f64) -> PyResult<Bound<'py, PyArray2<u32>>> {
+        let timeout = duration(timeout)?;
+        self.check_channel(py, channel)?;
+
+        let (height, width) = self.frame
+            .ok_or_else(|| PyValueError::new_err("The instance was created without frame_width and frame_height"))?;
+
+        let array = PyArray2::<u32>::zeros(py, [height, width], false);
+        let buf = unsafe { array.as_slice_mut() }.unwrap();
+
+        self.with(py, |instance| instance.get_frame_into(channel, buf, timeout))?
+            .ok_or_else(|| PyTimeoutError::new_err("No whole frame in time"))?;
+
+        Ok(array)
+    }
+
+    /// Returns the lifetime histograms of the last frame of `channel` returned
+    /// by `read_frame`, in photon counting mode, with shape
+    /// `(frame_height, frame_width, lifetime_bins)`.
+    fn read_lifetime<'py>(&self, py: Python<'py>, channel: usize) -> PyResult<Bound<'py, PyArray3<u32>>> {
+        self.check_channel(py, channel)?;
+
+        let (height, width) = self.frame
+            .ok_or_else(|| PyValueError::new_err("The instance was created without frame_width and frame_height"))?;
+        let bins = self.with(py, |instance| instance.get_lifetime_bins())?;
+
+        let array = PyArray3::<u32>::zeros(py, [height, width, bins], false);
+        let buf = unsafe { array.as_slice_mut() }.unwrap();
+
+        self.with(py, |instance| instance.get_lifetime_into(channel, buf))?;
+
+        Ok(array)
+    }
+
+    /// Times the frames of `channel` were re-aligned on their markers, with
+    /// `sync_mask`, after lost or extra points.
+    fn frame_resyncs(&self, py: Python<'_>, channel: usize) -> PyResult<u64> {
+        self.check_channel(py, channel)?;
+        self.with(py, |instance| instance.get_frame_resyncs(channel))
+    }
+
+    /// Shift of the backward lines of bidirectional frames, in pixels. With
+    /// `auto_phase`, reads the current estimate of channel 0.
+    #[getter]
+    fn phase(&self, py: Python<'_>) -> PyResult<f32> {
+        self.with(py, |instance| instance.get_phase(0))
+    }
+
+    #[setter]
+    fn set_phase(&self, py: Python<'_>, phase: f32) -> PyResult<()> {
+        self.with(py, |instance| instance.set_phase(phase))
+    }
+
+    /// Returns the markers of the points of `channel` still in its FIFO, oldest
+    /// first, as a list of `(point, flags)` tuples.
+    fn markers(&self, py: Python<'_>, channel: usize) -> PyResult<Vec<(u64, u32)>> {
+        self.check_channel(py, channel)?;
+
+        self.with(py, |instance| {
+            let mut markers = vec![aoldaq::AoldaqMarker::default(); instance.get_fifo_size(channel)];
+            let n = instance.get_markers(channel, &mut markers);
+            markers[..n].iter().map(|marker| (marker.point, marker.flags)).collect()
+        })
+    }
+
+    /// Returns the counters of `channel` as a dict.
+    fn stats<'py>(&self, py: Python<'py>, channel: usize) -> PyResult<Bound<'py, PyDict>> {
+        self.check_channel(py, channel)?;
+        let stats = self.with(py, |instance| instance.get_stats(channel))?;
+
+        let dict = PyDict::new(py);
+        dict.set_item("acquired", stats.acquired)?;
+        dict.set_item("delivered", stats.delivered)?;
+        dict.set_item("blocks", stats.blocks)?;
+        dict.set_item("overflows", stats.overflows)?;
+        dict.set_item("errors", stats.errors)?;
+        dict.set_item("last_error", stats.last_error)?;
+        dict.set_item("fifo_fill", stats.fifo_fill)?;
+
+        Ok(dict)
+    }
+
+    /// Returns the latest `n` points read from `channel`, or the whole preview,
+    /// without taking them from the FIFO. Fewer come back after a gap, as the
+    /// preview is lossy.
+    #[pyo3(signature = (channel, n = None))]
+    fn peek<'py>(&self, py: Python<'py>, channel: usize, n: Option<usize>) -> PyResult<Bound<'py, PyArray1<u32>>> {
+        self.check_channel(py, channel)?;
+
+        let mut buf = vec![0u32; n.unwrap_or(self.preview).min(self.preview)];
+        let preview = self.with(py, |instance| instance.get_preview_into(channel, &mut buf))?;
+        buf.truncate(preview.len);
+
+        Ok(PyArray1::from_vec(py, buf))
+    }
+
+    /// Returns the latest whole raw frame in the preview of `channel`, or
+    /// `None` if there's none yet.
+    fn peek_frame<'py>(&self, py: Python<'py>, channel: usize) -> PyResult<Option<Bound<'py, PyArray2<u32>>>> {
+        self.check_channel(py, channel)?;
+
+        let (height, width) = self.frame
+            .ok_or_else(|| PyValueError::new_err("The instance was created without frame_width and frame_height"))?;
+
+        let array = PyArray2::<u32>::zeros(py, [height, width], false);
+        let buf = unsafe { array.as_slice_mut() }.unwrap();
+
+        Ok(self.with(py, |instance| instance.get_preview_frame_into(channel, buf))?.map(|_| array))
+    }
+
+    /// Returns the histogram of the latest points read from `channel`, as a
+    /// numpy.uint64 array, and their statistics as a dict, without consuming
+    /// any data.
+    fn histogram<'py>(&self, py: Python<'py>, channel: usize) -> PyResult<(Bound<'py, PyArray1<u64>>, Bound<'py, PyDict>)> {
+        self.check_channel(py, channel)?;
+
+        let bins = self.histogram_bins
+            .ok_or_else(|| PyValueError::new_err("The instance was created without histogram"))?;
+
+        let array = PyArray1::<u64>::zeros(py, bins, false);
+        let buf = unsafe { array.as_slice_mut() }.unwrap();
+
+        let stats = self.with(py, |instance| instance.get_histogram_into(channel, buf))?
+            .unwrap_or_default();
+
+        let dict = PyDict::new(py);
+        dict.set_item("points", stats.points)?;
+        dict.set_item("min", stats.min)?;
+        dict.set_item("max", stats.max)?;
+        dict.set_item("mean", stats.mean)?;
+        dict.set_item("saturated", stats.saturated)?;
+
+        Ok((array, dict))
+    }
+
+    /// Adds a consumer of `channel` with its own FIFO of `capacity` points,
+    /// which gets a copy of every point delivered to the channel from now on.
+    /// With `overflow="drop"`, it loses points when it falls behind, instead
+    /// of stalling the acquisition as with `"block"`, which stalls it for at
+    /// most 100 ms at a time before losing points too.
+    #[pyo3(signature = (channel, capacity, overflow = "drop"))]
+    fn reader(&self, py: Python<'_>, channel: usize, capacity: usize, overflow: &str) -> PyResult<Reader> {
+        self.check_channel(py, channel)?;
+        let overflow = overflow.parse::<AoldaqOverflow>().map_err(to_py_err)?;
+
+        let reader = self.with(py, |instance| instance.add_reader(channel, capacity, overflow))?
+            .ok_or_else(|| PyValueError::new_err(format!("Channel {} out of range", channel)))?;
+
+        Ok(Reader { inner: Mutex::new(Some(reader)) })
+    }
+
+    fn read_register(&self, py: Python<'_>, address: u32) -> PyResult<u32> {
+        self.with(py, |instance| instance.read_register(address))?.map_err(to_py_err)
+    }
+
+    fn write_register(&self, py: Python<'_>, address: u32, value: u32) -> PyResult<()> {
+        self.with(py, |instance| instance.write_register(address, value))?.map_err(to_py_err)
+    }
+
+    /// The NiFpga session of the instance, or `None` in other modes.
+    #[getter]
+    fn session(&self, py: Python<'_>) -> PyResult<Option<u32>> {
+        self.with(py, |instance| instance.get_nifpga_session())
+    }
+}
+
+/// Extra consumer of a channel, created with `Aoldaq.reader`. It stops being
+/// fed once closed.
+#[pyclass(module = "aoldaq")]
+struct Reader {
+    inner: Mutex<Option<aoldaq::AoldaqReader>>,
+}
+
+impl Reader {
+    fn with<R, F>(&self, py: Python<'_>, f: F) -> PyResult<R>
+    where
+        R: Send,
+        F: Send + FnOnce(&mut aoldaq::AoldaqReader) -> R,
+    {
+        py.detach(|| self.inner.lock().unwrap().as_mut().map(f))
+            .ok_or_else(|| PyRuntimeError::new_err("The reader is closed"))
+    }
+}
+
+#[pymethods]
+impl Reader {
+    /// Reads up to `n` points into a new `uint32` array, waiting for at most
+    /// `timeout` seconds for them.
+    #[pyo3(signature = (n, timeout = 0.0))]
+    fn read<'py>(&self, py: Python<'py>, n: usize, timeout: f64) -> PyResult<Bound<'py, PyArray1<u32>>> {
+        let timeout = duration(timeout)?;
+
+        let array = PyArray1::<u32>::zeros(py, n, false);
+        let buf = unsafe { array.as_slice_mut() }.unwrap();
+
+        let read = self.with(py, |reader| reader.get_data_into_blocking(buf, timeout))?;
+
+        if read < n {
+            unsafe { array.resize(read)? };
+        }
+
+        Ok(array)
+    }
+
+    fn fifo_size(&self, py: Python<'_>) -> PyResult<usize> {
+        self.with(py, |reader| reader.get_fifo_size())
+    }
+
+    /// Points lost because the FIFO of the reader was full.
+    #[getter]
+    fn dropped(&self, py: Python<'_>) -> PyResult<u64> {
+        self.with(py, |reader| reader.get_dropped())
+    }
+
+    fn close(&self) {
+        self.inner.lock().unwrap().take();
+    }
+}
+
+/// Reading end of the shared-memory ring of a channel, written by an instance
+/// created with `shm`, possibly in another process.
+#[pyclass(module = "aoldaq")]
+struct ShmReader {
+    inner: Mutex<Option<aoldaq::AoldaqShmReader>>,
+}
+
+impl ShmReader {
+    fn with<R, F>(&self, py: Python<'_>, f: F) -> PyResult<R>
+    where
+        R: Send,
+        F: Send + FnOnce(&mut aoldaq::AoldaqShmReader) -> R,
+    {
+        py.detach(|| self.inner.lock().unwrap().as_mut().map(f))
+            .ok_or_else(|| PyRuntimeError::new_err("The reader is closed"))
+    }
+}
+
+#[pymethods]
+impl ShmReader {
+    /// Attaches to the ring `name`, like "/aoldaq.0". Reading starts from the
+    /// oldest point still in the ring.
+    #[new]
+    fn new(name: &str) -> PyResult<Self> {
+        let reader = aoldaq::AoldaqShmReader::open(name).map_err(to_py_err)?;
+        Ok(ShmReader { inner: Mutex::new(Some(reader)) })
+    }
+
+    /// Reads up to `n` points into a new `uint32` array, without waiting.
+    fn read<'py>(&self, py: Python<'py>, n: usize) -> PyResult<Bound<'py, PyArray1<u32>>> {
+        let array = PyArray1::<u32>::zeros(py, n, false);
+        let buf = unsafe { array.as_slice_mut() }.unwrap();
+
+        let read = self.with(py, |reader| reader.read(buf))?;
+
+        if read < n {
+            unsafe { array.resize(read)? };
+        }
+
+        Ok(array)
+    }
+
+    fn available(&self, py: Python<'_>) -> PyResult<usize> {
+        self.with(py, |reader| reader.available())
+    }
+
+    /// Points overwritten before being read.
+    #[getter]
+    fn lost(&self, py: Python<'_>) -> PyResult<u64> {
+        self.with(py, |reader| reader.lost())
+    }
+
+    /// Index, in the stream of the ring, of the next point to read.
+    #[getter]
+    fn position(&self, py: Python<'_>) -> PyResult<u64> {
+        self.with(py, |reader| reader.position())
+    }
+
+    /// Whether the instance that wrote the ring is gone.
+    #[getter]
+    fn closed(&self, py: Python<'_>) -> PyResult<bool> {
+        self.with(py, |reader| reader.is_closed())
+    }
+
+    fn close(&self) {
+        self.inner.lock().unwrap().take();
+    }
+}
+
+#[pymodule(name = "aoldaq")]
+fn init(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Aoldaq>()?;
+    m.add_class::<Reader>()?;
+    m.add_class::<ShmReader>()?;
+    Ok(())
+}
@@ -0,0 +1,239 @@
+import os
+
+import numpy as np
+import pytest
+
+import aoldaq
+
+
+def random_instance(channels=1):
+    return aoldaq.Aoldaq(mode="random", channels=channels, block_size=100, fifo_capacity=100_000)
+
+
+def test_read_returns_uint32_arrays():
+    with random_instance() as daq:
+        daq.start(1000)
+        assert daq.wait_done(5.0)
+
+        data = daq.read(0, 600, timeout=1.0)
+        assert isinstance(data, np.ndarray)
+        assert data.dtype == np.uint32
+        assert data.shape == (600,)
+
+        # Only 400 points are left, so the read comes back short
+        assert daq.read(0, 600).shape == (400,)
+        assert daq.fifo_size(0) == 0
+
+
+def test_read_all():
+    with random_instance(channels=3) as daq:
+        daq.start(500)
+        assert daq.wait_done(5.0)
+
+        data = daq.read_all(500, timeout=1.0)
+        assert data.shape == (3, 500)
+
+        with pytest.raises(TimeoutError):
+            daq.read_all(1, timeout=0.01)
+
+
+def test_counter_mode():
+    with aoldaq.Aoldaq(mode="counter", channels=2, block_size=100, fifo_capacity=100_000) as daq:
+        daq.start(1000)
+        assert daq.wait_done(5.0)
+
+        data = daq.read_all(1000, timeout=1.0)
+        assert (data == np.arange(1000, dtype=np.uint32)).all()
+
+
+def test_read_frame():
+    with aoldaq.Aoldaq(mode="counter", block_size=100, fifo_capacity=100_000,
+                       frame_width=4, frame_height=2, bidirectional=True) as daq:
+        daq.start(8)
+        assert daq.wait_done(5.0)
+
+        frame = daq.read_frame(0, timeout=1.0)
+        assert frame.shape == (2, 4)
+        # The second line was scanned backwards
+        assert list(frame[0]) == [0, 1, 2, 3]
+        assert list(frame[1]) == [7, 6, 5, 4]
+
+        with pytest.raises(TimeoutError):
+            daq.read_frame(0, timeout=0.01)
+
+        daq.phase = 0.5
+        assert daq.phase == 0.5
+
+
+def test_read_averaged_frame():
+    with aoldaq.Aoldaq(mode="counter", block_size=100, fifo_capacity=100_000,
+                       frame_width=4, frame_height=2, average="sum", average_count=2) as daq:
+        daq.start(16)
+        assert daq.wait_done(5.0)
+
+        # Frames of 0..8 and 8..16
+        frame = daq.read_frame(0, timeout=1.0)
+        assert (frame == np.arange(8).reshape(2, 4) * 2 + 8).all()
+
+        with pytest.raises(ValueError):
+            aoldaq.Aoldaq(frame_width=4, frame_height=2, average="median")
+
+
+def test_binning():
+    with aoldaq.Aoldaq(mode="counter", block_size=100, fifo_capacity=100_000, binning="decimate:4") as daq:
+        daq.start(50)
+        assert daq.wait_done(5.0)
+
+        data = daq.read(0, 100)
+        assert (data == np.arange(0, 200, 4, dtype=np.uint32)).all()
+
+    with pytest.raises(ValueError):
+        aoldaq.Aoldaq(block_size=100, binning="mean:3")
+
+
+def test_unpacking():
+    # Bit 2 of the count flags a marker on words 4 to 7 of every 8
+    with aoldaq.Aoldaq(mode="counter", channels=2, block_size=100, fifo_capacity=100_000,
+                       fields="0:0-1, 0:2-31", markers=[0x4]) as daq:
+        daq.start(100)
+        assert daq.wait_done(5.0)
+
+        assert daq.markers(0)[:2] == [(4, 0x4), (5, 0x4)]
+
+        data = daq.read_all(100, timeout=1.0)
+        assert (data[0] == np.arange(100) % 4).all()
+        assert (data[1] == np.arange(100) // 4).all()
+
+
+def test_frame_sync():
+    # Frames of 8 points start where bit 3 of the count goes up
+    with aoldaq.Aoldaq(mode="counter", block_size=100, fifo_capacity=100_000,
+                       frame_width=4, frame_height=2, sync_mask=0xf, sync_value=0x8) as daq:
+        daq.start(100)
+        assert daq.wait_done(5.0)
+
+        frame = daq.read_frame(0, timeout=1.0)
+        assert frame[0, 0] == 8
+        assert daq.frame_resyncs(0) == 0
+
+
+def test_photon_counting():
+    # The counter as events: the high words count odd, so their lowest bits
+    # make channels 1 (lines) and 3 (photons), 2 time units after each line
+    photon = {"channel_shift": 32, "channel_bits": 2, "time_bits": 32, "line_channel": 1}
+    with aoldaq.Aoldaq(mode="counter", block_size=100, fifo_capacity=100_000,
+                       frame_width=4, frame_height=2, photon=photon) as daq:
+        daq.start(100)
+        assert daq.wait_done(5.0)
+
+        frame = daq.read_frame(0, timeout=1.0)
+        assert frame.tolist() == [[0, 0, 1, 0], [0, 0, 1, 0]]
+        assert daq.read_lifetime(0).shape == (2, 4, 0)
+
+
+def test_histogram():
+    with aoldaq.Aoldaq(mode="counter", block_size=100, fifo_capacity=100_000,
+                       histogram={"bins": 10, "max": 1000, "window": 1000}) as daq:
+        daq.start(1000)
+        assert daq.wait_done(5.0)
+
+        counts, stats = daq.histogram(0)
+        assert counts.dtype == np.uint64
+        assert counts.sum() == stats["points"] == 1000
+        assert stats["max"] >= 999
+        # Nothing was consumed
+        assert daq.fifo_size(0) == 1000
+
+
+def test_peek():
+    with aoldaq.Aoldaq(mode="counter", block_size=100, fifo_capacity=100_000,
+                       frame_width=4, frame_height=5, preview=200) as daq:
+        daq.start(1000)
+        assert daq.wait_done(5.0)
+
+        assert list(daq.peek(0, 3)) == [997, 998, 999]
+        assert daq.peek(0).shape == (200,)
+        assert daq.peek_frame(0)[0, 0] == 980
+
+        # Nothing was consumed
+        assert daq.fifo_size(0) == 1000
+
+
+def test_readers():
+    with aoldaq.Aoldaq(mode="counter", block_size=100, fifo_capacity=100_000) as daq:
+        recorder = daq.reader(0, 100_000, overflow="block")
+        display = daq.reader(0, 200)
+        daq.start(1000)
+        assert daq.wait_done(5.0)
+
+        # Every consumer gets its own copy
+        assert list(daq.read(0, 1000)) == list(range(1000))
+        assert list(recorder.read(1000)) == list(range(1000))
+        assert display.dropped == 800
+        assert list(display.read(1000)) == list(range(200))
+
+        display.close()
+        with pytest.raises(RuntimeError):
+            display.read(1)
+
+
+def test_shm():
+    name = f"/aoldaq-pytest-{os.getpid()}"
+    with aoldaq.Aoldaq(mode="counter", block_size=100, fifo_capacity=100_000, shm=name, shm_capacity=500) as daq:
+        ring = aoldaq.ShmReader(f"{name}.0")
+        daq.start(1000)
+        assert daq.wait_done(5.0)
+
+        # Another process would only see the last points that fit
+        assert list(ring.read(1000)) == list(range(500, 1000))
+        assert ring.lost == 500
+        assert not ring.closed
+
+    assert ring.closed
+    ring.close()
+
+
+def test_stats():
+    with random_instance(channels=2) as daq:
+        daq.start(1000)
+        assert daq.wait_done(5.0)
+
+        stats = daq.stats(1)
+        assert stats["delivered"] == 1000
+        assert stats["acquired"] >= 1000
+        assert stats["fifo_fill"] == 1000
+        assert stats["errors"] == 0
+
+
+def test_context_manager_closes():
+    with random_instance() as daq:
+        assert daq.channels == 1
+        assert daq.block_size == 100
+        assert daq.session is None
+
+    with pytest.raises(RuntimeError):
+        daq.start()
+
+    # Closing twice is fine
+    daq.close()
+
+
+def test_registers_unsupported_in_random_mode():
+    with random_instance() as daq:
+        with pytest.raises(NotImplementedError):
+            daq.read_register(0)
+        with pytest.raises(NotImplementedError):
+            daq.write_register(0, 1)
+
+
+def test_invalid_arguments():
+    with pytest.raises(ValueError):
+        aoldaq.Aoldaq(mode="digitizer")
+    with pytest.raises(ValueError):
+        aoldaq.Aoldaq(mode="nifpga")
+    with pytest.raises(ValueError):
+        aoldaq.Aoldaq(channels=0)
+
+    with random_instance() as daq:
+        with pytest.raises(ValueError):
+            daq.read(1, 10)
@@ -0,0 +1,270 @@
+//! Throughput benchmark of the acquisition pipeline. Every combination of the
+//! swept settings runs for a while with a consumer reading the FIFOs in
+//! chunks, measuring the sustained rate, how long the points wait between the
+//! device read and the consumer, and the CPU time of the whole process.
+//!
+//! There is no simulated device with realistic DMA timing to run against yet:
+//! `counter` only adds the cost of generating the points, like `random`.
+//!
+//! Shared by `aoldaq bench` and `cargo bench`, so it only depends on the
+//! public API.
+
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+
+use aoldaq::{ AoldaqBuilder, AoldaqMode, NiFpgaOptions };
+
+/// How long the consumer sleeps when every FIFO is empty.
+const POLL_INTERVAL: Duration = Duration::from_micros(100);
+
+#[derive(Clone, Debug)]
+pub struct Sweep {
+    pub devices: Vec<String>,
+    pub channels: Vec<usize>,
+    pub block_sizes: Vec<usize>,
+    pub fifo_capacities: Vec<usize>,
+    pub chunk_sizes: Vec<usize>,
+    /// Duration of each case.
+    pub seconds: f64,
+}
+
+impl Default for Sweep {
+    fn default() -> Sweep {
+        let mut devices = vec!["random".to_string()];
+        if cfg!(feature = "mock-nifpga") {
+            devices.push("nifpga".to_string());
+        }
+
+        Sweep {
+            devices,
+            channels: vec![1, 4],
+            block_sizes: vec![1000, 4000, 16000],
+            fifo_capacities: vec![1 << 20],
+            chunk_sizes: vec![1000, 16000],
+            seconds: 0.5,
+        }
+    }
+}
+
+impl Sweep {
+    /// Sets the swept values named `key` from a comma separated list. Returns
+    /// whether `key` names a sweep setting.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<bool, String> {
+        let list = || value.split(',')
+            .map(|v| v.trim().parse().map_err(|_| format!("Invalid value {} for {}", v, key)))
+            .collect::<Result<Vec<usize>, String>>();
+
+        match key {
+            "devices" => self.devices = value.split(',').map(|v| v.trim().to_string()).collect(),
+            "channel_counts" => self.channels = list()?,
+            "block_sizes" => self.block_sizes = list()?,
+            "fifo_capacities" => self.fifo_capacities = list()?,
+            "chunk_sizes" => self.chunk_sizes = list()?,
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    pub fn cases(&self) -> Vec<Case> {
+        let mut cases = Vec::new();
+
+        for device in &self.devices {
+            for &channels in &self.channels {
+                for &block_size in &self.block_sizes {
+                    for &fifo_capacity in &self.fifo_capacities {
+                        for &chunk_size in &self.chunk_sizes {
+                            cases.push(Case {
+                                device: device.clone(),
+                                channels,
+                                block_size,
+                                fifo_capacity,
+                                chunk_size,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        cases
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Case {
+    /// `random`, `counter`, or `nifpga` for the NiFpga runtime, which is the
+    /// mock one when built with the `mock-nifpga` feature.
+    pub device: String,
+    pub channels: usize,
+    pub block_size: usize,
+    pub fifo_capacity: usize,
+    pub chunk_size: usize,
+}
+
+pub struct Report {
+    pub case: Case,
+    pub seconds: f64,
+    /// Points read by the consumer, over every channel.
+    pub samples: u64,
+    pub overflows: u64,
+    pub errors: u64,
+    /// Time between the device read and the consumer read, in microseconds:
+    /// mean, median, 99th percentile and maximum.
+    pub latency_us: [f64; 4],
+    /// CPU time of the process over wall time, so 1.0 is one busy core. `None`
+    /// where it can't be measured.
+    pub cpu: Option<f64>,
+}
+
+impl Report {
+    pub fn samples_per_second(&self) -> f64 {
+        self.samples as f64 / self.seconds
+    }
+
+    pub fn to_json(&self) -> String {
+        let cpu = match self.cpu {
+            Some(cpu) => format!("{:.3}", cpu),
+            None => "null".to_string(),
+        };
+
+        format!(
+            concat!(
+                "{{\"device\": \"{}\", \"channels\": {}, \"block_size\": {}, \"fifo_capacity\": {}, ",
+                "\"chunk_size\": {}, \"seconds\": {:.3}, \"samples\": {}, \"samples_per_second\": {:.0}, ",
+                "\"overflows\": {}, \"errors\": {}, \"latency_us\": {{\"mean\": {:.1}, \"p50\": {:.1}, ",
+                "\"p99\": {:.1}, \"max\": {:.1}}}, \"cpu\": {}}}"
+            ),
+            self.case.device, self.case.channels, self.case.block_size, self.case.fifo_capacity,
+            self.case.chunk_size, self.seconds, self.samples, self.samples_per_second(),
+            self.overflows, self.errors, self.latency_us[0], self.latency_us[1],
+            self.latency_us[2], self.latency_us[3], cpu
+        )
+    }
+}
+
+/// Formats `reports` as a JSON array, one case per line.
+pub fn to_json(reports: &[Report]) -> String {
+    let cases = reports.iter().map(|r| format!("  {}", r.to_json())).collect::<Vec<_>>();
+    format!("[\n{}\n]", cases.join(",\n"))
+}
+
+/// Runs `case` for `seconds`. NiFpga cases use `nifpga`, or default options if
+/// there are none, which is enough for the mock runtime.
+pub fn run(case: &Case, seconds: f64, nifpga: Option<&NiFpgaOptions>) -> Result<Report, String> {
+    let mode = match case.device.as_str() {
+        "random" => AoldaqMode::Random,
+        "counter" => AoldaqMode::Counter,
+        "nifpga" => AoldaqMode::NiFpga,
+        device => return Err(format!("Unknown benchmark device {}", device)),
+    };
+
+    let mut builder = AoldaqBuilder::new(mode)
+        .channels(case.channels)
+        .block_size(case.block_size)
+        .fifo_capacity(case.fifo_capacity);
+
+    if let AoldaqMode::NiFpga = mode {
+        builder = builder.nifpga(nifpga.cloned().unwrap_or_default());
+    }
+
+    let mut aoldaq = builder.build().map_err(|e| format!("Failed to create instance for {:?}: {}", case, e))?;
+
+    if case.chunk_size == 0 {
+        return Err(format!("Invalid chunk size for {:?}", case));
+    }
+
+    let mut bufs = vec![vec![0u32; case.chunk_size]; case.channels];
+    let mut filled = vec![0; case.channels];
+    // Device read times of the blocks in the chunk of each channel
+    let mut timestamps = vec![Vec::new(); case.channels];
+    let mut latencies = Vec::new();
+    let mut samples = 0u64;
+
+    let cpu_start = cpu_time();
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs_f64(seconds);
+
+    aoldaq.start();
+
+    while Instant::now() < deadline {
+        let mut idle = true;
+
+        // Chunks are read block by block, for the device read times, and only
+        // handed over to the consumer once full
+        for i in 0..case.channels {
+            while let Some(info) = aoldaq.get_block_into(i, &mut bufs[i][filled[i]..]) {
+                filled[i] += info.len;
+                timestamps[i].push(info.timestamp_ns);
+                idle = false;
+
+                if filled[i] == case.chunk_size {
+                    let now = now_ns();
+                    latencies.extend(timestamps[i].drain(..).map(|t| now.saturating_sub(t)));
+                    samples += filled[i] as u64;
+                    filled[i] = 0;
+                }
+            }
+        }
+
+        if idle {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    let wall = start.elapsed().as_secs_f64();
+    let cpu = cpu_start.and_then(|start| Some((cpu_time()? - start) / wall));
+
+    aoldaq.stop();
+
+    let stats = (0..case.channels).map(|i| aoldaq.get_stats(i)).collect::<Vec<_>>();
+
+    Ok(Report {
+        case: case.clone(),
+        seconds: wall,
+        samples,
+        overflows: stats.iter().map(|s| s.overflows).sum(),
+        errors: stats.iter().map(|s| s.errors).sum(),
+        latency_us: latency_summary(&mut latencies),
+        cpu,
+    })
+}
+
+fn latency_summary(latencies_ns: &mut [u64]) -> [f64; 4] {
+    if latencies_ns.is_empty() {
+        return [0.0; 4];
+    }
+
+    latencies_ns.sort_unstable();
+
+    let us = |ns: u64| ns as f64 / 1e3;
+    let percentile = |p: f64| us(latencies_ns[((latencies_ns.len() - 1) as f64 * p) as usize]);
+    let mean = latencies_ns.iter().map(|&ns| us(ns)).sum::<f64>() / latencies_ns.len() as f64;
+
+    [mean, percentile(0.5), percentile(0.99), us(latencies_ns[latencies_ns.len() - 1])]
+}
+
+fn now_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// User and system CPU time of the process, in seconds.
+#[cfg(target_os = "linux")]
+fn cpu_time() -> Option<f64> {
+    // Linux always reports these in units of 1/100 s, whatever the kernel tick
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+
+    // The command name may contain spaces, the fields after it don't
+    let fields = stat[stat.rfind(')')? + 2..].split(' ').collect::<Vec<_>>();
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+
+    Some((utime + stime) as f64 / 100.0)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cpu_time() -> Option<f64> {
+    None
+}
@@ -0,0 +1,104 @@
+//! Just enough of a reader for `.lvbitx` bitfiles to list their DMA FIFOs and
+//! registers. Bitfiles are XML, but their layout is simple and fixed, so
+//! there's no need for a full parser.
+
+pub struct Fifo {
+    pub name: String,
+    pub number: u32,
+    pub direction: String,
+    pub datatype: String,
+}
+
+pub struct Register {
+    pub name: String,
+    pub offset: u32,
+    pub indicator: bool,
+    pub datatype: String,
+}
+
+pub struct Bitfile {
+    pub fifos: Vec<Fifo>,
+    pub registers: Vec<Register>,
+}
+
+impl Bitfile {
+    pub fn parse(xml: &str) -> Bitfile {
+        let fifos = elements(xml, "Channel")
+            .into_iter()
+            .filter_map(|(attrs, body)| Some(Fifo {
+                name: attribute(attrs, "Name").unwrap_or("").to_string(),
+                number: text(body, "Number")?.parse().ok()?,
+                direction: text(body, "Direction").unwrap_or("").to_string(),
+                datatype: text(body, "SubType").unwrap_or("").to_string(),
+            }))
+            .collect();
+
+        let registers = elements(xml, "Register")
+            .into_iter()
+            .filter(|(_, body)| text(body, "Hidden") != Some("true"))
+            .filter_map(|(_, body)| Some(Register {
+                name: text(body, "Name")?.to_string(),
+                offset: text(body, "Offset")?.parse().ok()?,
+                indicator: text(body, "Indicator") == Some("true"),
+                datatype: datatype(body),
+            }))
+            .collect();
+
+        Bitfile { fifos, registers }
+    }
+}
+
+/// Returns the attributes and the body of every `<tag ...>...</tag>` element.
+/// Elements with the same tag must not nest.
+fn elements<'a>(xml: &'a str, tag: &str) -> Vec<(&'a str, &'a str)> {
+    let open = format!("<{}", tag);
+    let close = format!("</{}>", tag);
+    let mut found = Vec::new();
+    let mut rest = xml;
+
+    while let Some(start) = rest.find(&open) {
+        let after = &rest[start + open.len()..];
+
+        // Skip longer tags with the same prefix, like <ChannelList>
+        if !after.starts_with(|c: char| c == '>' || c.is_whitespace()) {
+            rest = after;
+            continue;
+        }
+
+        let head_end = match after.find('>') {
+            Some(end) => end,
+            None => break,
+        };
+        let body = &after[head_end + 1..];
+        let body_end = match body.find(&close) {
+            Some(end) => end,
+            None => break,
+        };
+
+        found.push((&after[..head_end], &body[..body_end]));
+        rest = &body[body_end + close.len()..];
+    }
+
+    found
+}
+
+fn text<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    elements(xml, tag).first().map(|(_, body)| body.trim())
+}
+
+fn attribute<'a>(attrs: &'a str, name: &str) -> Option<&'a str> {
+    let key = format!("{}=\"", name);
+    let start = attrs.find(&key)? + key.len();
+    let end = attrs[start..].find('"')?;
+
+    Some(&attrs[start..start + end])
+}
+
+/// Name of the first tag inside `<Datatype>`, like `U32` for `<U32/>`.
+fn datatype(xml: &str) -> String {
+    text(xml, "Datatype")
+        .and_then(|body| body.strip_prefix('<'))
+        .map(|tag| tag.split(|c: char| c == '/' || c == '>' || c.is_whitespace()).next().unwrap_or(""))
+        .unwrap_or("")
+        .to_string()
+}
@@ -0,0 +1,370 @@
+use std::fs::File;
+use std::io::{ BufWriter, Write };
+use std::path::PathBuf;
+use std::time::{ Duration, Instant };
+
+use aoldaq::{ Aoldaq, AoldaqBuilder, AoldaqMode, CounterVerifier };
+use simplelog::{ Config, LevelFilter, TermLogger, TerminalMode };
+
+mod bench;
+mod bitfile;
+use bench::Sweep;
+use bitfile::Bitfile;
+
+const USAGE: &str = "\
+Usage: aoldaq <command> [options]
+
+Commands:
+    acquire     Acquires for a while and records each channel to <output>/channel<i>.bin,
+                as little-endian u32 points
+    monitor     Prints live per-channel rates, FIFO fill, overflows and errors
+    info        Prints the instance settings, and the DMA FIFOs and registers of the bitfile
+    flush       Discards the data in the hardware FIFOs
+    verify      Acquires like acquire, checking that every channel counts up by one,
+                and reports gaps, missing points and reordering. Uses the counter
+                mode unless another mode is given, for FPGA code that counts
+    bench       Measures the throughput, latency and CPU usage of the pipeline over
+                a range of settings, printing the results as JSON
+
+Options:
+    --config <file>         Reads the instance options from a config file
+    --<option> <value>      Sets an instance option, as in config files, overriding the
+                            config file. For example: --mode nifpga --channels 2
+                            --block-size 4000 --nifpga.bitfile aol.lvbitx
+    --seconds <n>           acquire, monitor, verify: how long to run. Defaults to 1
+                            second, and forever for monitor
+    --points <n>            acquire, verify: acquires exactly <n> points per channel instead
+    --output <dir>          acquire: where to write the files. Defaults to .
+    --interval <ms>         monitor: time between reports. Defaults to 1000
+
+Benchmark options, as comma separated lists:
+    --devices <list>        random, counter and/or nifpga. Defaults to random, and nifpga
+                            when built with the mock-nifpga feature. There is no device
+                            simulating the DMA timing of the FPGA yet
+    --channel-counts <list>, --block-sizes <list>, --fifo-capacities <list>,
+    --chunk-sizes <list>    Values to sweep. --seconds sets the duration of each case,
+                            0.5 by default
+";
+
+struct Args {
+    command: String,
+    config: Option<PathBuf>,
+    options: Vec<(String, String)>,
+    seconds: Option<f64>,
+    points: Option<usize>,
+    output: PathBuf,
+    interval: Duration,
+    sweep: Sweep,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = std::env::args().skip(1);
+
+    let command = match args.next() {
+        Some(command) if !command.starts_with("--") => command,
+        _ => return Err("Missing command".to_string()),
+    };
+
+    let mut parsed = Args {
+        command,
+        config: None,
+        options: Vec::new(),
+        seconds: None,
+        points: None,
+        output: PathBuf::from("."),
+        interval: Duration::from_millis(1000),
+        sweep: Sweep::default(),
+    };
+
+    while let Some(flag) = args.next() {
+        let name = match flag.strip_prefix("--") {
+            Some(name) => name.replace('-', "_"),
+            None => return Err(format!("Unexpected argument {}", flag)),
+        };
+
+        let value = args.next().ok_or_else(|| format!("Missing value for {}", flag))?;
+        let number = || value.parse().map_err(|_| format!("Invalid value {} for {}", value, flag));
+
+        match name.as_str() {
+            "config" => parsed.config = Some(PathBuf::from(&value)),
+            "seconds" => parsed.seconds = Some(value.parse().map_err(|_| format!("Invalid value {} for {}", value, flag))?),
+            "points" => parsed.points = Some(number()?),
+            "output" => parsed.output = PathBuf::from(&value),
+            "interval" => parsed.interval = Duration::from_millis(number()? as u64),
+            _ if parsed.sweep.set(&name, &value)? => (),
+            _ => parsed.options.push((name, value)),
+        }
+    }
+
+    Ok(parsed)
+}
+
+fn builder(args: &Args) -> Result<AoldaqBuilder, String> {
+    let mut builder = match args.config {
+        Some(ref config) => AoldaqBuilder::from_config_file(config).map_err(|e| e.to_string())?,
+        None if args.command == "verify" => AoldaqBuilder::new(AoldaqMode::Counter),
+        None => AoldaqBuilder::new(AoldaqMode::Random),
+    };
+
+    for (key, value) in &args.options {
+        builder = builder.set(key, value).map_err(|e| e.to_string())?;
+    }
+
+    Ok(builder)
+}
+
+fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    // Keeps the library logs out of the JSON
+    if args.command == "bench" {
+        TermLogger::init(LevelFilter::Warn, Config::default(), TerminalMode::Stderr).unwrap_or(());
+    }
+
+    let result = builder(&args).and_then(|builder| match args.command.as_str() {
+        "acquire" => acquire(&args, builder),
+        "monitor" => monitor(&args, builder),
+        "info" => info(builder),
+        "flush" => flush(builder),
+        "bench" => bench(&args, builder),
+        "verify" => verify(&args, builder),
+        "help" => {
+            print!("{}", USAGE);
+            Ok(())
+        }
+        command => Err(format!("Unknown command {}\n\n{}", command, USAGE)),
+    });
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn build(builder: AoldaqBuilder) -> Result<Aoldaq, String> {
+    builder.build().map_err(|e| format!("Failed to create instance: {}", e))
+}
+
+fn acquire(args: &Args, builder: AoldaqBuilder) -> Result<(), String> {
+    let mut aoldaq = build(builder)?;
+    let n_channels = aoldaq.get_n_channels();
+
+    let mut files = (0..n_channels)
+        .map(|i| {
+            let path = args.output.join(format!("channel{}.bin", i));
+            File::create(&path)
+                .map(BufWriter::new)
+                .map_err(|e| format!("Cannot create {}: {}", path.display(), e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut written = vec![0usize; n_channels];
+
+    run(args, &mut aoldaq, |i, data| {
+        let bytes = data.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<_>>();
+        files[i].write_all(&bytes).map_err(|e| format!("Failed to write channel {}: {}", i, e))?;
+        written[i] += data.len();
+        Ok(())
+    })?;
+
+    for file in files.iter_mut() {
+        file.flush().map_err(|e| format!("Failed to write: {}", e))?;
+    }
+
+    for (i, n) in written.iter().enumerate() {
+        let stats = aoldaq.get_stats(i);
+        println!("channel {}: {} points, {} overflows, {} errors", i, n, stats.overflows, stats.errors);
+    }
+
+    Ok(())
+}
+
+/// Runs a finite acquisition of `--points`, or a continuous one of `--seconds`,
+/// passing everything read from each channel to `consume`.
+fn run<F>(args: &Args, aoldaq: &mut Aoldaq, mut consume: F) -> Result<(), String>
+    where F: FnMut(usize, &[u32]) -> Result<(), String>
+{
+    let mut buf = vec![0u32; aoldaq.get_block_size() * 16];
+
+    let mut drain = |aoldaq: &mut Aoldaq| -> Result<(), String> {
+        for i in 0..aoldaq.get_n_channels() {
+            loop {
+                let n = aoldaq.get_data_into(i, &mut buf[..]);
+                if n == 0 {
+                    break;
+                }
+
+                consume(i, &buf[..n])?;
+            }
+        }
+
+        Ok(())
+    };
+
+    match args.points {
+        Some(points) => {
+            aoldaq.start_finite(points);
+            while !aoldaq.wait_done(Duration::from_millis(100)) {
+                drain(aoldaq)?;
+            }
+        }
+        None => {
+            let deadline = Instant::now() + Duration::from_secs_f64(args.seconds.unwrap_or(1.0));
+            aoldaq.start();
+            while Instant::now() < deadline {
+                drain(aoldaq)?;
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+
+    aoldaq.stop();
+    drain(aoldaq)
+}
+
+fn monitor(args: &Args, builder: AoldaqBuilder) -> Result<(), String> {
+    let capacity = builder.get_options().fifo_capacity;
+    let mut aoldaq = build(builder)?;
+    let n_channels = aoldaq.get_n_channels();
+
+    let mut buf = vec![0u32; aoldaq.get_block_size() * 16];
+    let mut last = (0..n_channels).map(|i| aoldaq.get_stats(i)).collect::<Vec<_>>();
+    let mut last_time = Instant::now();
+    let deadline = args.seconds.map(|s| Instant::now() + Duration::from_secs_f64(s));
+
+    aoldaq.start();
+
+    while deadline.map_or(true, |deadline| Instant::now() < deadline) {
+        // The data itself is thrown away, so the FIFOs don't fill up
+        let next = Instant::now() + args.interval;
+        while Instant::now() < next {
+            for i in 0..n_channels {
+                while aoldaq.get_data_into(i, &mut buf[..]) > 0 {}
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let elapsed = last_time.elapsed().as_secs_f64();
+        last_time = Instant::now();
+
+        for (i, last) in last.iter_mut().enumerate() {
+            let stats = aoldaq.get_stats(i);
+            let rate = (stats.delivered - last.delivered) as f64 / elapsed;
+
+            println!(
+                "ch {}: {:8.3} MS/s  fifo {:10} ({:5.1}%)  overflows {}  errors {}",
+                i, rate / 1e6, stats.fifo_fill, 100.0 * stats.fifo_fill as f64 / capacity as f64,
+                stats.overflows, stats.errors
+            );
+
+            *last = stats;
+        }
+    }
+
+    aoldaq.stop();
+    Ok(())
+}
+
+fn info(builder: AoldaqBuilder) -> Result<(), String> {
+    let bitfile = builder.get_nifpga_options().map(|nifpga| nifpga.bitfile.clone());
+    let aoldaq = build(builder)?;
+
+    println!("channels: {}", aoldaq.get_n_channels());
+    println!("block size: {}", aoldaq.get_block_size());
+    match aoldaq.get_nifpga_session() {
+        Some(session) => println!("session: {}", session),
+        None => println!("session: none"),
+    }
+
+    let path = match bitfile {
+        Some(ref path) if !path.as_os_str().is_empty() => path,
+        _ => return Ok(()),
+    };
+
+    let xml = std::fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    let bitfile = Bitfile::parse(&xml);
+
+    println!("\nDMA FIFOs:");
+    for fifo in &bitfile.fifos {
+        println!("    {:3}  {:24} {:14} {}", fifo.number, fifo.name, fifo.direction, fifo.datatype);
+    }
+
+    println!("\nRegisters:");
+    for register in &bitfile.registers {
+        let kind = if register.indicator { "indicator" } else { "control" };
+        let value = if register.datatype == "U32" {
+            match aoldaq.read_register(register.offset) {
+                Ok(value) => format!("{} (0x{:x})", value, value),
+                Err(e) => e.to_string(),
+            }
+        } else {
+            "-".to_string()
+        };
+
+        println!("    0x{:06x}  {:24} {:9} {:8} {}", register.offset, register.name, kind, register.datatype, value);
+    }
+
+    Ok(())
+}
+
+fn flush(builder: AoldaqBuilder) -> Result<(), String> {
+    let aoldaq = build(builder)?;
+
+    for i in 0..aoldaq.get_n_channels() {
+        match aoldaq.flush_hardware_fifo(i) {
+            Ok(n) => println!("channel {}: flushed {} points", i, n),
+            Err(e) => println!("channel {}: {}", i, e),
+        }
+    }
+
+    Ok(())
+}
+
+fn bench(args: &Args, builder: AoldaqBuilder) -> Result<(), String> {
+    let seconds = args.seconds.unwrap_or(args.sweep.seconds);
+    let reports = args.sweep.cases()
+        .iter()
+        .map(|case| {
+            eprintln!("{:?}", case);
+            bench::run(case, seconds, builder.get_nifpga_options())
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    println!("{}", bench::to_json(&reports));
+    Ok(())
+}
+
+fn verify(args: &Args, builder: AoldaqBuilder) -> Result<(), String> {
+    let mut aoldaq = build(builder)?;
+    let mut verifiers = vec![CounterVerifier::new(); aoldaq.get_n_channels()];
+
+    run(args, &mut aoldaq, |i, data| {
+        verifiers[i].check(data);
+        Ok(())
+    })?;
+
+    for (i, verifier) in verifiers.iter().enumerate() {
+        let report = verifier.report();
+        print!("channel {}: {} points, {} gaps, {} missing, {} reordered",
+               i, report.checked, report.gaps, report.missing, report.reorders);
+
+        if report.has_gap {
+            println!(", first at point {}: expected {}, found {}",
+                     report.first_gap, report.first_gap_expected, report.first_gap_found);
+        } else {
+            println!();
+        }
+    }
+
+    if verifiers.iter().all(|v| v.report().is_ok()) {
+        Ok(())
+    } else {
+        Err("Data integrity check failed".to_string())
+    }
+}
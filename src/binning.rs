@@ -0,0 +1,124 @@
+//! Reduction of oversampled channels on the host: every `factor` consecutive
+//! samples read from the device become one pixel before reaching the FIFO, so
+//! the FIFOs, the triggers and the finite acquisitions deal in pixels.
+
+use crate::AoldaqError;
+
+/// How the samples of a bin become a pixel.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum AoldaqBinMode {
+    /// Every sample is a pixel.
+    #[default]
+    None,
+    /// Sum of the samples, saturating at `u32::MAX`.
+    Sum,
+    /// Mean of the samples, rounded to the nearest integer.
+    Mean,
+    Max,
+    /// Keeps the first sample of each bin.
+    Decimate,
+}
+
+impl std::str::FromStr for AoldaqBinMode {
+    type Err = AoldaqError;
+
+    fn from_str(s: &str) -> Result<AoldaqBinMode, AoldaqError> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(AoldaqBinMode::None),
+            "sum" => Ok(AoldaqBinMode::Sum),
+            "mean" => Ok(AoldaqBinMode::Mean),
+            "max" => Ok(AoldaqBinMode::Max),
+            "decimate" => Ok(AoldaqBinMode::Decimate),
+            _ => Err(AoldaqError::InvalidArgument(format!("unknown binning {:?}", s))),
+        }
+    }
+}
+
+/// Binning of a channel.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct AoldaqBinning {
+    pub mode: AoldaqBinMode,
+    /// Samples per pixel. Must divide the block size.
+    pub factor: usize,
+}
+
+impl AoldaqBinning {
+    /// Samples per pixel, one without binning.
+    pub fn factor(&self) -> usize {
+        match self.mode {
+            AoldaqBinMode::None => 1,
+            _ => self.factor,
+        }
+    }
+
+    /// Reduces the samples in `buf` to pixels, in place, at the start of `buf`.
+    /// Returns the amount of pixels.
+    pub fn apply(&self, buf: &mut [u32]) -> usize {
+        let factor = self.factor();
+        let pixels = buf.len() / factor;
+
+        if factor == 1 {
+            return buf.len();
+        }
+
+        for pixel in 0..pixels {
+            let bin = &buf[pixel * factor..(pixel + 1) * factor];
+
+            buf[pixel] = match self.mode {
+                AoldaqBinMode::None | AoldaqBinMode::Decimate => bin[0],
+                AoldaqBinMode::Sum => bin.iter().map(|&s| s as u64).sum::<u64>().min(u32::MAX as u64) as u32,
+                AoldaqBinMode::Mean => {
+                    let sum = bin.iter().map(|&s| s as u64).sum::<u64>();
+                    ((sum + factor as u64 / 2) / factor as u64) as u32
+                }
+                AoldaqBinMode::Max => bin.iter().copied().max().unwrap_or(0),
+            };
+        }
+
+        pixels
+    }
+}
+
+impl std::str::FromStr for AoldaqBinning {
+    type Err = AoldaqError;
+
+    /// Parses `mode:factor`, like `mean:4`, or just `none`.
+    fn from_str(s: &str) -> Result<AoldaqBinning, AoldaqError> {
+        let mut parts = s.splitn(2, ':');
+        let mode = parts.next().unwrap_or("").trim().parse()?;
+        let factor = match parts.next() {
+            Some(factor) => factor.trim().parse()
+                .map_err(|_| AoldaqError::InvalidArgument(format!("invalid binning factor in {:?}", s)))?,
+            None if mode == AoldaqBinMode::None => 1,
+            None => return Err(AoldaqError::InvalidArgument(format!("binning {:?} needs a factor, like mean:4", s))),
+        };
+
+        Ok(AoldaqBinning { mode, factor })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_binning() {
+        let samples = [1, 2, 3, 7, 5, 6, 0, u32::MAX];
+        let bin = |binning: &str| {
+            let mut buf = samples.to_vec();
+            let n = binning.parse::<AoldaqBinning>().unwrap().apply(&mut buf);
+            buf[..n].to_vec()
+        };
+
+        assert_eq!(bin("none"), samples.to_vec());
+        assert_eq!(bin("sum:2"), vec![3, 10, 11, u32::MAX]);
+        assert_eq!(bin("mean:4"), vec![3, 1073741827]);
+        assert_eq!(bin("max:2"), vec![2, 7, 6, u32::MAX]);
+        assert_eq!(bin("decimate:4"), vec![1, 5]);
+
+        assert!("mean".parse::<AoldaqBinning>().is_err());
+        assert!("median:2".parse::<AoldaqBinning>().is_err());
+    }
+}
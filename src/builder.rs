@@ -0,0 +1,538 @@
+use std::convert::TryFrom;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::{ Aoldaq, AoldaqBinMode, AoldaqBinning, AoldaqError, AoldaqField, AoldaqHistogram, AoldaqMode, AoldaqTriggerMode, ShmOptions, UnpackOptions };
+use crate::device::{ CounterDevice, Device, RandomDevice, NiFpgaDevice };
+use crate::frame::FrameOptions;
+use crate::trigger::TriggerOptions;
+
+/// 4GB worth of points. If needed, can safely be increased.
+pub const DEFAULT_FIFO_CAPACITY: usize = 4 * 268435456;
+
+/// Settings of an instance that don't depend on the device.
+#[derive(Clone, Debug)]
+pub struct AoldaqOptions {
+    pub n_channels: usize,
+    /// Amount of points read from the device at once, per channel.
+    pub block_size: usize,
+    /// Capacity of the software FIFO of each channel, in points.
+    pub fifo_capacity: usize,
+    pub trigger: TriggerOptions,
+    /// Frame geometry and processing, needed to read frames with
+    /// `Aoldaq::get_frame_into`.
+    pub frame: Option<FrameOptions>,
+    /// Binning of each channel, or a single one for all of them. Empty doesn't
+    /// bin. With binning, the FIFOs, the trigger and every amount of points
+    /// other than the block size count pixels.
+    pub binning: Vec<AoldaqBinning>,
+    /// Unpacking of the device FIFOs into the channels. Without it, each
+    /// channel is the whole words of the device FIFO with its index.
+    pub unpack: Option<UnpackOptions>,
+    /// Live histogram of each channel, read with `Aoldaq::get_histogram_into`.
+    pub histogram: Option<AoldaqHistogram>,
+    /// Latest points of each channel kept for `Aoldaq::get_preview_into`.
+    /// Zero keeps none.
+    pub preview: usize,
+    /// Shared-memory rings that other processes can read the channels from.
+    pub shm: Option<ShmOptions>,
+}
+
+impl AoldaqOptions {
+    /// Amount of device FIFOs read.
+    pub fn n_fifos(&self) -> usize {
+        match self.unpack {
+            Some(ref unpack) => unpack.n_fifos(),
+            None => self.n_channels,
+        }
+    }
+
+    /// Device FIFO `channel` comes from.
+    pub fn channel_fifo(&self, channel: usize) -> usize {
+        self.channel_field(channel).map_or(channel, |field| field.fifo)
+    }
+
+    /// Bit field of `channel`, if it doesn't take the whole words.
+    pub fn channel_field(&self, channel: usize) -> Option<AoldaqField> {
+        self.unpack.as_ref().map(|unpack| unpack.fields[channel])
+    }
+
+    /// Mask of the marker bits of the device FIFO `fifo`.
+    pub fn marker_mask(&self, fifo: usize) -> u32 {
+        self.unpack.as_ref().and_then(|unpack| unpack.markers.get(fifo).copied()).unwrap_or(0)
+    }
+
+    /// Points per event of the channels, which are only cut in whole events:
+    /// two in photon counting, as the events are U64 words, and one otherwise.
+    pub fn event_points(&self) -> usize {
+        match self.frame {
+            Some(ref frame) if frame.photon.is_some() => 2,
+            _ => 1,
+        }
+    }
+
+    /// Binning of `channel`.
+    pub fn channel_binning(&self, channel: usize) -> AoldaqBinning {
+        match self.binning.len() {
+            0 => AoldaqBinning::default(),
+            1 => self.binning[0],
+            _ => self.binning[channel],
+        }
+    }
+}
+
+impl Default for AoldaqOptions {
+    fn default() -> AoldaqOptions {
+        AoldaqOptions {
+            n_channels: 1,
+            block_size: 4000,
+            fifo_capacity: DEFAULT_FIFO_CAPACITY,
+            trigger: TriggerOptions::default(),
+            frame: None,
+            binning: Vec::new(),
+            unpack: None,
+            histogram: None,
+            preview: 0,
+            shm: None,
+        }
+    }
+}
+
+/// Settings of the `NiFpga` mode.
+#[derive(Clone, Debug, Default)]
+pub struct NiFpgaOptions {
+    pub bitfile: PathBuf,
+    pub signature: String,
+    pub resource: String,
+    pub attribute: u32,
+    /// DMA FIFO of each channel. If empty, channel `i` reads FIFO `i`.
+    pub addrs: Vec<u32>,
+    /// Depth of the host side of the DMA FIFOs, in points. If `None`, the
+    /// NiFpga default is kept.
+    pub fifo_depth: Option<usize>,
+    /// IRQ asserted by the bitfile when the acquisition should start, used by
+    /// `AoldaqTriggerMode::Fpga` when there is no `trigger_register`.
+    pub trigger_irq: u32,
+    /// Register that becomes non-zero when the acquisition should start.
+    pub trigger_register: Option<u32>,
+    /// U64 indicator with the FPGA tick count, read after every block.
+    pub tick_counter: Option<u32>,
+    /// The DMA FIFOs hold U64 words, like photon events, each read as two
+    /// points, low half first. The block size must then be even.
+    pub u64_fifos: bool,
+}
+
+/// Builds an `Aoldaq` instance from owned, validated settings.
+///
+/// ```no_run
+/// use aoldaq::{ AoldaqBuilder, AoldaqMode, NiFpgaOptions };
+///
+/// let aoldaq = AoldaqBuilder::new(AoldaqMode::NiFpga)
+///     .channels(2)
+///     .block_size(4000)
+///     .nifpga(NiFpgaOptions {
+///         bitfile: "C:\\aol\\aol.lvbitx".into(),
+///         signature: "0123456789ABCDEF".to_string(),
+///         resource: "RIO0".to_string(),
+///         addrs: vec![1, 2],
+///         ..NiFpgaOptions::default()
+///     })
+///     .build()
+///     .unwrap();
+/// ```
+pub struct AoldaqBuilder {
+    mode: AoldaqMode,
+    options: AoldaqOptions,
+    nifpga: Option<NiFpgaOptions>,
+    device: Option<Box<dyn Device>>,
+}
+
+impl AoldaqBuilder {
+    pub fn new(mode: AoldaqMode) -> AoldaqBuilder {
+        AoldaqBuilder {
+            mode,
+            options: AoldaqOptions::default(),
+            nifpga: None,
+            device: None,
+        }
+    }
+
+    pub fn mode(mut self, mode: AoldaqMode) -> AoldaqBuilder {
+        self.mode = mode;
+        self
+    }
+
+    pub fn channels(mut self, n_channels: usize) -> AoldaqBuilder {
+        self.options.n_channels = n_channels;
+        self
+    }
+
+    pub fn block_size(mut self, block_size: usize) -> AoldaqBuilder {
+        self.options.block_size = block_size;
+        self
+    }
+
+    pub fn fifo_capacity(mut self, fifo_capacity: usize) -> AoldaqBuilder {
+        self.options.fifo_capacity = fifo_capacity;
+        self
+    }
+
+    pub fn trigger(mut self, mode: AoldaqTriggerMode) -> AoldaqBuilder {
+        self.options.trigger.mode = mode;
+        self
+    }
+
+    /// Amount of points per channel from before the trigger delivered ahead
+    /// of the triggered data.
+    pub fn pre_trigger(mut self, pre_trigger: usize) -> AoldaqBuilder {
+        self.options.trigger.pre_trigger = pre_trigger;
+        self
+    }
+
+    /// Marker of `AoldaqTriggerMode::Marker`: the first sample for which
+    /// `sample & mask == value`.
+    pub fn marker(mut self, value: u32, mask: u32) -> AoldaqBuilder {
+        self.options.trigger.marker_value = value;
+        self.options.trigger.marker_mask = mask;
+        self
+    }
+
+    /// Bins every channel the same way.
+    pub fn binning(mut self, binning: AoldaqBinning) -> AoldaqBuilder {
+        self.options.binning = vec![binning];
+        self
+    }
+
+    /// Bins each channel its own way, one binning per channel.
+    pub fn channel_binnings(mut self, binning: Vec<AoldaqBinning>) -> AoldaqBuilder {
+        self.options.binning = binning;
+        self
+    }
+
+    pub fn unpack(mut self, unpack: UnpackOptions) -> AoldaqBuilder {
+        self.options.unpack = Some(unpack);
+        self
+    }
+
+    pub fn histogram(mut self, histogram: AoldaqHistogram) -> AoldaqBuilder {
+        self.options.histogram = Some(histogram);
+        self
+    }
+
+    /// Keeps the latest `preview` points of each channel aside, for a live view.
+    pub fn preview(mut self, preview: usize) -> AoldaqBuilder {
+        self.options.preview = preview;
+        self
+    }
+
+    /// Also writes every channel to a shared-memory ring.
+    pub fn shm(mut self, shm: ShmOptions) -> AoldaqBuilder {
+        self.options.shm = Some(shm);
+        self
+    }
+
+    pub fn frame(mut self, frame: FrameOptions) -> AoldaqBuilder {
+        self.options.frame = Some(frame);
+        self
+    }
+
+    pub fn nifpga(mut self, nifpga: NiFpgaOptions) -> AoldaqBuilder {
+        self.nifpga = Some(nifpga);
+        self
+    }
+
+    /// Device of `AoldaqMode::Custom`.
+    pub fn device(mut self, device: Box<dyn Device>) -> AoldaqBuilder {
+        self.device = Some(device);
+        self
+    }
+
+    pub fn get_options(&self) -> &AoldaqOptions {
+        &self.options
+    }
+
+    pub fn get_nifpga_options(&self) -> Option<&NiFpgaOptions> {
+        self.nifpga.as_ref()
+    }
+
+    /// Sets the option named `key` from its textual `value`, as found in
+    /// config files. NiFpga, unpacking, histogram, shared memory and frame
+    /// options create their settings if needed.
+    pub fn set(mut self, key: &str, value: &str) -> Result<AoldaqBuilder, AoldaqError> {
+        let value = value.trim();
+
+        if let Some(nifpga) = key.strip_prefix("nifpga.") {
+            let mut options = self.nifpga.take().unwrap_or_default();
+
+            match nifpga {
+                "bitfile" => options.bitfile = PathBuf::from(value),
+                "signature" => options.signature = value.to_string(),
+                "resource" => options.resource = value.to_string(),
+                "attribute" => options.attribute = parse(key, value)?,
+                "addrs" => options.addrs = value
+                    .split(',')
+                    .filter(|addr| !addr.trim().is_empty())
+                    .map(|addr| parse(key, addr))
+                    .collect::<Result<_, _>>()?,
+                "fifo_depth" => options.fifo_depth = Some(parse(key, value)?),
+                "trigger_irq" => options.trigger_irq = parse(key, value)?,
+                "trigger_register" => options.trigger_register = Some(parse(key, value)?),
+                "tick_counter" => options.tick_counter = Some(parse(key, value)?),
+                "u64_fifos" => options.u64_fifos = parse_bool(key, value)?,
+                _ => return Err(AoldaqError::InvalidArgument(format!("unknown option {}", key))),
+            }
+
+            self.nifpga = Some(options);
+            return Ok(self);
+        }
+
+        if let Some(unpack) = key.strip_prefix("unpack.") {
+            let mut options = self.options.unpack.take().unwrap_or_default();
+            let items = value.split(',').filter(|item| !item.trim().is_empty());
+
+            match unpack {
+                "fields" => options.fields = items.map(|field| field.parse()).collect::<Result<_, _>>()?,
+                "markers" => options.markers = items.map(|mask| parse(key, mask)).collect::<Result<_, _>>()?,
+                _ => return Err(AoldaqError::InvalidArgument(format!("unknown option {}", key))),
+            }
+
+            self.options.unpack = Some(options);
+            return Ok(self);
+        }
+
+        if let Some(histogram) = key.strip_prefix("histogram.") {
+            let mut options = self.options.histogram.take().unwrap_or_default();
+
+            match histogram {
+                "bins" => options.bins = parse(key, value)?,
+                "min" => options.min = parse(key, value)?,
+                "max" => options.max = parse(key, value)?,
+                "window" => options.window = parse(key, value)?,
+                "saturation" => options.saturation = parse(key, value)?,
+                _ => return Err(AoldaqError::InvalidArgument(format!("unknown option {}", key))),
+            }
+
+            self.options.histogram = Some(options);
+            return Ok(self);
+        }
+
+        if let Some(shm) = key.strip_prefix("shm.") {
+            let mut options = self.options.shm.take().unwrap_or_default();
+
+            match shm {
+                "name" => options.name = value.to_string(),
+                "capacity" => options.capacity = parse(key, value)?,
+                _ => return Err(AoldaqError::InvalidArgument(format!("unknown option {}", key))),
+            }
+
+            self.options.shm = Some(options);
+            return Ok(self);
+        }
+
+        if let Some(photon) = key.strip_prefix("photon.") {
+            let mut frame = self.options.frame.take().unwrap_or_default();
+            let mut options = frame.photon.take().unwrap_or_default();
+
+            match photon {
+                "channel_shift" => options.channel_shift = parse(key, value)?,
+                "channel_bits" => options.channel_bits = parse(key, value)?,
+                "time_shift" => options.time_shift = parse(key, value)?,
+                "time_bits" => options.time_bits = parse(key, value)?,
+                "pixel_channel" => options.pixel_channel = Some(parse(key, value)?),
+                "pixel_time" => options.pixel_time = parse(key, value)?,
+                "line_channel" => options.line_channel = parse(key, value)?,
+                "frame_channel" => options.frame_channel = Some(parse(key, value)?),
+                "sync_channel" => options.sync_channel = Some(parse(key, value)?),
+                "lifetime_bins" => options.lifetime_bins = parse(key, value)?,
+                "lifetime_bin_width" => options.lifetime_bin_width = parse(key, value)?,
+                _ => return Err(AoldaqError::InvalidArgument(format!("unknown option {}", key))),
+            }
+
+            frame.photon = Some(options);
+            self.options.frame = Some(frame);
+            return Ok(self);
+        }
+
+        if let Some(frame) = key.strip_prefix("frame.") {
+            let mut options = self.options.frame.take().unwrap_or_default();
+
+            match frame {
+                "width" => options.width = parse(key, value)?,
+                "height" => options.height = parse(key, value)?,
+                "bidirectional" => options.bidirectional = parse_bool(key, value)?,
+                "phase" => options.phase = value.parse()
+                    .map_err(|_| AoldaqError::InvalidArgument(format!("invalid value {:?} for {}", value, key)))?,
+                "auto_phase" => options.auto_phase = parse_bool(key, value)?,
+                "average" => options.average = value.parse()?,
+                "average_count" => options.average_count = parse(key, value)?,
+                "record" => options.record = Some(PathBuf::from(value)),
+                "sync_mask" => options.sync_mask = parse(key, value)?,
+                "sync_value" => options.sync_value = parse(key, value)?,
+                "sync_sentinel" => options.sync_sentinel = parse_bool(key, value)?,
+                "sync_markers" => options.sync_markers = parse_bool(key, value)?,
+                _ => return Err(AoldaqError::InvalidArgument(format!("unknown option {}", key))),
+            }
+
+            self.options.frame = Some(options);
+            return Ok(self);
+        }
+
+        Ok(match key {
+            "mode" => self.mode(value.parse()?),
+            "channels" => self.channels(parse(key, value)?),
+            "block_size" => self.block_size(parse(key, value)?),
+            "fifo_capacity" => self.fifo_capacity(parse(key, value)?),
+            "trigger" => self.trigger(value.parse()?),
+            "binning" => self.channel_binnings(value
+                .split(',')
+                .filter(|binning| !binning.trim().is_empty())
+                .map(|binning| binning.parse())
+                .collect::<Result<_, _>>()?),
+            "pre_trigger" => self.pre_trigger(parse(key, value)?),
+            "preview" => self.preview(parse(key, value)?),
+            "marker_value" => {
+                self.options.trigger.marker_value = parse(key, value)?;
+                self
+            }
+            "marker_mask" => {
+                self.options.trigger.marker_mask = parse(key, value)?;
+                self
+            }
+            _ => return Err(AoldaqError::InvalidArgument(format!("unknown option {}", key))),
+        })
+    }
+
+    pub fn build(self) -> Result<Aoldaq, AoldaqError> {
+        crate::init_logging();
+
+        let options = &self.options;
+        validate(options)?;
+
+        let device = match self.mode {
+            AoldaqMode::Random => Arc::new(RandomDevice::new()) as Arc<dyn Device>,
+            AoldaqMode::Counter => Arc::new(CounterDevice::new(options.n_fifos())) as Arc<dyn Device>,
+            AoldaqMode::NiFpga => {
+                let nifpga = self.nifpga.as_ref().ok_or(AoldaqError::MissingOptions("NiFpga"))?;
+
+                if !nifpga.addrs.is_empty() && nifpga.addrs.len() != options.n_fifos() {
+                    return Err(AoldaqError::InvalidArgument(format!(
+                        "{} FIFO addresses for {} FIFOs", nifpga.addrs.len(), options.n_fifos())));
+                }
+
+                if nifpga.u64_fifos && options.block_size % 2 != 0 {
+                    return Err(AoldaqError::InvalidArgument(format!(
+                        "block size {} does not hold whole U64 words", options.block_size)));
+                }
+
+                Arc::new(NiFpgaDevice::new(nifpga, options.n_fifos(), false)?) as Arc<dyn Device>
+            }
+            AoldaqMode::Custom => Arc::from(self.device.ok_or(AoldaqError::MissingOptions("device"))?),
+        };
+
+        Aoldaq::new(self.options, device)
+    }
+}
+
+/// Parses a number of a config option, in decimal or `0x` hexadecimal.
+fn parse<T: TryFrom<u64>>(key: &str, value: &str) -> Result<T, AoldaqError> {
+    let value = value.trim();
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+
+    parsed.ok()
+        .and_then(|v| T::try_from(v).ok())
+        .ok_or_else(|| AoldaqError::InvalidArgument(format!("invalid value {:?} for {}", value, key)))
+}
+
+/// Parses a boolean config option: `true`, `false`, `yes`, `no`, `1` or `0`.
+fn parse_bool(key: &str, value: &str) -> Result<bool, AoldaqError> {
+    match value.trim().to_lowercase().as_str() {
+        "true" | "yes" | "1" => Ok(true),
+        "false" | "no" | "0" => Ok(false),
+        _ => Err(AoldaqError::InvalidArgument(format!("invalid value {:?} for {}", value, key))),
+    }
+}
+
+pub(crate) fn validate(options: &AoldaqOptions) -> Result<(), AoldaqError> {
+    if options.n_channels == 0 {
+        return Err(AoldaqError::InvalidArgument("at least one channel is needed".to_string()));
+    }
+
+    if options.block_size == 0 || options.fifo_capacity < options.block_size {
+        return Err(AoldaqError::InvalidArgument(format!(
+            "block size {} does not fit a FIFO of {} points", options.block_size, options.fifo_capacity)));
+    }
+
+    if options.binning.len() > 1 && options.binning.len() != options.n_channels {
+        return Err(AoldaqError::InvalidArgument(format!(
+            "{} binnings for {} channels", options.binning.len(), options.n_channels)));
+    }
+
+    for binning in &options.binning {
+        let factor = binning.factor();
+        if factor == 0 || options.block_size % factor != 0 {
+            return Err(AoldaqError::InvalidArgument(format!(
+                "binning factor {} does not divide the block size {}", factor, options.block_size)));
+        }
+    }
+
+    if let Some(ref unpack) = options.unpack {
+        unpack.validate(options.n_channels)?;
+    }
+
+    if let Some(ref histogram) = options.histogram {
+        histogram.validate()?;
+    }
+
+    if let Some(ref shm) = options.shm {
+        shm.validate()?;
+    }
+
+    if let Some(ref frame) = options.frame {
+        if frame.width == 0 || frame.height == 0 {
+            return Err(AoldaqError::InvalidArgument(format!("invalid frame size {}x{}", frame.width, frame.height)));
+        }
+
+        match frame.photon {
+            // Photon frames are decoded as the events arrive, so they don't need to fit
+            Some(_) if frame.sync_mask != 0 || frame.sync_markers => {
+                return Err(AoldaqError::InvalidArgument("photon frames start on the frame clock, not on the frame sync".to_string()));
+            }
+            // The lifetime histograms add up several raw frames, each corrected
+            // with its own phase
+            Some(_) if frame.bidirectional => {
+                return Err(AoldaqError::InvalidArgument("photon frames can't be corrected for bidirectional scans".to_string()));
+            }
+            // Binned or unpacked points would no longer be halves of event words
+            Some(_) if options.binning.iter().any(|binning| binning.mode != AoldaqBinMode::None) => {
+                return Err(AoldaqError::InvalidArgument("photon events can't be binned".to_string()));
+            }
+            Some(_) if options.unpack.is_some() => {
+                return Err(AoldaqError::InvalidArgument("photon events can't be unpacked".to_string()));
+            }
+            // The blocks and the pre-trigger window hold whole events, and a
+            // marker could be on either half of one
+            Some(_) if options.block_size % 2 != 0 => {
+                return Err(AoldaqError::InvalidArgument(format!(
+                    "block size {} does not hold whole photon events", options.block_size)));
+            }
+            Some(_) if options.trigger.pre_trigger % 2 != 0 => {
+                return Err(AoldaqError::InvalidArgument(format!(
+                    "pre-trigger window of {} points does not hold whole photon events", options.trigger.pre_trigger)));
+            }
+            Some(_) if options.trigger.mode == AoldaqTriggerMode::Marker => {
+                return Err(AoldaqError::InvalidArgument("photon events can't trigger on markers".to_string()));
+            }
+            Some(ref photon) => photon.validate()?,
+            None if options.fifo_capacity < frame.width * frame.height => {
+                return Err(AoldaqError::InvalidArgument(format!(
+                    "frames of {}x{} do not fit a FIFO of {} points", frame.width, frame.height, options.fifo_capacity)));
+            }
+            None => {}
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,391 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::{ Arc, Condvar, Mutex };
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use std::thread::JoinHandle;
+use std::time::{ Duration, Instant };
+
+use crossbeam_channel::{ Receiver, Sender };
+
+/// Events delivered to the registered callbacks.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AoldaqEvent {
+    /// The software FIFO of the channel crossed the callback threshold. The
+    /// value is the amount of points in the FIFO.
+    DataReady,
+    Started,
+    Stopped,
+    Triggered,
+    /// The finite acquisition delivered all of its points.
+    Done,
+    /// A device operation failed on the channel. The value is the device status.
+    Error,
+    /// A point with marker bits reached the FIFO of the channel. The value is
+    /// its marker bits.
+    Marker,
+}
+
+/// Callback signature of the C API. Called from the dispatcher thread with the
+/// `user_data` given at registration.
+pub type AoldaqCallback = extern fn(user_data: *mut std::os::raw::c_void, channel: usize, event: AoldaqEvent, value: i64);
+
+type Callback = Box<dyn FnMut(usize, AoldaqEvent, i64) + Send>;
+
+/// Taken out on unregistration, so a copy of the registration still held by
+/// the dispatcher thread doesn't call it anymore.
+type SharedCallback = Arc<Mutex<Option<Callback>>>;
+
+thread_local! {
+    /// Set on the dispatcher threads, where a callback may be running.
+    static DISPATCHING: Cell<bool> = const { Cell::new(false) };
+}
+
+#[derive(Clone)]
+struct Registration {
+    id: usize,
+    channel: usize,
+    threshold: usize,
+    callback: SharedCallback,
+    /// Set on unregistration from a callback, when the callback can't be taken
+    /// out because it is the one running.
+    removed: Arc<AtomicBool>,
+}
+
+impl Registration {
+    fn call(&self, channel: usize, event: AoldaqEvent, value: i64) {
+        if self.removed.load(Ordering::SeqCst) {
+            return;
+        }
+
+        if let Some(callback) = self.callback.lock().unwrap().as_mut() {
+            callback(channel, event, value);
+        }
+    }
+}
+
+enum Notification {
+    Data { channel: usize, before: usize, after: usize },
+    Event { channel: Option<usize>, event: AoldaqEvent, value: i64 },
+    Shutdown,
+}
+
+/// Handle used by the producer threads and the acquisition state to report
+/// what happened. Nothing is sent while there are no registered callbacks.
+#[derive(Clone)]
+pub struct Notifier {
+    tx: Sender<Notification>,
+    active: Arc<AtomicBool>,
+    /// Tasks of the async API waiting for data, by channel.
+    #[cfg(feature = "async")]
+    wakers: Arc<Mutex<HashMap<usize, std::task::Waker>>>,
+    /// Threads blocked in `wait_data`.
+    blocks: Arc<BlockSignal>,
+}
+
+/// Wakes the threads waiting for data whenever a block reaches a FIFO.
+#[derive(Default)]
+struct BlockSignal {
+    count: Mutex<u64>,
+    cond: Condvar,
+    waiters: AtomicUsize,
+}
+
+impl Notifier {
+    /// Reports that the FIFO of `channel` went from `before` to `after` points.
+    pub fn data(&self, channel: usize, before: usize, after: usize) {
+        #[cfg(feature = "async")]
+        self.wake(Some(channel));
+
+        // Only costs a lock while someone waits
+        if self.blocks.waiters.load(Ordering::SeqCst) > 0 {
+            *self.blocks.count.lock().unwrap() += 1;
+            self.blocks.cond.notify_all();
+        }
+
+        if self.active.load(Ordering::Relaxed) {
+            self.tx.send(Notification::Data { channel, before, after }).unwrap_or(());
+        }
+    }
+
+    /// Reports an event concerning every channel.
+    pub fn event(&self, event: AoldaqEvent) {
+        #[cfg(feature = "async")]
+        self.wake(None);
+
+        if self.active.load(Ordering::Relaxed) {
+            self.tx.send(Notification::Event { channel: None, event, value: 0 }).unwrap_or(());
+        }
+    }
+
+    pub fn marker(&self, channel: usize, flags: u32) {
+        if self.active.load(Ordering::Relaxed) {
+            self.tx.send(Notification::Event { channel: Some(channel), event: AoldaqEvent::Marker, value: flags as i64 }).unwrap_or(());
+        }
+    }
+
+    pub fn error(&self, channel: usize, status: i32) {
+        if self.active.load(Ordering::Relaxed) {
+            self.tx.send(Notification::Event { channel: Some(channel), event: AoldaqEvent::Error, value: status as i64 }).unwrap_or(());
+        }
+    }
+
+    /// Blocks until `ready` returns true, checking it again whenever a block
+    /// reaches a FIFO, for at most `timeout`. Returns the last result of `ready`.
+    pub fn wait_data<F: Fn() -> bool>(&self, timeout: Duration, ready: F) -> bool {
+        let deadline = Instant::now() + timeout;
+        self.blocks.waiters.fetch_add(1, Ordering::SeqCst);
+
+        let mut count = self.blocks.count.lock().unwrap();
+        let ready = loop {
+            if ready() {
+                break true;
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                break false;
+            }
+
+            count = self.blocks.cond.wait_timeout(count, deadline - now).unwrap().0;
+        };
+
+        drop(count);
+        self.blocks.waiters.fetch_sub(1, Ordering::SeqCst);
+        ready
+    }
+
+    /// Makes the next data notification of `channel` wake `waker`.
+    #[cfg(feature = "async")]
+    pub fn register_waker(&self, channel: usize, waker: &std::task::Waker) {
+        self.wakers.lock().unwrap().insert(channel, waker.clone());
+    }
+
+    /// Wakes the task waiting on `channel`, or every task.
+    #[cfg(feature = "async")]
+    fn wake(&self, channel: Option<usize>) {
+        let mut wakers = self.wakers.lock().unwrap();
+
+        match channel {
+            Some(channel) => {
+                if let Some(waker) = wakers.remove(&channel) {
+                    waker.wake();
+                }
+            }
+            None => wakers.drain().for_each(|(_, waker)| waker.wake()),
+        }
+    }
+}
+
+/// Owns the registered callbacks and the thread that calls them. The thread is
+/// only spawned when the first callback is registered.
+pub struct Dispatcher {
+    notifier: Notifier,
+    rx: Receiver<Notification>,
+    registrations: Arc<Mutex<Vec<Registration>>>,
+    next_id: AtomicUsize,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Dispatcher {
+    pub fn new() -> Dispatcher {
+        let (tx, rx) = crossbeam_channel::unbounded();
+
+        Dispatcher {
+            notifier: Notifier {
+                tx,
+                active: Arc::new(AtomicBool::new(false)),
+                #[cfg(feature = "async")]
+                wakers: Arc::new(Mutex::new(HashMap::new())),
+                blocks: Arc::new(BlockSignal::default()),
+            },
+            rx,
+            registrations: Arc::new(Mutex::new(Vec::new())),
+            next_id: AtomicUsize::new(1),
+            thread: Mutex::new(None),
+        }
+    }
+
+    pub fn notifier(&self) -> Notifier {
+        self.notifier.clone()
+    }
+
+    /// Registers `callback` for the data of `channel`, returning its id.
+    /// `DataReady` is delivered whenever the FIFO of `channel` goes from less
+    /// than `threshold` points to `threshold` points or more. Can be called
+    /// from a callback, which doesn't see the new callback until it returns.
+    pub fn register(&self, channel: usize, threshold: usize, callback: Callback) -> usize {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+
+        self.registrations.lock().unwrap().push(Registration {
+            id,
+            channel,
+            threshold: threshold.max(1),
+            callback: Arc::new(Mutex::new(Some(callback))),
+            removed: Arc::new(AtomicBool::new(false)),
+        });
+
+        let mut thread = self.thread.lock().unwrap();
+        if thread.is_none() {
+            let rx = self.rx.clone();
+            let registrations = self.registrations.clone();
+            *thread = Some(std::thread::spawn(move || dispatch(rx, registrations)));
+        }
+
+        self.notifier.active.store(true, Ordering::SeqCst);
+
+        id
+    }
+
+    /// Removes the callback with `id`. Once this returns, the callback will not
+    /// be called again and is not running, unless this is called from a
+    /// callback: then it doesn't wait, as the running callback could be the
+    /// caller.
+    pub fn unregister(&self, id: usize) -> bool {
+        let removed = {
+            let mut registrations = self.registrations.lock().unwrap();
+            let removed = registrations.iter().position(|r| r.id == id).map(|i| registrations.remove(i));
+
+            if registrations.is_empty() {
+                self.notifier.active.store(false, Ordering::SeqCst);
+            }
+
+            removed
+        };
+
+        let r = match removed {
+            Some(r) => r,
+            None => return false,
+        };
+
+        r.removed.store(true, Ordering::SeqCst);
+
+        if DISPATCHING.with(Cell::get) {
+            // The running callback holds its lock, and is dropped with the
+            // last copy of its registration
+            if let Ok(mut callback) = r.callback.try_lock() {
+                callback.take();
+            }
+
+            true
+        } else {
+            // Waits for the callback to return, if it's running
+            r.callback.lock().unwrap().take().is_some()
+        }
+    }
+}
+
+impl Drop for Dispatcher {
+    fn drop(&mut self) {
+        self.notifier.active.store(false, Ordering::SeqCst);
+
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            self.notifier.tx.send(Notification::Shutdown).unwrap_or(());
+            thread.join().unwrap();
+        }
+    }
+}
+
+/// Copies the registrations, so the callbacks run without holding the list and
+/// can register others.
+fn snapshot(registrations: &Mutex<Vec<Registration>>) -> Vec<Registration> {
+    registrations.lock().unwrap().clone()
+}
+
+/// Calls back for the coalesced data notifications in `data`, emptying it.
+fn data_ready(registrations: &Mutex<Vec<Registration>>, data: &mut HashMap<usize, (usize, usize)>) {
+    for (channel, (before, after)) in data.drain() {
+        for r in snapshot(registrations) {
+            if r.channel == channel && before < r.threshold && after >= r.threshold {
+                r.call(channel, AoldaqEvent::DataReady, after as i64);
+            }
+        }
+    }
+}
+
+fn dispatch(rx: Receiver<Notification>, registrations: Arc<Mutex<Vec<Registration>>>) {
+    log::debug!("Callback dispatcher started");
+    DISPATCHING.with(|dispatching| dispatching.set(true));
+
+    while let Ok(first) = rx.recv() {
+        let mut shutdown = false;
+        // Coalesce the data notifications that piled up while calling back
+        let mut data: HashMap<usize, (usize, usize)> = HashMap::new();
+
+        for notification in std::iter::once(first).chain(rx.try_iter()) {
+            match notification {
+                Notification::Data { channel, before, after } => {
+                    let entry = data.entry(channel).or_insert((before, after));
+                    entry.0 = entry.0.min(before);
+                    entry.1 = after;
+                }
+                Notification::Event { channel, event, value } => {
+                    // The data that came before goes first, so `Done` comes
+                    // after the last `DataReady`
+                    data_ready(&registrations, &mut data);
+
+                    // Events of a channel only go to its callbacks
+                    for r in snapshot(&registrations).iter().filter(|r| channel.map_or(true, |channel| channel == r.channel)) {
+                        r.call(r.channel, event, value);
+                    }
+                }
+                Notification::Shutdown => shutdown = true,
+            }
+        }
+
+        data_ready(&registrations, &mut data);
+
+        if shutdown {
+            break;
+        }
+    }
+
+    log::debug!("Callback dispatcher finished");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unregister_from_callback() {
+        let dispatcher = Arc::new(Dispatcher::new());
+        let notifier = dispatcher.notifier();
+        let calls = Arc::new([AtomicUsize::new(0), AtomicUsize::new(0)]);
+
+        let other = {
+            let calls = calls.clone();
+            dispatcher.register(0, 1, Box::new(move |_, _, _| { calls[1].fetch_add(1, Ordering::SeqCst); }))
+        };
+
+        // Removes itself and the other callback on its first call
+        let id = Arc::new(AtomicUsize::new(0));
+        let this = {
+            let (calls, id) = (calls.clone(), id.clone());
+            let weak = Arc::downgrade(&dispatcher);
+            dispatcher.register(0, 1, Box::new(move |_, _, _| {
+                calls[0].fetch_add(1, Ordering::SeqCst);
+                let dispatcher = weak.upgrade().unwrap();
+                assert!(dispatcher.unregister(id.load(Ordering::SeqCst)));
+                assert!(dispatcher.unregister(other));
+            }))
+        };
+        id.store(this, Ordering::SeqCst);
+
+        for _ in 0..3 {
+            notifier.event(AoldaqEvent::Started);
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while calls[0].load(Ordering::SeqCst) == 0 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        std::thread::sleep(Duration::from_millis(20));
+
+        // The other callback may have run for the first event, before removal
+        assert_eq!(calls[0].load(Ordering::SeqCst), 1);
+        assert!(calls[1].load(Ordering::SeqCst) <= 1);
+        assert!(!dispatcher.unregister(this));
+        assert!(!dispatcher.unregister(other));
+    }
+}
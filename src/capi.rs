@@ -1,10 +1,319 @@
-use super::{ Aoldaq, AoldaqArgs };
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::path::PathBuf;
 
-/// Creates an AOLDAQ instance.
+use super::{ Aoldaq, AoldaqBlockInfo, AoldaqBuilder, AoldaqCallback, AoldaqDeviceVtable, AoldaqAverage, AoldaqError, AoldaqBinning, AoldaqField, AoldaqFrameInfo, AoldaqHistogram, AoldaqHistogramStats, AoldaqMarker, AoldaqMode, AoldaqOverflow, AoldaqReader, AoldaqPreview, AoldaqShmReader, AoldaqStats, AoldaqTriggerMode, AoldaqVerifyReport, CounterVerifier, FrameOptions, NiFpgaOptions, PhotonOptions, ShmOptions, UnpackOptions };
+use super::device::CDevice;
+
+/// Settings of `aoldaq_create_instance`. Since `struct_size` was added in
+/// front, structs laid out for older versions are rejected: set it to
+/// `sizeof(aoldaq_args_t)`, and likewise in the nested structs that have one.
+#[repr(C)]
+pub struct AoldaqArgs {
+    /// `sizeof` the struct, as seen by the caller. Structs from another version
+    /// of aoldaq.h are rejected instead of misread.
+    pub struct_size: usize,
+    pub block_size: usize,
+    pub n_channels: usize,
+    pub mode: AoldaqMode,
+    pub nifpga: *const NiFpgaArgs,
+    /// Optional trigger configuration. If null, the acquisition starts immediately.
+    pub trigger: *const AoldaqTriggerArgs,
+    /// Capacity of the software FIFO of each channel, in points. Zero keeps the
+    /// default of 1G points.
+    pub fifo_capacity: usize,
+    /// Device of `AOLDAQ_MODE_CUSTOM`. The vtable is copied, and its `destroy`
+    /// function is called when the instance is destroyed, or right away if the
+    /// instance couldn't be created, unless `struct_size` was wrong.
+    pub device: *const AoldaqDeviceVtable,
+    /// Optional frame settings, needed by `aoldaq_get_frame`.
+    pub frame: *const AoldaqFrameArgs,
+    /// Binning of each channel, or a single one for all of them. Null or zero
+    /// `n_binning` doesn't bin.
+    pub binning: *const AoldaqBinning,
+    pub n_binning: usize,
+    /// Optional bit field of each channel, `n_channels` of them, to feed
+    /// several channels from one device FIFO. If null, each channel is the
+    /// whole words of the device FIFO with its index.
+    pub fields: *const AoldaqField,
+    /// Optional mask of the marker bits of each device FIFO, `n_markers` of
+    /// them. Only used with `fields`.
+    pub markers: *const u32,
+    pub n_markers: usize,
+    /// Optional histogram settings, needed by `aoldaq_get_histogram`.
+    pub histogram: *const AoldaqHistogram,
+    /// Latest points of each channel kept for `aoldaq_get_preview`. Zero
+    /// keeps none.
+    pub preview: usize,
+    /// Optional name of the shared-memory rings that other processes can read
+    /// the channels from, like `/aoldaq`, with `aoldaq_shm_open`. Channel `i`
+    /// goes to `<shm_name>.<i>`. Null doesn't write any.
+    pub shm_name: *const c_char,
+    /// Points in the ring of each channel. Zero keeps the default of 4M points.
+    pub shm_capacity: usize,
+}
+
+#[repr(C)]
+pub struct NiFpgaArgs {
+    /// `sizeof` the struct, as seen by the caller. Structs from another version
+    /// of aoldaq.h are rejected instead of misread.
+    pub struct_size: usize,
+    pub bitfile: *const c_char,
+    pub signature: *const c_char,
+    pub resource: *const c_char,
+    pub attribute: u32,
+    /// Address of each device FIFO. If null, FIFO `i` is at address `i`.
+    pub addrs: *const u32,
+    /// IRQ number asserted by the bitfile when the acquisition should start.
+    /// Only used with `AoldaqTriggerMode::Fpga` and a null `trigger_register`.
+    pub trigger_irq: u32,
+    /// Optional address of a register that becomes non-zero when the acquisition
+    /// should start. Takes precedence over `trigger_irq`.
+    pub trigger_register: *const u32,
+    /// Optional address of a U64 indicator with the FPGA tick count, read after
+    /// every block to timestamp it.
+    pub tick_counter: *const u32,
+    /// Depth of the host side of the DMA FIFOs, in points. Zero keeps the
+    /// NiFpga default.
+    pub fifo_depth: usize,
+    /// The DMA FIFOs hold U64 words, like photon events, each read as two
+    /// points, low half first.
+    pub u64_fifos: bool,
+}
+
+#[repr(C)]
+pub struct AoldaqTriggerArgs {
+    pub mode: AoldaqTriggerMode,
+    /// Amount of points per channel from before the trigger that are kept and
+    /// delivered ahead of the triggered data. Zero discards everything.
+    pub pre_trigger: usize,
+    pub marker_value: u32,
+    pub marker_mask: u32,
+}
+
+#[repr(C)]
+pub struct AoldaqFrameArgs {
+    /// `sizeof` the struct, as seen by the caller. Structs from another version
+    /// of aoldaq.h are rejected instead of misread.
+    pub struct_size: usize,
+    /// Pixels per line.
+    pub width: usize,
+    /// Lines per frame.
+    pub height: usize,
+    /// Odd lines are scanned backwards, so they are reversed.
+    pub bidirectional: bool,
+    /// Shift of the reversed lines, in pixels. Fractions of a pixel are
+    /// interpolated. Positive values move them to the right.
+    pub phase: f32,
+    /// Estimates the phase from the frames themselves, starting from `phase`.
+    pub auto_phase: bool,
+    pub average: AoldaqAverage,
+    /// Amount of frames averaged. Zero counts as one.
+    pub average_count: usize,
+    /// Optional folder where the raw frames of channel `i` are appended to
+    /// `channel<i>.bin`, as little endian `uint32_t`, before any correction.
+    pub record: *const c_char,
+    /// Frames start on the points for which `point & sync_mask == sync_value`,
+    /// instead of every `width * height` points. Zero doesn't sync.
+    pub sync_mask: u32,
+    pub sync_value: u32,
+    /// The marked point is a sentinel word ahead of the frame, and is dropped.
+    pub sync_sentinel: bool,
+    /// Matches the marker bits of unpacked channels instead of the points.
+    pub sync_markers: bool,
+    /// Optional photon counting settings. If not null, the points are
+    /// time-tagged events, counted into the pixels of the frames.
+    pub photon: *const AoldaqPhotonArgs,
+}
+
+#[repr(C)]
+pub struct AoldaqPhotonArgs {
+    /// Lowest bit and width of the channel in the U64 event words, sent as
+    /// two points, low half first.
+    pub channel_shift: u32,
+    pub channel_bits: u32,
+    /// Lowest bit and width of the timestamp, which wraps around.
+    pub time_shift: u32,
+    pub time_bits: u32,
+    /// Optional channel of the pixel clock. If null, pixels last `pixel_time`
+    /// from the start of their line.
+    pub pixel_channel: *const u32,
+    pub pixel_time: u64,
+    pub line_channel: u32,
+    /// Optional channel of the frame clock. If null, frames end after `height` lines.
+    pub frame_channel: *const u32,
+    /// Optional channel of the laser sync, needed by the lifetime histograms.
+    pub sync_channel: *const u32,
+    /// Bins of the lifetime histogram of each pixel, read with
+    /// `aoldaq_get_lifetime`. Zero doesn't make them.
+    pub lifetime_bins: usize,
+    /// Width of the lifetime bins, in timestamp units.
+    pub lifetime_bin_width: u64,
+}
+
+impl AoldaqPhotonArgs {
+    unsafe fn to_options(&self) -> PhotonOptions {
+        PhotonOptions {
+            channel_shift: self.channel_shift,
+            channel_bits: self.channel_bits,
+            time_shift: self.time_shift,
+            time_bits: self.time_bits,
+            pixel_channel: self.pixel_channel.as_ref().copied(),
+            pixel_time: self.pixel_time,
+            line_channel: self.line_channel,
+            frame_channel: self.frame_channel.as_ref().copied(),
+            sync_channel: self.sync_channel.as_ref().copied(),
+            lifetime_bins: self.lifetime_bins,
+            lifetime_bin_width: self.lifetime_bin_width,
+        }
+    }
+}
+
+impl AoldaqArgs {
+    /// # Safety
+    ///
+    /// `nifpga`, `trigger`, `device`, `frame` and `histogram` must be null or point to valid structs,
+    /// whose pointers must in turn be null or valid. `binning`, `fields` and
+    /// `markers` must be null or hold as many items as said above.
+    pub unsafe fn to_builder(&self) -> Result<AoldaqBuilder, AoldaqError> {
+        check_size::<AoldaqArgs>(self.struct_size, "aoldaq_args_t")?;
+
+        let mut builder = AoldaqBuilder::new(self.mode)
+            .channels(self.n_channels)
+            .block_size(self.block_size);
+
+        // First, so that the device is destroyed if anything else fails
+        if let Some(device) = self.device.as_ref() {
+            builder = builder.device(Box::new(CDevice::new(device)));
+        }
+
+        if self.fifo_capacity > 0 {
+            builder = builder.fifo_capacity(self.fifo_capacity);
+        }
+
+        if let Some(trigger) = self.trigger.as_ref() {
+            builder = builder
+                .trigger(trigger.mode)
+                .pre_trigger(trigger.pre_trigger)
+                .marker(trigger.marker_value, trigger.marker_mask);
+        }
+
+        if let Some(frame) = self.frame.as_ref() {
+            check_size::<AoldaqFrameArgs>(frame.struct_size, "aoldaq_frame_args_t")?;
+
+            builder = builder.frame(FrameOptions {
+                width: frame.width,
+                height: frame.height,
+                bidirectional: frame.bidirectional,
+                phase: frame.phase,
+                auto_phase: frame.auto_phase,
+                average: frame.average,
+                average_count: frame.average_count,
+                record: if frame.record.is_null() { None } else { Some(PathBuf::from(string(frame.record))) },
+                sync_mask: frame.sync_mask,
+                sync_value: frame.sync_value,
+                sync_sentinel: frame.sync_sentinel,
+                sync_markers: frame.sync_markers,
+                photon: frame.photon.as_ref().map(|photon| photon.to_options()),
+            });
+        }
+
+        if let Some(histogram) = self.histogram.as_ref() {
+            builder = builder.histogram(*histogram);
+        }
+
+        builder = builder.preview(self.preview);
+
+        if !self.shm_name.is_null() {
+            let mut shm = ShmOptions { name: string(self.shm_name), ..ShmOptions::default() };
+            if self.shm_capacity > 0 {
+                shm.capacity = self.shm_capacity;
+            }
+
+            builder = builder.shm(shm);
+        }
+
+        if !self.binning.is_null() && self.n_binning > 0 {
+            builder = builder.channel_binnings(std::slice::from_raw_parts(self.binning, self.n_binning).to_vec());
+        }
+
+        if !self.fields.is_null() {
+            builder = builder.unpack(UnpackOptions {
+                fields: std::slice::from_raw_parts(self.fields, self.n_channels).to_vec(),
+                markers: if self.markers.is_null() {
+                    Vec::new()
+                } else {
+                    std::slice::from_raw_parts(self.markers, self.n_markers).to_vec()
+                },
+            });
+        }
+
+        if let Some(nifpga) = self.nifpga.as_ref() {
+            let n_fifos = builder.get_options().n_fifos();
+            builder = builder.nifpga(nifpga.to_options(n_fifos)?);
+        }
+
+        Ok(builder)
+    }
+}
+
+impl NiFpgaArgs {
+    /// # Safety
+    ///
+    /// The strings must be null or nul-terminated, and `addrs` must be null or
+    /// hold `n_fifos` addresses.
+    pub unsafe fn to_options(&self, n_fifos: usize) -> Result<NiFpgaOptions, AoldaqError> {
+        check_size::<NiFpgaArgs>(self.struct_size, "NiFpgaArgs")?;
+
+        Ok(NiFpgaOptions {
+            bitfile: PathBuf::from(string(self.bitfile)),
+            signature: string(self.signature),
+            resource: string(self.resource),
+            attribute: self.attribute,
+            addrs: if self.addrs.is_null() {
+                Vec::new()
+            } else {
+                std::slice::from_raw_parts(self.addrs, n_fifos).to_vec()
+            },
+            fifo_depth: if self.fifo_depth > 0 { Some(self.fifo_depth) } else { None },
+            trigger_irq: self.trigger_irq,
+            trigger_register: self.trigger_register.as_ref().copied(),
+            tick_counter: self.tick_counter.as_ref().copied(),
+            u64_fifos: self.u64_fifos,
+        })
+    }
+}
+
+/// Checks the `struct_size` of the C struct `name` against `T`.
+fn check_size<T>(struct_size: usize, name: &str) -> Result<(), AoldaqError> {
+    if struct_size != std::mem::size_of::<T>() {
+        return Err(AoldaqError::InvalidArgument(format!(
+            "{} of {} bytes instead of {}, from another version of aoldaq.h", name, struct_size, std::mem::size_of::<T>())));
+    }
+
+    Ok(())
+}
+
+unsafe fn string(s: *const c_char) -> String {
+    if s.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(s).to_string_lossy().into_owned()
+    }
+}
+
+/// Creates an AOLDAQ instance. Returns null if it couldn't be created.
 #[no_mangle]
 pub unsafe extern fn aoldaq_create_instance(args: *const AoldaqArgs) -> *mut Aoldaq {
-    let instance = Box::new(Aoldaq::create(&*args));
-    Box::into_raw(instance)
+    let args = args.as_ref().expect("Args is null!");
+
+    match args.to_builder().and_then(|builder| builder.build()) {
+        Ok(instance) => Box::into_raw(Box::new(instance)),
+        Err(e) => {
+            log::error!("Failed to create instance: {}", e);
+            std::ptr::null_mut()
+        }
+    }
 }
 
 /// Destroys an AOLDAQ instance, stopping the threads and dropping everything.
@@ -63,6 +372,69 @@ pub extern fn aoldaq_get_data_blocking(instance: *mut Aoldaq, channel: usize, n:
     instance.get_data_into_blocking(channel, ptr, timeout)
 }
 
+/// Copies at most `n` points of the oldest block of `channel` into `buf`, and
+/// describes them in `info`: sequence number, position in the stream and
+/// acquisition time. Never mixes points of two blocks. Returns the amount of
+/// points copied, 0 if there was no data.
+/// Assumes that `buf` is a preallocated buffer capable of receiving `n` points.
+#[no_mangle]
+pub extern fn aoldaq_get_block(instance: *mut Aoldaq, channel: usize, n: usize, buf: *mut u32, info: *mut AoldaqBlockInfo) -> usize {
+    let instance = unsafe { instance.as_mut().expect("Instance is null!") };
+    let info = unsafe { info.as_mut().expect("Block info is null!") };
+
+    let ptr = if n > 0 { unsafe { std::slice::from_raw_parts_mut(buf, n) } } else { &mut [] };
+
+    match instance.get_block_into(channel, ptr) {
+        Some(block) => {
+            *info = block;
+            block.len
+        }
+        None => 0,
+    }
+}
+
+/// Waits for at most `timeout` milliseconds until every channel has `n` points,
+/// then copies exactly `n` points of each channel into `bufs[channel]`.
+/// Returns `n`, or 0 if the timeout expired, in which case nothing is consumed.
+/// Assumes that `bufs` holds one preallocated buffer per channel.
+#[no_mangle]
+pub extern fn aoldaq_get_data_all(instance: *mut Aoldaq, n: usize, bufs: *const *mut u32, timeout: u64) -> usize {
+    let instance = unsafe { instance.as_mut().expect("Instance is null!") };
+
+    let n_channels = instance.get_n_channels();
+    let ptrs = unsafe { std::slice::from_raw_parts(bufs, n_channels) };
+    let mut bufs = ptrs.iter()
+        .map(|&ptr| if n > 0 { unsafe { std::slice::from_raw_parts_mut(ptr, n) } } else { &mut [] })
+        .collect::<Vec<_>>();
+
+    let timeout = std::time::Duration::from_millis(timeout);
+
+    instance.get_data_all(&mut bufs[..], n, timeout)
+}
+
+/// Same as `aoldaq_get_data_all`, but interleaves the channels into a single
+/// buffer: `buf[i * n_channels + channel]` is point `i` of `channel`.
+/// Assumes that `buf` is a preallocated buffer of `n * n_channels` points.
+#[no_mangle]
+pub extern fn aoldaq_get_data_interleaved(instance: *mut Aoldaq, n: usize, buf: *mut u32, timeout: u64) -> usize {
+    let instance = unsafe { instance.as_mut().expect("Instance is null!") };
+
+    let n_channels = instance.get_n_channels();
+    let len = match n.checked_mul(n_channels) {
+        Some(len) => len,
+        None => {
+            log::error!("{} points of {} channels don't fit in memory", n, n_channels);
+            return 0;
+        }
+    };
+
+    let ptr = if len > 0 { unsafe { std::slice::from_raw_parts_mut(buf, len) } } else { &mut [] };
+
+    let timeout = std::time::Duration::from_millis(timeout);
+
+    instance.get_data_all_interleaved(ptr, n, timeout)
+}
+
 /// Consumes and frees everything in the specified channel.
 #[no_mangle]
 pub extern fn aoldaq_flush_fifo(instance: *mut Aoldaq, channel: usize) {
@@ -78,6 +450,45 @@ pub extern fn aoldaq_start(instance: *mut Aoldaq) {
     instance.start();
 }
 
+/// Starts a finite acquisition of `n` points per channel. The threads stop on
+/// their own once every channel delivered its points.
+#[no_mangle]
+pub extern fn aoldaq_start_finite(instance: *mut Aoldaq, n: usize) {
+    let instance = unsafe { instance.as_mut().expect("Instance is null!") };
+    instance.start_finite(n);
+}
+
+/// Starts a finite acquisition of `n` frames per channel, as in
+/// `Aoldaq::start_finite_frames`. Returns false, without starting, if the
+/// instance has no frame settings.
+#[no_mangle]
+pub extern fn aoldaq_start_finite_frames(instance: *mut Aoldaq, n: usize) -> bool {
+    let instance = unsafe { instance.as_mut().expect("Instance is null!") };
+
+    match instance.start_finite_frames(n) {
+        Ok(()) => true,
+        Err(e) => {
+            log::error!("Can't start a finite acquisition of frames: {}", e);
+            false
+        }
+    }
+}
+
+/// Returns whether the current finite acquisition is done.
+#[no_mangle]
+pub extern fn aoldaq_is_done(instance: *mut Aoldaq) -> bool {
+    let instance = unsafe { instance.as_mut().expect("Instance is null!") };
+    instance.is_done()
+}
+
+/// Blocks for at most `timeout` milliseconds until the current finite acquisition
+/// is done. Returns whether it is done.
+#[no_mangle]
+pub extern fn aoldaq_wait_done(instance: *mut Aoldaq, timeout: u64) -> bool {
+    let instance = unsafe { instance.as_mut().expect("Instance is null!") };
+    instance.wait_done(std::time::Duration::from_millis(timeout))
+}
+
 /// Parks the threads, pausing the acquisition.
 #[no_mangle]
 pub extern fn aoldaq_stop(instance: *mut Aoldaq) {
@@ -85,9 +496,388 @@ pub extern fn aoldaq_stop(instance: *mut Aoldaq) {
     instance.stop();
 }
 
+/// Fires the trigger by software, letting the acquisition data reach the FIFOs.
+#[no_mangle]
+pub extern fn aoldaq_trigger(instance: *mut Aoldaq) {
+    let instance = unsafe { instance.as_mut().expect("Instance is null!") };
+    instance.trigger();
+}
+
+/// Returns whether the trigger of the current acquisition has fired.
+#[no_mangle]
+pub extern fn aoldaq_is_triggered(instance: *mut Aoldaq) -> bool {
+    let instance = unsafe { instance.as_mut().expect("Instance is null!") };
+    instance.is_triggered()
+}
+
+struct UserData(*mut std::os::raw::c_void);
+
+// The user data is only handed back to the host's callback, which must be
+// ready to be called from the dispatcher thread.
+unsafe impl Send for UserData {}
+
+/// Registers `callback` to be called from a dispatcher thread whenever the
+/// software FIFO of `channel` crosses `threshold` points, on state changes,
+/// and on the errors and markers of `channel`. Returns an id for
+/// `aoldaq_unregister_callback`.
+/// Callbacks can register and unregister callbacks, including themselves, but
+/// must not destroy the instance.
+#[no_mangle]
+pub extern fn aoldaq_register_callback(instance: *mut Aoldaq,
+                                       channel: usize,
+                                       threshold: usize,
+                                       callback: AoldaqCallback,
+                                       user_data: *mut std::os::raw::c_void) -> usize {
+    let instance = unsafe { instance.as_mut().expect("Instance is null!") };
+    let user_data = UserData(user_data);
+
+    instance.register_callback(channel, threshold, move |channel, event, value| {
+        callback(user_data.0, channel, event, value)
+    })
+}
+
+/// Unregisters a callback. Once this returns, the callback will not be called
+/// again and is not running, unless this is called from within a callback:
+/// then it doesn't wait, as the running callback could be the caller. Returns
+/// false if the id is unknown.
+#[no_mangle]
+pub extern fn aoldaq_unregister_callback(instance: *mut Aoldaq, id: usize) -> bool {
+    let instance = unsafe { instance.as_mut().expect("Instance is null!") };
+    instance.unregister_callback(id)
+}
+
 /// Returns the underlying NiFPGA session object.
 #[no_mangle]
 pub extern fn aoldaq_get_nifpga_session(instance: *mut Aoldaq) -> u32 {
     let instance = unsafe { instance.as_mut().expect("Instance is null!") };
     instance.get_nifpga_session().unwrap_or(1234)
 }
+
+/// Returns the counters of `channel` since the instance was created.
+#[no_mangle]
+pub extern fn aoldaq_get_stats(instance: *mut Aoldaq, channel: usize) -> AoldaqStats {
+    let instance = unsafe { instance.as_mut().expect("Instance is null!") };
+    instance.get_stats(channel)
+}
+
+/// Copies to `bins`, which holds `n` counts, the histogram of the latest points
+/// read from `channel`, and fills `stats` if it isn't null, without consuming
+/// any data. Returns false if the instance was created without histogram
+/// settings.
+#[no_mangle]
+pub extern fn aoldaq_get_histogram(instance: *mut Aoldaq, channel: usize, bins: *mut u64, n: usize, stats: *mut AoldaqHistogramStats) -> bool {
+    let instance = unsafe { instance.as_mut().expect("Instance is null!") };
+    let bins = if n > 0 { unsafe { std::slice::from_raw_parts_mut(bins, n) } } else { &mut [] };
+
+    match instance.get_histogram_into(channel, bins) {
+        Some(histogram) => {
+            if let Some(stats) = unsafe { stats.as_mut() } {
+                *stats = histogram;
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// Copies to `buf`, which holds `n` points, the latest points read from
+/// `channel`, without taking them from its FIFO. Never blocks the acquisition:
+/// the preview is lossy, and is empty if the instance keeps none.
+#[no_mangle]
+pub extern fn aoldaq_get_preview(instance: *mut Aoldaq, channel: usize, buf: *mut u32, n: usize) -> AoldaqPreview {
+    let instance = unsafe { instance.as_mut().expect("Instance is null!") };
+    let buf = if n > 0 { unsafe { std::slice::from_raw_parts_mut(buf, n) } } else { &mut [] };
+
+    instance.get_preview_into(channel, buf)
+}
+
+/// Copies to `buf`, which must hold `width * height` points, the latest whole
+/// raw frame in the preview of `channel`, and stores its number in `number` if
+/// it isn't null. Returns false if there's no whole frame in the preview.
+#[no_mangle]
+pub extern fn aoldaq_get_preview_frame(instance: *mut Aoldaq, channel: usize, buf: *mut u32, number: *mut u64) -> bool {
+    let instance = unsafe { instance.as_mut().expect("Instance is null!") };
+
+    let n = instance.get_frame_len();
+    if n == 0 {
+        return false;
+    }
+
+    let buf = unsafe { std::slice::from_raw_parts_mut(buf, n) };
+
+    match instance.get_preview_frame_into(channel, buf) {
+        Some(frame) => {
+            if let Some(number) = unsafe { number.as_mut() } {
+                *number = frame;
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// Reads the U32 register at `address` into `value`. Returns 0 on success, the
+/// device status otherwise, or `AOLDAQ_UNSUPPORTED` if the device has no registers.
+#[no_mangle]
+pub extern fn aoldaq_read_register(instance: *mut Aoldaq, address: u32, value: *mut u32) -> i32 {
+    let instance = unsafe { instance.as_mut().expect("Instance is null!") };
+    let value = unsafe { value.as_mut().expect("Value is null!") };
+
+    match instance.device().read_register(address) {
+        Ok(v) => {
+            *value = v;
+            0
+        }
+        Err(e) => e,
+    }
+}
+
+/// Writes `value` to the U32 register at `address`. Returns 0 on success, the
+/// device status otherwise, or `AOLDAQ_UNSUPPORTED` if the device has no registers.
+#[no_mangle]
+pub extern fn aoldaq_write_register(instance: *mut Aoldaq, address: u32, value: u32) -> i32 {
+    let instance = unsafe { instance.as_mut().expect("Instance is null!") };
+
+    match instance.device().write_register(address, value) {
+        Ok(()) => 0,
+        Err(e) => e,
+    }
+}
+
+/// Creates an AOLDAQ instance from the config file at `path`. Returns null if
+/// it couldn't be created.
+#[no_mangle]
+pub unsafe extern fn aoldaq_create_instance_from_config(path: *const c_char) -> *mut Aoldaq {
+    let path = string(path);
+
+    match AoldaqBuilder::from_config_file(&path).and_then(|builder| builder.build()) {
+        Ok(instance) => Box::into_raw(Box::new(instance)),
+        Err(e) => {
+            log::error!("Failed to create instance from {}: {}", path, e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Copies to `buf`, which holds `n` markers, the markers of the points of
+/// `channel` still in its FIFO, oldest first, without removing them. Returns the
+/// amount of markers copied.
+#[no_mangle]
+pub extern fn aoldaq_get_markers(instance: *mut Aoldaq, channel: usize, buf: *mut AoldaqMarker, n: usize) -> usize {
+    let instance = unsafe { instance.as_mut().expect("Instance is null!") };
+    let buf = if n > 0 { unsafe { std::slice::from_raw_parts_mut(buf, n) } } else { &mut [] };
+
+    instance.get_markers(channel, buf)
+}
+
+/// Waits for at most `timeout` milliseconds for the next frame of `channel`,
+/// corrected and averaged, then copies it to `buf`, which must hold
+/// `width * height` points, and fills
+/// `info` if it isn't null. Returns false if the timeout expired or if the
+/// instance was created without frame settings.
+#[no_mangle]
+pub extern fn aoldaq_get_frame(instance: *mut Aoldaq, channel: usize, buf: *mut u32, timeout: u64, info: *mut AoldaqFrameInfo) -> bool {
+    let instance = unsafe { instance.as_mut().expect("Instance is null!") };
+
+    let n = instance.get_frame_len();
+    if n == 0 {
+        return false;
+    }
+
+    let buf = unsafe { std::slice::from_raw_parts_mut(buf, n) };
+
+    match instance.get_frame_into(channel, buf, std::time::Duration::from_millis(timeout)) {
+        Some(frame) => {
+            if let Some(info) = unsafe { info.as_mut() } {
+                *info = frame;
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// Copies to `buf`, which holds `n` counts, the lifetime histograms of the last
+/// frame of `channel` returned by `aoldaq_get_frame`, `lifetime_bins` for each
+/// pixel. Returns the amount of counts copied, zero without photon counting.
+#[no_mangle]
+pub extern fn aoldaq_get_lifetime(instance: *mut Aoldaq, channel: usize, buf: *mut u32, n: usize) -> usize {
+    let instance = unsafe { instance.as_mut().expect("Instance is null!") };
+    let buf = if n > 0 { unsafe { std::slice::from_raw_parts_mut(buf, n) } } else { &mut [] };
+
+    instance.get_lifetime_into(channel, buf)
+}
+
+/// Sets the shift of the reversed lines of bidirectional frames, in pixels.
+#[no_mangle]
+pub extern fn aoldaq_set_phase(instance: *mut Aoldaq, phase: f32) {
+    let instance = unsafe { instance.as_mut().expect("Instance is null!") };
+    instance.set_phase(phase);
+}
+
+/// Returns the shift of the reversed lines of `channel`, in pixels.
+#[no_mangle]
+pub extern fn aoldaq_get_phase(instance: *mut Aoldaq, channel: usize) -> f32 {
+    let instance = unsafe { instance.as_mut().expect("Instance is null!") };
+    instance.get_phase(channel)
+}
+
+/// Adds a consumer of `channel` with its own FIFO of `capacity` points, which
+/// gets a copy of every point delivered to the channel from now on, and can be
+/// read from any thread. With `AOLDAQ_OVERFLOW_DROP`, it loses points instead of
+/// stalling the acquisition when it falls behind. With `AOLDAQ_OVERFLOW_BLOCK`,
+/// it stalls it for at most 100 ms at a time, then loses points until it
+/// catches up. Returns null if `channel` doesn't exist. Must be destroyed with `aoldaq_reader_destroy`.
+#[no_mangle]
+pub extern fn aoldaq_reader_create(instance: *mut Aoldaq, channel: usize, capacity: usize, overflow: AoldaqOverflow) -> *mut AoldaqReader {
+    let instance = unsafe { instance.as_mut().expect("Instance is null!") };
+
+    match instance.add_reader(channel, capacity, overflow) {
+        Some(reader) => Box::into_raw(Box::new(reader)),
+        None => std::ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern fn aoldaq_reader_destroy(reader: *mut AoldaqReader) {
+    if !reader.is_null() {
+        drop(Box::from_raw(reader));
+    }
+}
+
+/// Copies at most `n` points from the FIFO of the reader into `buf`, waiting
+/// at most `timeout` milliseconds for `n` points. Returns the amount copied.
+#[no_mangle]
+pub extern fn aoldaq_reader_get_data(reader: *mut AoldaqReader, n: usize, buf: *mut u32, timeout: u64) -> usize {
+    let reader = unsafe { reader.as_mut().expect("Reader is null!") };
+    let buf = if n > 0 { unsafe { std::slice::from_raw_parts_mut(buf, n) } } else { &mut [] };
+
+    reader.get_data_into_blocking(buf, std::time::Duration::from_millis(timeout))
+}
+
+/// Like `aoldaq_get_block`, from the FIFO of the reader.
+#[no_mangle]
+pub extern fn aoldaq_reader_get_block(reader: *mut AoldaqReader, n: usize, buf: *mut u32, info: *mut AoldaqBlockInfo) -> usize {
+    let reader = unsafe { reader.as_mut().expect("Reader is null!") };
+    let info = unsafe { info.as_mut().expect("Block info is null!") };
+    let buf = if n > 0 { unsafe { std::slice::from_raw_parts_mut(buf, n) } } else { &mut [] };
+
+    match reader.get_block_into(buf) {
+        Some(block) => {
+            *info = block;
+            block.len
+        }
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub extern fn aoldaq_reader_get_fifo_size(reader: *mut AoldaqReader) -> usize {
+    let reader = unsafe { reader.as_mut().expect("Reader is null!") };
+    reader.get_fifo_size()
+}
+
+/// Returns the amount of points the reader lost because its FIFO was full.
+#[no_mangle]
+pub extern fn aoldaq_reader_get_dropped(reader: *mut AoldaqReader) -> u64 {
+    let reader = unsafe { reader.as_mut().expect("Reader is null!") };
+    reader.get_dropped()
+}
+
+/// Attaches to the shared-memory ring named `name`, like `/aoldaq.0`, written
+/// by an instance in another process. Doesn't need an instance of its own.
+/// Returns null if there's no such ring. Must be closed with `aoldaq_shm_close`.
+#[no_mangle]
+pub unsafe extern fn aoldaq_shm_open(name: *const c_char) -> *mut AoldaqShmReader {
+    match AoldaqShmReader::open(&string(name)) {
+        Ok(reader) => Box::into_raw(Box::new(reader)),
+        Err(e) => {
+            log::error!("{}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern fn aoldaq_shm_close(reader: *mut AoldaqShmReader) {
+    if !reader.is_null() {
+        drop(Box::from_raw(reader));
+    }
+}
+
+/// Copies the next points of the ring, at most `n`, into `buf`, and returns
+/// how many. Never waits. Points overwritten before being read are skipped.
+#[no_mangle]
+pub extern fn aoldaq_shm_read(reader: *mut AoldaqShmReader, n: usize, buf: *mut u32) -> usize {
+    let reader = unsafe { reader.as_mut().expect("Reader is null!") };
+    let buf = if n > 0 { unsafe { std::slice::from_raw_parts_mut(buf, n) } } else { &mut [] };
+
+    reader.read(buf)
+}
+
+/// Returns the amount of points waiting to be read.
+#[no_mangle]
+pub extern fn aoldaq_shm_get_available(reader: *mut AoldaqShmReader) -> usize {
+    let reader = unsafe { reader.as_mut().expect("Reader is null!") };
+    reader.available()
+}
+
+/// Returns the amount of points overwritten before being read.
+#[no_mangle]
+pub extern fn aoldaq_shm_get_lost(reader: *mut AoldaqShmReader) -> u64 {
+    let reader = unsafe { reader.as_mut().expect("Reader is null!") };
+    reader.lost()
+}
+
+/// Returns the index, in the stream of the ring, of the next point to read.
+#[no_mangle]
+pub extern fn aoldaq_shm_get_position(reader: *mut AoldaqShmReader) -> u64 {
+    let reader = unsafe { reader.as_mut().expect("Reader is null!") };
+    reader.position()
+}
+
+/// Returns whether the instance that wrote the ring is gone.
+#[no_mangle]
+pub extern fn aoldaq_shm_is_closed(reader: *mut AoldaqShmReader) -> bool {
+    let reader = unsafe { reader.as_mut().expect("Reader is null!") };
+    reader.is_closed()
+}
+
+/// Creates a verifier for the data of `AOLDAQ_MODE_COUNTER`, or of any other
+/// source that counts up by one on every point.
+#[no_mangle]
+pub extern fn aoldaq_verifier_create() -> *mut CounterVerifier {
+    Box::into_raw(Box::new(CounterVerifier::new()))
+}
+
+#[no_mangle]
+pub unsafe extern fn aoldaq_verifier_destroy(verifier: *mut CounterVerifier) {
+    if !verifier.is_null() {
+        drop(Box::from_raw(verifier));
+    }
+}
+
+/// Checks the next `n` points of the stream, from `data`.
+#[no_mangle]
+pub extern fn aoldaq_verifier_check(verifier: *mut CounterVerifier, data: *const u32, n: usize) {
+    let verifier = unsafe { verifier.as_mut().expect("Verifier is null!") };
+
+    if n > 0 {
+        let data = unsafe { std::slice::from_raw_parts(data, n) };
+        verifier.check(data);
+    }
+}
+
+/// Returns what the verifier found so far.
+#[no_mangle]
+pub extern fn aoldaq_verifier_get_report(verifier: *mut CounterVerifier) -> AoldaqVerifyReport {
+    let verifier = unsafe { verifier.as_mut().expect("Verifier is null!") };
+    verifier.report()
+}
+
+/// Forgets everything checked so far.
+#[no_mangle]
+pub extern fn aoldaq_verifier_reset(verifier: *mut CounterVerifier) {
+    let verifier = unsafe { verifier.as_mut().expect("Verifier is null!") };
+    verifier.reset();
+}
@@ -0,0 +1,115 @@
+//! Config files, holding one `key = value` option per line, as accepted by
+//! `AoldaqBuilder::set`. Empty lines and lines starting with `#` are ignored.
+//!
+//! ```text
+//! # Imaging FPGA
+//! mode = nifpga
+//! channels = 2
+//! block_size = 4000
+//! trigger = fpga
+//! binning = mean:4, max:4
+//! # Latest points kept for a live view, two frames
+//! preview = 524288
+//!
+//! # Two 16 bit channels packed in the words of FIFO 0, with a frame flag
+//! unpack.fields = 0:0-14, 0:16-31
+//! unpack.markers = 0x8000
+//!
+//! # Histograms of 12 bit points, for setting the PMT gains
+//! histogram.bins = 128
+//! histogram.max = 4096
+//! histogram.saturation = 4095
+//!
+//! # Channels mirrored to /aoldaq.0 and /aoldaq.1 for other processes
+//! shm.name = /aoldaq
+//! shm.capacity = 4194304
+//!
+//! nifpga.bitfile = C:\aol\aol.lvbitx
+//! nifpga.signature = 0123456789ABCDEF
+//! nifpga.resource = RIO0
+//! nifpga.addrs = 1, 2
+//! nifpga.trigger_register = 0x18
+//!
+//! frame.width = 512
+//! frame.height = 512
+//! frame.bidirectional = true
+//! frame.auto_phase = true
+//! frame.average = mean
+//! frame.average_count = 8
+//! frame.record = D:\data\raw
+//! frame.sync_mask = 0x8000
+//! frame.sync_value = 0x8000
+//! frame.sync_markers = true
+//!
+//! # For photon counting, instead of the frame sync: lines and frames start on
+//! # the clocks in channels 1 and 2, and photons are binned from the sync in 3
+//! # photon.line_channel = 1
+//! # photon.frame_channel = 2
+//! # photon.pixel_time = 400
+//! # photon.sync_channel = 3
+//! # photon.lifetime_bins = 64
+//! ```
+
+use std::path::Path;
+
+use crate::{ AoldaqBuilder, AoldaqError, AoldaqMode };
+
+impl AoldaqBuilder {
+    /// Creates a builder from the config file at `path`. The mode defaults to
+    /// `AoldaqMode::Random`.
+    pub fn from_config_file<P: AsRef<Path>>(path: P) -> Result<AoldaqBuilder, AoldaqError> {
+        let path = path.as_ref();
+        let config = std::fs::read_to_string(path)
+            .map_err(|e| AoldaqError::InvalidArgument(format!("cannot read {}: {}", path.display(), e)))?;
+
+        AoldaqBuilder::new(AoldaqMode::Random).apply_config(&config)
+    }
+
+    /// Applies the options of `config` on top of the current ones.
+    pub fn apply_config(self, config: &str) -> Result<AoldaqBuilder, AoldaqError> {
+        let mut builder = self;
+
+        for (i, line) in config.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = match line.find('=') {
+                Some(eq) => (line[..eq].trim(), &line[eq + 1..]),
+                None => return Err(AoldaqError::InvalidArgument(format!("line {}: expected `key = value`", i + 1))),
+            };
+
+            builder = builder.set(key, value)?;
+        }
+
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ AoldaqBuilder, AoldaqMode };
+
+    #[test]
+    fn test_config() {
+        let config = "
+            # Comment
+            mode = random
+            channels = 3
+            block_size = 0x10
+            fifo_capacity = 1024
+            nifpga.addrs = 1, 2,3
+        ";
+
+        let builder = AoldaqBuilder::new(AoldaqMode::NiFpga).apply_config(config).unwrap();
+        let aoldaq = builder.build().unwrap();
+        assert_eq!(aoldaq.get_n_channels(), 3);
+        assert_eq!(aoldaq.get_block_size(), 16);
+
+        assert!(AoldaqBuilder::new(AoldaqMode::Random).apply_config("channels = -1").is_err());
+        assert!(AoldaqBuilder::new(AoldaqMode::Random).apply_config("colour = blue").is_err());
+        assert!(AoldaqBuilder::new(AoldaqMode::Random).apply_config("channels").is_err());
+    }
+}
@@ -0,0 +1,127 @@
+use super::Device;
+
+use std::any::Any;
+use std::os::raw::c_void;
+
+/// Device implemented in C. Every function is called with `user_data`.
+///
+/// The functions are called concurrently from the acquisition threads, one per
+/// channel, so they must be thread-safe.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct AoldaqDeviceVtable {
+    pub user_data: *mut c_void,
+    /// Fills `buf` with up to `n` points of `channel`, blocking as needed, and
+    /// stores the amount of points read in `read`. Returns 0 on success, or a
+    /// device status reported through the `Error` event otherwise. With binning,
+    /// short reads should hold whole bins, as the samples of an incomplete bin
+    /// are dropped, and in photon counting, whole events.
+    pub read_into: extern fn(user_data: *mut c_void, channel: usize, buf: *mut u32, n: usize, read: *mut usize) -> i32,
+    /// Optional. Stores the amount of points available in the hardware buffer of
+    /// `channel` in `available`, returning false if unknown.
+    pub poll: Option<extern fn(user_data: *mut c_void, channel: usize, available: *mut usize) -> bool>,
+    /// Optional. Stores whether the hardware trigger fired in `fired`, returning
+    /// 0 on success. If null, the trigger fires immediately.
+    pub poll_trigger: Option<extern fn(user_data: *mut c_void, fired: *mut bool) -> i32>,
+    /// Optional. Stores the device tick count in `ticks`, returning false if
+    /// there is none.
+    pub ticks: Option<extern fn(user_data: *mut c_void, ticks: *mut u64) -> bool>,
+    /// Optional. Called once when the instance is destroyed.
+    pub destroy: Option<extern fn(user_data: *mut c_void)>,
+    /// Optional. Discards the points in the hardware buffer of `channel`, storing
+    /// how many were discarded in `flushed`. Returns 0 on success.
+    pub flush: Option<extern fn(user_data: *mut c_void, channel: usize, flushed: *mut usize) -> i32>,
+}
+
+pub struct CDevice {
+    vtable: AoldaqDeviceVtable,
+}
+
+// The vtable contract requires the functions to be callable from any thread.
+unsafe impl Send for CDevice {}
+unsafe impl Sync for CDevice {}
+
+impl CDevice {
+    /// # Safety
+    ///
+    /// The functions of `vtable` must be valid and thread-safe, and `user_data`
+    /// must stay valid until `destroy` is called.
+    pub unsafe fn new(vtable: &AoldaqDeviceVtable) -> CDevice {
+        CDevice {
+            vtable: *vtable,
+        }
+    }
+}
+
+impl Drop for CDevice {
+    fn drop(&mut self) {
+        if let Some(destroy) = self.vtable.destroy {
+            destroy(self.vtable.user_data);
+        }
+    }
+}
+
+impl Device for CDevice {
+    fn read_into(&self, channel: usize, buf: &mut [u32]) -> Result<usize, i32> {
+        let mut read = 0;
+        let status = (self.vtable.read_into)(self.vtable.user_data, channel, buf.as_mut_ptr(), buf.len(), &mut read);
+
+        if status != 0 {
+            return Err(status);
+        }
+
+        Ok(read.min(buf.len()))
+    }
+
+    fn hardware_fill(&self, channel: usize) -> Option<usize> {
+        let poll = self.vtable.poll?;
+        let mut available = 0;
+
+        if poll(self.vtable.user_data, channel, &mut available) {
+            Some(available)
+        } else {
+            None
+        }
+    }
+
+    fn flush(&self, channel: usize) -> Result<usize, i32> {
+        let flush = match self.vtable.flush {
+            Some(flush) => flush,
+            None => return Ok(0),
+        };
+
+        let mut flushed = 0;
+        match flush(self.vtable.user_data, channel, &mut flushed) {
+            0 => Ok(flushed),
+            status => Err(status),
+        }
+    }
+
+    fn poll_trigger(&self) -> Result<bool, i32> {
+        let poll_trigger = match self.vtable.poll_trigger {
+            Some(poll_trigger) => poll_trigger,
+            None => return Ok(true),
+        };
+
+        let mut fired = false;
+        match poll_trigger(self.vtable.user_data, &mut fired) {
+            0 => Ok(fired),
+            status => Err(status),
+        }
+    }
+
+    fn ticks(&self) -> Option<u64> {
+        let ticks = self.vtable.ticks?;
+        let mut value = 0;
+
+        if ticks(self.vtable.user_data, &mut value) {
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
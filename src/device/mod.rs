@@ -1,10 +1,71 @@
+use std::any::Any;
+use std::sync::atomic::{ AtomicU32, AtomicUsize, Ordering };
+
 pub mod nifpga_device;
 pub use nifpga_device::NiFpgaDevice;
 
+pub mod c_device;
+pub use c_device::{ AoldaqDeviceVtable, CDevice };
+
+/// Status returned by devices for operations they don't support.
+pub const UNSUPPORTED: i32 = -1;
+
+/// Source of the data of an `Aoldaq` instance. `read_into` is called
+/// concurrently from the acquisition threads, one per channel.
 pub trait Device: Send + Sync {
-    fn read_data(&self, channel: usize, n: usize) ->  Vec<u32>;
+    fn read_data(&self, channel: usize, n: usize) -> Vec<u32> {
+        let mut buf = vec![0; n];
+        let n = self.read_into(channel, &mut buf[..]).unwrap_or(0);
+        buf.truncate(n);
+        buf
+    }
+
+    /// Fills `buf` with points of `channel`, blocking as needed. Returns the
+    /// amount of points read, or a device status. Reads can be short, in which
+    /// case only the start of `buf` is used; with binning, they should hold
+    /// whole bins, as the samples of an incomplete bin are dropped, and in
+    /// photon counting, whole events.
     fn read_into(&self, channel: usize, buf: &mut [u32]) -> Result<usize, i32>;
-    fn poll(&self, channel: usize) -> Option<usize>;
+
+    /// Returns the amount of points available in the hardware buffer of
+    /// `channel`, if the device knows it.
+    fn hardware_fill(&self, _channel: usize) -> Option<usize> {
+        None
+    }
+
+    /// Discards the points in the hardware buffer of `channel`, returning how
+    /// many were discarded.
+    fn flush(&self, _channel: usize) -> Result<usize, i32> {
+        Ok(0)
+    }
+
+    fn read_register(&self, _address: u32) -> Result<u32, i32> {
+        Err(UNSUPPORTED)
+    }
+
+    fn write_register(&self, _address: u32, _value: u32) -> Result<(), i32> {
+        Err(UNSUPPORTED)
+    }
+
+    /// Returns the NiFpga session of the device, if it has one.
+    fn session(&self) -> Option<u32> {
+        None
+    }
+
+    /// Returns the device as `Any`, so it can be downcast to its concrete type.
+    /// Implementations just return `self`.
+    fn as_any(&self) -> &dyn Any;
+
+    /// Checks, without blocking, whether the hardware trigger has fired.
+    /// Devices without a hardware trigger fire immediately.
+    fn poll_trigger(&self) -> Result<bool, i32> {
+        Ok(true)
+    }
+
+    /// Returns the current device tick count, if the device has one.
+    fn ticks(&self) -> Option<u64> {
+        None
+    }
 }
 
 pub struct RandomDevice;
@@ -28,8 +89,52 @@ impl Device for RandomDevice {
         Ok(buf.len())
     }
 
-    fn poll(&self, _channel: usize) -> Option<usize> {
-        None
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Produces, for every channel, the running count of the points read from it,
+/// wrapping around at `u32::MAX`. Acquired data can then be checked for lost
+/// or reordered points with `CounterVerifier`.
+pub struct CounterDevice {
+    counters: Vec<AtomicU32>,
+    flushes: AtomicUsize,
+}
+
+impl CounterDevice {
+    pub fn new(n_channels: usize) -> CounterDevice {
+        CounterDevice {
+            counters: (0..n_channels).map(|_| AtomicU32::new(0)).collect(),
+            flushes: AtomicUsize::new(0),
+        }
+    }
+
+    /// Amount of flushes requested so far, all channels together.
+    pub fn flushes(&self) -> usize {
+        self.flushes.load(Ordering::SeqCst)
     }
 }
 
+impl Device for CounterDevice {
+    fn read_into(&self, channel: usize, buf: &mut [u32]) -> Result<usize, i32> {
+        // Only the thread of the channel reads it, so relaxed is fine
+        let start = self.counters[channel].fetch_add(buf.len() as u32, Ordering::Relaxed);
+
+        for (i, point) in buf.iter_mut().enumerate() {
+            *point = start.wrapping_add(i as u32);
+        }
+
+        Ok(buf.len())
+    }
+
+    /// There's no hardware buffer, so this only counts the flush.
+    fn flush(&self, _channel: usize) -> Result<usize, i32> {
+        self.flushes.fetch_add(1, Ordering::SeqCst);
+        Ok(0)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
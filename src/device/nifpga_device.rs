@@ -1,56 +1,86 @@
 use super::Device;
-use crate::NiFpgaArgs;
+use crate::{ AoldaqError, NiFpgaOptions };
 use crate::nifpga;
 
+use std::any::Any;
 use std::fs::{ File, OpenOptions };
-use std::sync::{ Arc, atomic::AtomicUsize, atomic::Ordering, Mutex };
+use std::sync::Mutex;
 use std::io::Write;
+use std::ffi::CString;
 
 pub struct NiFpgaDevice {
     session: nifpga::NiFpga_Session,
-    pub addrs: Vec<u32>,
+    addrs: Vec<u32>,
     out_file: Option<Mutex<File>>,
-    counters: Vec<Arc<AtomicUsize>>,
+    trigger: TriggerSource,
+    tick_counter: Option<u32>,
+    /// Buffer of each channel for the U64 words of its FIFO, before they are
+    /// split into points. Empty with U32 FIFOs.
+    words: Vec<Mutex<Vec<u64>>>,
+    /// Dropped after the session is closed.
+    _library: Library,
 }
 
-impl NiFpgaDevice {
-    pub fn new(args: *const NiFpgaArgs, n_channels: usize, dump: bool) -> Result<NiFpgaDevice, nifpga::NiFpga_Status> {
-        let args = unsafe { args.as_ref().expect("NiFpgaArgs is null!") };
+enum TriggerSource {
+    Register(u32),
+    /// The IRQ context is only reserved the first time the trigger is polled.
+    Irq(u32, Mutex<Option<IrqContext>>),
+}
 
-        let ret = unsafe { nifpga::NiFpga_Initialize() };
+struct IrqContext(nifpga::NiFpga_IrqContext);
 
-        if ret != nifpga::NiFpga_Status_Success {
-            return Err(ret);
-        }
+// The context is an opaque handle owned by the NiFpga runtime, and it is only
+// ever used behind the mutex.
+unsafe impl Send for IrqContext {}
 
-        //let instance = unsafe { instance.as_mut().expect("Instance is null!") };
+impl NiFpgaDevice {
+    pub fn new(options: &NiFpgaOptions, n_channels: usize, dump: bool) -> Result<NiFpgaDevice, AoldaqError> {
+        let bitfile = c_string(&options.bitfile.to_string_lossy(), "bitfile")?;
+        let signature = c_string(&options.signature, "signature")?;
+        let resource = c_string(&options.resource, "resource")?;
+
+        let library = Library::acquire()?;
 
         let mut session = 0u32;
 
         let ret = unsafe {
             nifpga::NiFpga_Open(
-                args.bitfile,
-                args.signature,
-                args.resource,
-                args.attribute,
+                bitfile.as_ptr(),
+                signature.as_ptr(),
+                resource.as_ptr(),
+                options.attribute,
                 &mut session as *mut _
             )
         };
 
         if ret != nifpga::NiFpga_Status_Success {
-            return Err(ret);
+            return Err(AoldaqError::NiFpga(ret));
         }
 
-        let addrs = if !args.addrs.is_null() {
-            unsafe { std::ptr::slice_from_raw_parts(args.addrs, n_channels).as_ref().unwrap().to_vec() }
+        let addrs = if !options.addrs.is_empty() {
+            options.addrs.clone()
         } else {
-            (0..n_channels as u32).into_iter().collect()
+            (0..n_channels as u32).collect()
         };
 
+        if let Some(depth) = options.fifo_depth {
+            // In elements, which hold two points each with U64 FIFOs
+            let depth = if options.u64_fifos { depth.div_ceil(2) } else { depth };
+
+            for &addr in &addrs {
+                let ret = unsafe { nifpga::NiFpga_ConfigureFifo(session, addr, depth as _) };
+
+                if ret != nifpga::NiFpga_Status_Success {
+                    unsafe { nifpga::NiFpga_Close(session, 0) };
+                    return Err(AoldaqError::NiFpga(ret));
+                }
+            }
+        }
+
         let out_file = if dump {
             let tmp = std::env::var("TEMP").unwrap_or("/tmp".to_string());
             let mut tmp = std::path::PathBuf::from(tmp);
-            tmp.push("aoldaq-nifpga-out.log");
+            tmp.push(format!("aoldaq-nifpga-out-{}.log", session));
             OpenOptions::new()
                 .truncate(true)
                 .write(true)
@@ -62,93 +92,362 @@ impl NiFpgaDevice {
             None
         };
 
-        let counters = ( 0..n_channels ).into_iter().map(|_| Arc::new(AtomicUsize::new(0))).collect::<Vec<_>>();
+        let trigger = match options.trigger_register {
+            Some(register) => TriggerSource::Register(register),
+            None => TriggerSource::Irq(options.trigger_irq, Mutex::new(None)),
+        };
+
+        let tick_counter = options.tick_counter;
+
+        let words = if options.u64_fifos {
+            addrs.iter().map(|_| Mutex::new(Vec::new())).collect()
+        } else {
+            Vec::new()
+        };
 
         Ok(NiFpgaDevice {
             session,
             addrs,
             out_file,
-            counters,
+            trigger,
+            tick_counter,
+            words,
+            _library: library,
         })
     }
 
-    pub fn get_nifpga_session(&self) -> nifpga::NiFpga_Session {
-        self.session
+    /// Queries the amount of points waiting in the FIFO of `channel`.
+    fn fill(&self, channel: usize) -> Result<usize, i32> {
+        let mut n = 0u64;
+
+        let ret = unsafe {
+            if self.words.is_empty() {
+                nifpga::NiFpga_ReadFifoU32(self.session,
+                                           self.addrs[channel],
+                                           std::ptr::null_mut(),
+                                           0,
+                                           0,
+                                           &mut n as *mut _)
+            } else {
+                nifpga::NiFpga_ReadFifoU64(self.session,
+                                           self.addrs[channel],
+                                           std::ptr::null_mut(),
+                                           0,
+                                           0,
+                                           &mut n as *mut _)
+            }
+        };
+
+        if ret != nifpga::NiFpga_Status_Success {
+            return Err(ret);
+        }
+
+        if self.words.is_empty() {
+            Ok(n as usize)
+        } else {
+            Ok(2 * n as usize)
+        }
     }
+
+    /// Reads `buf.len()` points from the FIFO of `channel`, waiting for at
+    /// most `timeout` milliseconds. With U64 FIFOs, `buf` must hold whole words.
+    fn read_fifo(&self, channel: usize, buf: &mut [u32], timeout: u32) -> Result<(), i32> {
+        let ret = match self.words.get(channel) {
+            None => unsafe {
+                nifpga::NiFpga_ReadFifoU32(self.session,
+                                           self.addrs[channel],
+                                           buf.as_mut_ptr(),
+                                           buf.len() as u64,
+                                           timeout,
+                                           std::ptr::null_mut())
+            },
+            Some(words) => {
+                let mut words = words.lock().unwrap();
+                words.resize(buf.len() / 2, 0);
+
+                let ret = unsafe {
+                    nifpga::NiFpga_ReadFifoU64(self.session,
+                                               self.addrs[channel],
+                                               words.as_mut_ptr(),
+                                               words.len() as u64,
+                                               timeout,
+                                               std::ptr::null_mut())
+                };
+
+                for (points, &word) in buf.chunks_exact_mut(2).zip(words.iter()) {
+                    points[0] = word as u32;
+                    points[1] = (word >> 32) as u32;
+                }
+
+                ret
+            }
+        };
+
+        if ret != nifpga::NiFpga_Status_Success {
+            return Err(ret);
+        }
+
+        Ok(())
+    }
+}
+
+fn c_string(s: &str, what: &str) -> Result<CString, AoldaqError> {
+    CString::new(s).map_err(|_| AoldaqError::InvalidArgument(format!("NiFpga {} contains a nul byte", what)))
 }
 
 impl Drop for NiFpgaDevice {
     fn drop(&mut self) {
-        log::debug!("NiFpga device wrote {} {}", self.counters[0].load(Ordering::Relaxed), self.counters[1].load(Ordering::Relaxed));
+        if let TriggerSource::Irq(_, ref context) = self.trigger {
+            if let Some(ref context) = *context.lock().unwrap() {
+                unsafe { nifpga::NiFpga_UnreserveIrqContext(self.session, context.0) };
+            }
+        }
         unsafe { nifpga::NiFpga_Close(self.session, 0) }; // TODO fix attribute
-        unsafe { nifpga::NiFpga_Finalize() };
     }
 }
 
-impl Device for NiFpgaDevice {
-    fn read_data(&self, channel: usize, n: usize) -> Vec<u32> {
-        let mut buf = vec![42; n];
-
-        unsafe {
-            nifpga::NiFpga_ReadFifoU32(
-                self.session,
-                self.addrs[channel],
-                buf.as_mut_ptr() as *mut _,
-                n as u64,
-                0,
-                std::ptr::null_mut()
-            )
-        };
+/// Amount of live `Library` handles. The NiFpga library is a process-wide
+/// resource, so it is only finalized when the last session is gone.
+static LIBRARY_USERS: Mutex<usize> = Mutex::new(0);
 
-        buf
+/// Keeps the NiFpga library initialized while alive.
+struct Library;
+
+impl Library {
+    fn acquire() -> Result<Library, AoldaqError> {
+        let mut users = LIBRARY_USERS.lock().unwrap();
+
+        if *users == 0 {
+            let ret = unsafe { nifpga::NiFpga_Initialize() };
+
+            if ret != nifpga::NiFpga_Status_Success {
+                return Err(AoldaqError::NiFpga(ret));
+            }
+
+            log::debug!("NiFpga library initialized");
+        }
+
+        *users += 1;
+        Ok(Library)
+    }
+}
+
+impl Drop for Library {
+    fn drop(&mut self) {
+        let mut users = LIBRARY_USERS.lock().unwrap();
+        *users -= 1;
+
+        if *users == 0 {
+            unsafe { nifpga::NiFpga_Finalize() };
+            log::debug!("NiFpga library finalized");
+        }
     }
+}
 
+impl Device for NiFpgaDevice {
     fn read_into(&self, channel: usize, buf: &mut [u32]) -> Result<usize, i32> {
         // TODO uncomment this after debugging!
-        let ret = unsafe {
-            nifpga::NiFpga_ReadFifoU32(
-                self.session,
-                self.addrs[channel],
-                buf.as_mut_ptr() as *mut _,
-                buf.len() as u64,
-                nifpga::NiFpga_InfiniteTimeout,
-                std::ptr::null_mut()
-            )
-        };
+        self.read_fifo(channel, buf, nifpga::NiFpga_InfiniteTimeout)?;
+
+        // This whole thing is horrible and slow.
+        // This must only be used for debugging.
+        if let Some(ref mutex) = self.out_file {
+            let mut file = mutex.lock().unwrap();
+            writeln!(file, "Channel {}: {:?}", channel, buf).unwrap();
+            file.flush().unwrap();
+        }
 
-        if ret == nifpga::NiFpga_Status_Success {
-            //for i in buf.iter_mut() {
-            // This only runs on one thread, so relaxed is fine
-            //*i = ( self.counters[channel].fetch_add(1, Ordering::Relaxed) % (512*512) ) as u32 + 1; 
-            //}
-
-            // This whole thing is horrible and slow.
-            // This must only be used for debugging.
-            if let Some(ref mutex) = self.out_file {
-                let mut file = mutex.lock().unwrap();
-                writeln!(file, "Channel {}: {:?}", channel, buf).unwrap();
-                file.flush().unwrap();
+        Ok(buf.len())
+    }
+
+    fn hardware_fill(&self, channel: usize) -> Option<usize> {
+        match self.fill(channel) {
+            Ok(n) => Some(n),
+            Err(ret) => {
+                log::error!("Failed to query the fill of FIFO {}: {}", self.addrs[channel], ret);
+                None
             }
+        }
+    }
 
-            Ok(buf.len())
-        } else {
-            Err(ret)
+    fn flush(&self, channel: usize) -> Result<usize, i32> {
+        let n = self.fill(channel)?;
+
+        let mut buf = vec![0u32; n];
+        self.read_fifo(channel, &mut buf, 100)?;
+
+        Ok(n)
+    }
+
+    fn read_register(&self, address: u32) -> Result<u32, i32> {
+        let mut value = 0u32;
+        let ret = unsafe { nifpga::NiFpga_ReadU32(self.session, address, &mut value as *mut _) };
+
+        if ret != nifpga::NiFpga_Status_Success {
+            return Err(ret);
         }
+
+        Ok(value)
     }
 
-    fn poll(&self, channel: usize) -> Option<usize> {
-        let mut n = 0u64;
+    fn write_register(&self, address: u32, value: u32) -> Result<(), i32> {
+        let ret = unsafe { nifpga::NiFpga_WriteU32(self.session, address, value) };
+
+        if ret != nifpga::NiFpga_Status_Success {
+            return Err(ret);
+        }
+
+        Ok(())
+    }
+
+    fn session(&self) -> Option<u32> {
+        Some(self.session)
+    }
+
+    fn poll_trigger(&self) -> Result<bool, i32> {
+        match self.trigger {
+            TriggerSource::Register(register) => {
+                let mut value = 0u32;
+                let ret = unsafe { nifpga::NiFpga_ReadU32(self.session, register, &mut value as *mut _) };
+
+                if ret != nifpga::NiFpga_Status_Success {
+                    return Err(ret);
+                }
+
+                Ok(value != 0)
+            }
+            TriggerSource::Irq(irq, ref context) => {
+                let mut context = context.lock().unwrap();
+
+                if context.is_none() {
+                    let mut new_context = std::ptr::null_mut();
+                    let ret = unsafe { nifpga::NiFpga_ReserveIrqContext(self.session, &mut new_context as *mut _) };
+
+                    if ret != nifpga::NiFpga_Status_Success {
+                        return Err(ret);
+                    }
 
-        unsafe {
-            nifpga::NiFpga_ReadFifoU32(self.session, 
-                                       self.addrs[channel],
-                                       std::ptr::null_mut(),
-                                       0 as u64,
-                                       nifpga::NiFpga_InfiniteTimeout,
-                                       &mut n as *mut _,
-                                       );
+                    *context = Some(IrqContext(new_context));
+                }
+
+                let context = context.as_ref().unwrap();
+                let irq = 1u32 << irq;
+                let mut asserted = 0u32;
+                let mut timed_out: nifpga::NiFpga_Bool = 0;
+
+                let ret = unsafe {
+                    nifpga::NiFpga_WaitOnIrqs(self.session,
+                                              context.0,
+                                              irq,
+                                              0,
+                                              &mut asserted as *mut _,
+                                              &mut timed_out as *mut _)
+                };
+
+                if ret != nifpga::NiFpga_Status_Success {
+                    return Err(ret);
+                }
+
+                if asserted & irq == 0 {
+                    return Ok(false);
+                }
+
+                unsafe { nifpga::NiFpga_AcknowledgeIrqs(self.session, irq) };
+                Ok(true)
+            }
+        }
+    }
+
+    fn ticks(&self) -> Option<u64> {
+        let indicator = self.tick_counter?;
+        let mut ticks = 0u64;
+
+        let ret = unsafe { nifpga::NiFpga_ReadU64(self.session, indicator, &mut ticks as *mut _) };
+
+        if ret != nifpga::NiFpga_Status_Success {
+            log::error!("Failed to read FPGA tick count: {}", ret);
+            return None;
         }
 
-        Some(n as usize)
+        Some(ticks)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use crate::{ Aoldaq, AoldaqBuilder, AoldaqMode, NiFpgaOptions };
+    use crate::nifpga::mock;
+
+    /// The mock runtime is shared by the whole process, and the tests count
+    /// its sessions, so they run one at a time.
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    fn instance(n_channels: usize) -> Aoldaq {
+        AoldaqBuilder::new(AoldaqMode::NiFpga)
+            .channels(n_channels)
+            .block_size(100)
+            .fifo_capacity(100_000)
+            .nifpga(NiFpgaOptions::default())
+            .build()
+            .unwrap()
+    }
+
+    fn assert_count(aoldaq: &mut Aoldaq, channel: usize, n: usize) {
+        let mut buf = vec![0; n];
+        assert_eq!(aoldaq.get_data_into(channel, &mut buf[..]), n);
+        assert!(buf.iter().enumerate().all(|(i, &x)| x == i as u32));
+    }
+
+    #[test]
+    fn test_interleaved_instances() {
+        let _serial = SERIAL.lock().unwrap();
+        let mut imaging = instance(2);
+        let mut stimulation = instance(1);
+        assert_eq!(mock::open_sessions(), 2);
+        assert_ne!(imaging.get_nifpga_session(), stimulation.get_nifpga_session());
+
+        imaging.start_finite(1000);
+        stimulation.start_finite(500);
+        assert!(imaging.wait_done(Duration::from_secs(5)));
+        assert!(stimulation.wait_done(Duration::from_secs(5)));
+        assert_count(&mut imaging, 1, 1000);
+
+        // Destroying one instance leaves the library to the others
+        drop(imaging);
+        assert!(mock::is_loaded());
+        assert_count(&mut stimulation, 0, 500);
+
+        let mut other = instance(1);
+        other.start_finite(300);
+        assert!(other.wait_done(Duration::from_secs(5)));
+        assert_count(&mut other, 0, 300);
+
+        drop(stimulation);
+        assert!(mock::is_loaded());
+        drop(other);
+        assert!(!mock::is_loaded());
+        assert_eq!(mock::open_sessions(), 0);
+    }
+
+    #[test]
+    fn test_u64_fifos() {
+        let _serial = SERIAL.lock().unwrap();
+        let options = NiFpgaOptions { u64_fifos: true, ..NiFpgaOptions::default() };
+        let builder = || AoldaqBuilder::new(AoldaqMode::NiFpga).block_size(100).fifo_capacity(100_000).nifpga(options.clone());
+
+        // The words are split into points, low half first
+        let mut aoldaq = builder().build().unwrap();
+        aoldaq.start_finite(1000);
+        assert!(aoldaq.wait_done(Duration::from_secs(5)));
+        assert_count(&mut aoldaq, 0, 1000);
+
+        assert!(builder().block_size(99).build().is_err());
     }
 }
@@ -0,0 +1,28 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AoldaqError {
+    /// A NiFpga call failed with this status.
+    NiFpga(i32),
+    /// A device operation failed with this status.
+    Device(i32),
+    /// The device doesn't support this operation.
+    Unsupported(&'static str),
+    /// The mode needs device-specific options that weren't given.
+    MissingOptions(&'static str),
+    InvalidArgument(String),
+}
+
+impl fmt::Display for AoldaqError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AoldaqError::NiFpga(status) => write!(f, "NiFpga call failed with status {}", status),
+            AoldaqError::Device(status) => write!(f, "Device operation failed with status {}", status),
+            AoldaqError::Unsupported(what) => write!(f, "The device does not support {}", what),
+            AoldaqError::MissingOptions(what) => write!(f, "Missing {} options", what),
+            AoldaqError::InvalidArgument(what) => write!(f, "Invalid argument: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for AoldaqError {}
@@ -0,0 +1,270 @@
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
+
+use ringbuf::{ Consumer, Producer, RingBuffer };
+
+use crate::AoldaqMarker;
+
+/// Describes a block of points pushed by a producer thread, as returned by the
+/// block-oriented read API.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct AoldaqBlockInfo {
+    /// Monotonic number of the device read the points came from. Lost
+    /// information is reported as `u64::MAX`.
+    pub sequence: u64,
+    /// Index, in the stream of every point read from the device on this
+    /// channel, of the first returned point. A jump between two blocks means
+    /// points were dropped in between.
+    pub first_point: u64,
+    /// Amount of returned points.
+    pub len: usize,
+    /// Host time at which the device read finished, in nanoseconds since the
+    /// Unix epoch.
+    pub timestamp_ns: u64,
+    /// FPGA tick count read right after the block, if the device provides one.
+    pub fpga_ticks: u64,
+    pub has_fpga_ticks: bool,
+}
+
+#[derive(Copy, Clone)]
+struct BlockRecord {
+    info: AoldaqBlockInfo,
+    /// Amount of points pushed to the FIFO before this block.
+    fifo_pos: u64,
+}
+
+#[derive(Copy, Clone)]
+struct MarkerRecord {
+    marker: AoldaqMarker,
+    /// Amount of points pushed to the FIFO before the marked one.
+    fifo_pos: u64,
+}
+
+/// Most markers kept for the points in a FIFO. Further ones are dropped.
+const MAX_MARKERS: usize = 1 << 16;
+
+/// Creates the software FIFO of a channel, holding `capacity` points, the
+/// information of the blocks they came from and their markers. Points are
+/// pushed in whole events of `event_points`.
+pub fn channel_fifo(capacity: usize, block_size: usize, event_points: usize) -> (FifoProducer, FifoConsumer) {
+    let (data_tx, data_rx) = RingBuffer::new(capacity).split();
+    let (blocks_tx, blocks_rx) = RingBuffer::new(capacity / block_size.max(1) + 16).split();
+    let (markers_tx, markers_rx) = RingBuffer::new(capacity.min(MAX_MARKERS)).split();
+    let recorded = Arc::new(AtomicU64::new(0));
+
+    let tx = FifoProducer {
+        data: data_tx,
+        blocks: blocks_tx,
+        markers: markers_tx,
+        recorded: recorded.clone(),
+        event_points: event_points.max(1),
+        pushed: 0,
+        overflows: 0,
+    };
+
+    let rx = FifoConsumer {
+        data: data_rx,
+        blocks: blocks_rx,
+        markers: markers_rx,
+        recorded,
+        popped: 0,
+    };
+
+    (tx, rx)
+}
+
+pub struct FifoProducer {
+    data: Producer<u32>,
+    blocks: Producer<BlockRecord>,
+    markers: Producer<MarkerRecord>,
+    /// Amount of pushed points whose block and markers were recorded.
+    recorded: Arc<AtomicU64>,
+    /// Points per event, so a partial push doesn't cut one.
+    event_points: usize,
+    pushed: u64,
+    overflows: u64,
+}
+
+impl FifoProducer {
+    /// Amount of points currently in the FIFO.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Amount of points pushed to the FIFO so far.
+    pub fn pushed(&self) -> u64 {
+        self.pushed
+    }
+
+    /// Amount of pushes that found the FIFO full.
+    pub fn overflows(&self) -> u64 {
+        self.overflows
+    }
+
+    /// Pushes all of `data`, spinning while the FIFO is full. Gives up when
+    /// `can_acquire` is cleared. Returns the amount of points pushed.
+    pub fn push(&mut self, data: &[u32], can_acquire: &AtomicBool, channel: usize) -> usize {
+        let mut written = 0;
+        let mut overflowed = false;
+
+        while written < data.len() && can_acquire.load(Ordering::Relaxed) {
+            written += self.push_events(&data[written..]);
+
+            if written < data.len() {
+                log::debug!("Overflow: Full fifo for channel {}, wrote {} out of {}", channel, written, data.len());
+                overflowed = true;
+            }
+        }
+
+        if overflowed {
+            self.overflows += 1;
+        }
+
+        self.pushed += written as u64;
+        written
+    }
+
+    /// Pushes as much of `data` as fits, without waiting. Returns the amount of
+    /// points pushed. A full FIFO isn't counted, see `overflowed`.
+    pub fn try_push(&mut self, data: &[u32]) -> usize {
+        let written = self.push_events(data);
+        self.pushed += written as u64;
+        written
+    }
+
+    /// Pushes as much of `data` as fits in whole events.
+    fn push_events(&mut self, data: &[u32]) -> usize {
+        let room = self.data.remaining() / self.event_points * self.event_points;
+        self.data.push_slice(&data[..room.min(data.len())])
+    }
+
+    /// Counts a push that found the FIFO full, for callers of `try_push`.
+    pub fn overflowed(&mut self) {
+        self.overflows += 1;
+    }
+
+    /// Records the information of the points pushed since `fifo_pos`. Must be
+    /// called after the points themselves were pushed, and after their markers.
+    pub fn record(&mut self, info: AoldaqBlockInfo, fifo_pos: u64) {
+        if self.blocks.push(BlockRecord { info, fifo_pos }).is_err() {
+            log::error!("Block information FIFO is full, lost block {}", info.sequence);
+        }
+
+        self.recorded.store(self.pushed, Ordering::Release);
+    }
+
+    /// Records `marker` on the point pushed after `fifo_pos` points. Must be
+    /// called after the point itself was pushed.
+    pub fn mark(&mut self, marker: AoldaqMarker, fifo_pos: u64) {
+        if self.markers.push(MarkerRecord { marker, fifo_pos }).is_err() {
+            log::debug!("Marker FIFO is full, lost the marker of point {}", marker.point);
+        }
+    }
+}
+
+pub struct FifoConsumer {
+    data: Consumer<u32>,
+    blocks: Consumer<BlockRecord>,
+    markers: Consumer<MarkerRecord>,
+    recorded: Arc<AtomicU64>,
+    popped: u64,
+}
+
+impl FifoConsumer {
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    #[cfg(feature = "async")]
+    pub fn capacity(&self) -> usize {
+        self.data.capacity()
+    }
+
+    pub fn as_slices(&self) -> (&[u32], &[u32]) {
+        self.data.as_slices()
+    }
+
+    pub fn pop_slice(&mut self, buf: &mut [u32]) -> usize {
+        let n = self.data.pop_slice(buf);
+        self.consumed(n)
+    }
+
+    pub fn discard(&mut self, n: usize) -> usize {
+        let n = self.data.discard(n);
+        self.consumed(n)
+    }
+
+    /// Pops the points of the oldest block in the FIFO into `buf`, never
+    /// crossing into the next block. Returns `None` if there's no data, or if
+    /// the information of the oldest points wasn't recorded yet.
+    pub fn pop_block(&mut self, buf: &mut [u32]) -> Option<AoldaqBlockInfo> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let (mut info, remaining) = match self.blocks.iter().next() {
+            Some(record) if record.fifo_pos <= self.popped => {
+                let offset = self.popped - record.fifo_pos;
+                let mut info = record.info;
+                info.first_point += offset;
+                (info, record.info.len - offset as usize)
+            }
+            Some(record) => {
+                // The information of the points before this block was lost
+                let info = AoldaqBlockInfo { sequence: u64::MAX, ..AoldaqBlockInfo::default() };
+                (info, (record.fifo_pos - self.popped) as usize)
+            }
+            None => return None,
+        };
+
+        let n = remaining.min(buf.len());
+        info.len = self.pop_slice(&mut buf[..n]);
+
+        Some(info)
+    }
+
+    /// Amount of points in the FIFO whose block and markers were recorded.
+    pub fn recorded(&self) -> usize {
+        self.recorded.load(Ordering::Acquire).saturating_sub(self.popped).min(self.len() as u64) as usize
+    }
+
+    /// Markers of the points still in the FIFO, oldest first, with the amount
+    /// of points before each of them in the FIFO.
+    pub fn markers(&self) -> impl Iterator<Item = (usize, AoldaqMarker)> + '_ {
+        let popped = self.popped;
+        // Markers recorded after their point was already popped are skipped
+        self.markers.iter()
+            .filter(move |record| record.fifo_pos >= popped)
+            .map(move |record| ((record.fifo_pos - popped) as usize, record.marker))
+    }
+
+    fn consumed(&mut self, n: usize) -> usize {
+        self.popped += n as u64;
+
+        // Drop the markers of the consumed points
+        while self.markers.iter().next().is_some_and(|record| record.fifo_pos < self.popped) {
+            self.markers.pop();
+        }
+
+        // Drop the information of blocks that were entirely consumed
+        loop {
+            let consumed = match self.blocks.iter().next() {
+                Some(record) => record.fifo_pos + record.info.len as u64 <= self.popped,
+                None => false,
+            };
+
+            if !consumed {
+                break;
+            }
+
+            self.blocks.pop();
+        }
+
+        n
+    }
+}
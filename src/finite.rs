@@ -0,0 +1,190 @@
+use std::sync::{ Condvar, Mutex };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::time::Duration;
+
+use crate::callback::{ AoldaqEvent, Notifier };
+
+/// Tracks finite acquisitions, in which every channel stops after a target
+/// amount of points, or of frames read.
+pub struct Completion {
+    n_channels: usize,
+    /// Points per channel of the current acquisition. Zero means continuous.
+    target: AtomicUsize,
+    /// Frames per channel of the current acquisition, counted as they are
+    /// read, when they can't be counted in points. Zero means no such target.
+    frames: AtomicUsize,
+    epoch: AtomicUsize,
+    done: Mutex<usize>,
+    cond: Condvar,
+    notifier: Notifier,
+}
+
+impl Completion {
+    pub fn new(n_channels: usize, notifier: Notifier) -> Completion {
+        Completion {
+            n_channels,
+            target: AtomicUsize::new(0),
+            frames: AtomicUsize::new(0),
+            epoch: AtomicUsize::new(0),
+            done: Mutex::new(0),
+            cond: Condvar::new(),
+            notifier,
+        }
+    }
+
+    /// Starts tracking a new acquisition of `target` points per channel.
+    pub fn begin(&self, target: usize) {
+        self.begin_with(target, 0);
+    }
+
+    /// Starts tracking a new acquisition of `frames` frames per channel,
+    /// counted by `FrameQuota`.
+    pub fn begin_frames(&self, frames: usize) {
+        self.begin_with(0, frames);
+    }
+
+    fn begin_with(&self, target: usize, frames: usize) {
+        let mut done = self.done.lock().unwrap();
+        *done = 0;
+        self.target.store(target, Ordering::SeqCst);
+        self.frames.store(frames, Ordering::SeqCst);
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn is_finite(&self) -> bool {
+        self.target.load(Ordering::SeqCst) > 0 || self.frames.load(Ordering::SeqCst) > 0
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.is_finite() && *self.done.lock().unwrap() == self.n_channels
+    }
+
+    /// Blocks until every channel got its points, for at most `timeout`.
+    /// Returns whether the acquisition is done.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        if !self.is_finite() {
+            return false;
+        }
+
+        let done = self.done.lock().unwrap();
+        let (done, _) = self.cond
+            .wait_timeout_while(done, timeout, |done| *done < self.n_channels)
+            .unwrap();
+
+        *done == self.n_channels
+    }
+
+    fn channel_done(&self) {
+        let mut done = self.done.lock().unwrap();
+        *done += 1;
+
+        if *done == self.n_channels {
+            match self.frames.load(Ordering::SeqCst) {
+                0 => log::info!("Finite acquisition of {} points per channel done", self.target.load(Ordering::SeqCst)),
+                frames => log::info!("Finite acquisition of {} frames per channel done", frames),
+            }
+            self.cond.notify_all();
+            self.notifier.event(AoldaqEvent::Done);
+        }
+    }
+}
+
+/// Per-channel count of points delivered in the current acquisition, owned by
+/// a producer thread.
+pub struct Quota {
+    epoch: usize,
+    acquired: usize,
+    signaled: bool,
+}
+
+impl Quota {
+    pub fn new(completion: &Completion) -> Quota {
+        Quota {
+            epoch: completion.epoch.load(Ordering::SeqCst),
+            acquired: 0,
+            signaled: false,
+        }
+    }
+
+    /// Returns whether this channel already got all of its points, or every
+    /// channel its frames.
+    pub fn is_full(&mut self, completion: &Completion) -> bool {
+        let epoch = completion.epoch.load(Ordering::SeqCst);
+        if epoch != self.epoch {
+            self.epoch = epoch;
+            self.acquired = 0;
+            self.signaled = false;
+        }
+
+        let frames = completion.frames.load(Ordering::SeqCst);
+        self.is_settled(completion) || (frames > 0 && completion.is_done())
+    }
+
+    fn is_settled(&self, completion: &Completion) -> bool {
+        let target = completion.target.load(Ordering::SeqCst);
+        target > 0 && self.acquired >= target
+    }
+
+    /// Accounts for up to `n` new points, returning how many of them still fit
+    /// in the acquisition.
+    pub fn take(&mut self, completion: &Completion, n: usize) -> usize {
+        let target = completion.target.load(Ordering::SeqCst);
+        if target == 0 {
+            return n;
+        }
+
+        let n = n.min(target.saturating_sub(self.acquired));
+        self.acquired += n;
+        n
+    }
+
+    /// Signals the completion if the target was reached. Must be called after
+    /// the points accounted by `take` were pushed to the FIFO.
+    pub fn settle(&mut self, completion: &Completion) {
+        if !self.signaled && self.is_settled(completion) {
+            self.signaled = true;
+            completion.channel_done();
+        }
+    }
+}
+
+/// Per-channel count of the frames read in the current acquisition, owned by
+/// the consumer of the channel.
+pub struct FrameQuota {
+    epoch: usize,
+    taken: usize,
+}
+
+impl FrameQuota {
+    pub fn new(completion: &Completion) -> FrameQuota {
+        FrameQuota {
+            epoch: completion.epoch.load(Ordering::SeqCst),
+            taken: 0,
+        }
+    }
+
+    /// Returns whether this channel already got all of its frames.
+    pub fn is_full(&mut self, completion: &Completion) -> bool {
+        let epoch = completion.epoch.load(Ordering::SeqCst);
+        if epoch != self.epoch {
+            self.epoch = epoch;
+            self.taken = 0;
+        }
+
+        let frames = completion.frames.load(Ordering::SeqCst);
+        frames > 0 && self.taken >= frames
+    }
+
+    /// Accounts for a frame read, signaling the completion on the last one.
+    pub fn take(&mut self, completion: &Completion) {
+        if self.is_full(completion) || completion.frames.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+
+        self.taken += 1;
+
+        if self.is_full(completion) {
+            completion.channel_done();
+        }
+    }
+}
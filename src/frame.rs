@@ -0,0 +1,624 @@
+//! Frame assembly on the consumer side: the points of a channel are cut into
+//! frames of `width * height` pixels, and bidirectional scans get their odd
+//! lines reversed and shifted into place. The corrected frames can then be
+//! averaged before they are returned, optionally recording the raw ones.
+
+use std::fs::File;
+use std::io::{ BufWriter, Write };
+use std::path::PathBuf;
+
+use crate::AoldaqError;
+use crate::fifo::FifoConsumer;
+use crate::photon::{ PhotonDecoder, PhotonOptions };
+
+/// How the frames of a channel are combined before they are returned.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum AoldaqAverage {
+    /// Every frame is returned as is.
+    #[default]
+    None,
+    /// Mean of every `count` frames.
+    Mean,
+    /// Sum of every `count` frames, saturating at `u32::MAX`.
+    Sum,
+    /// Maximum of every pixel over `count` frames.
+    Max,
+    /// Exponential moving average, weighting each new frame by `1 / count`.
+    /// Returns a frame for every frame acquired.
+    Rolling,
+}
+
+impl std::str::FromStr for AoldaqAverage {
+    type Err = AoldaqError;
+
+    fn from_str(s: &str) -> Result<AoldaqAverage, AoldaqError> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(AoldaqAverage::None),
+            "mean" => Ok(AoldaqAverage::Mean),
+            "sum" => Ok(AoldaqAverage::Sum),
+            "max" => Ok(AoldaqAverage::Max),
+            "rolling" => Ok(AoldaqAverage::Rolling),
+            _ => Err(AoldaqError::InvalidArgument(format!("unknown average {:?}", s))),
+        }
+    }
+}
+
+/// Geometry and processing of the frames of every channel.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FrameOptions {
+    /// Pixels per line.
+    pub width: usize,
+    /// Lines per frame.
+    pub height: usize,
+    /// Odd lines are scanned backwards, so they are reversed.
+    pub bidirectional: bool,
+    /// Shift of the reversed lines, in pixels. Fractions of a pixel are
+    /// interpolated. Positive values move them to the right.
+    pub phase: f32,
+    /// Estimates the phase from the frames themselves, starting from `phase`.
+    pub auto_phase: bool,
+    pub average: AoldaqAverage,
+    /// Amount of frames averaged. Zero counts as one.
+    pub average_count: usize,
+    /// Folder where the raw frames of channel `i` are appended to
+    /// `channel<i>.bin`, as little endian `u32`, before any correction.
+    pub record: Option<PathBuf>,
+    /// Frames start on the points for which `point & sync_mask == sync_value`,
+    /// instead of every `width * height` points. Zero doesn't sync.
+    pub sync_mask: u32,
+    pub sync_value: u32,
+    /// The marked point is a sentinel word ahead of the frame, not its first
+    /// pixel, and is dropped.
+    pub sync_sentinel: bool,
+    /// Matches the marker bits of unpacked channels (see `UnpackOptions`)
+    /// against the mask and value, instead of the points.
+    pub sync_markers: bool,
+    /// Builds the frames out of time-tagged photon events, instead of taking
+    /// the points as pixels. Doesn't go with sync, bidirectional scans,
+    /// binning or unpacking.
+    pub photon: Option<PhotonOptions>,
+}
+
+/// Describes a frame returned by `Aoldaq::get_frame_into`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct AoldaqFrameInfo {
+    /// Number of the frame on its channel, counting from zero.
+    pub number: u64,
+    /// Shift applied to the reversed lines, in pixels.
+    pub phase: f32,
+    /// Amount of acquired frames combined into this one.
+    pub averaged: u32,
+    /// Times the frames of the channel were re-aligned on their markers after
+    /// lost or extra points, so far.
+    pub resyncs: u64,
+}
+
+/// Weight of the estimate of each new frame in the auto-phase, so the phase
+/// follows drifts without jumping around on noisy frames.
+const AUTO_PHASE_WEIGHT: f32 = 0.25;
+
+/// Largest shift, in pixels, looked for by the auto-phase.
+const MAX_AUTO_PHASE: usize = 32;
+
+/// Frame state of a channel.
+pub struct Framer {
+    options: FrameOptions,
+    phase: f32,
+    frames: u64,
+    line: Vec<u32>,
+    /// Running sum, maximum or average of the frames averaged so far.
+    accumulator: Vec<f64>,
+    averaged: u32,
+    record: Option<BufWriter<File>>,
+    /// Whether a frame was already found on its marker.
+    locked: bool,
+    /// Whether points were discarded since the last frame.
+    discarded: bool,
+    resyncs: u64,
+    photon: Option<PhotonDecoder>,
+    /// Lifetime histograms of the last returned frame.
+    lifetime: Vec<u32>,
+}
+
+impl Framer {
+    /// Creates the frame state of `channel`, creating its recording if asked to.
+    pub fn new(options: &FrameOptions, channel: usize) -> Result<Framer, AoldaqError> {
+        let record = match options.record {
+            Some(ref folder) => {
+                let path = folder.join(format!("channel{}.bin", channel));
+                let file = File::create(&path)
+                    .map_err(|e| AoldaqError::InvalidArgument(format!("cannot create {}: {}", path.display(), e)))?;
+
+                Some(BufWriter::new(file))
+            }
+            None => None,
+        };
+
+        let accumulator = match options.average {
+            AoldaqAverage::None => Vec::new(),
+            _ => vec![0.0; options.width * options.height],
+        };
+
+        Ok(Framer {
+            options: options.clone(),
+            phase: options.phase,
+            frames: 0,
+            line: vec![0; options.width],
+            accumulator,
+            averaged: 0,
+            record,
+            locked: false,
+            discarded: false,
+            resyncs: 0,
+            photon: options.photon.as_ref().map(|photon| PhotonDecoder::new(photon, options.width, options.height)),
+            lifetime: Vec::new(),
+        })
+    }
+
+    /// Amount of points in a frame.
+    pub fn frame_len(&self) -> usize {
+        self.options.width * self.options.height
+    }
+
+    pub fn phase(&self) -> f32 {
+        self.phase
+    }
+
+    pub fn set_phase(&mut self, phase: f32) {
+        self.phase = phase;
+    }
+
+    pub fn resyncs(&self) -> u64 {
+        self.resyncs
+    }
+
+    /// Bins of the lifetime histogram of each pixel, zero without them.
+    pub fn lifetime_bins(&self) -> usize {
+        self.options.photon.as_ref().map_or(0, |photon| photon.lifetime_bins)
+    }
+
+    /// Lifetime histograms of the last returned frame, `lifetime_bins` for each
+    /// pixel, or nothing without them.
+    pub fn lifetime(&self) -> &[u32] {
+        &self.lifetime
+    }
+
+    /// Puts the next raw frame of `rx` in `frame`, which holds `frame_len`
+    /// points. Returns false if there isn't a whole one yet.
+    pub fn fill(&mut self, rx: &mut FifoConsumer, frame: &mut [u32]) -> bool {
+        match self.photon {
+            Some(ref mut decoder) => {
+                let (a, b) = rx.as_slices();
+                let (used, complete) = decoder.feed(a.iter().chain(b.iter()).copied());
+                rx.discard(used);
+
+                if complete {
+                    decoder.take_frame(frame);
+                }
+
+                complete
+            }
+            None => {
+                if !self.align(rx) {
+                    return false;
+                }
+
+                rx.pop_slice(frame);
+                true
+            }
+        }
+    }
+
+    /// Whether frames start on markers.
+    pub fn is_synced(&self) -> bool {
+        self.options.sync_mask != 0
+    }
+
+    /// Whether every raw frame takes exactly `frame_len` points of the stream,
+    /// so that frames can be counted in points.
+    pub fn is_fixed(&self) -> bool {
+        !self.is_synced() && self.photon.is_none()
+    }
+
+    /// Discards the points of `rx` that can't start a frame, until one starts
+    /// right at its read position. Returns whether a whole frame is there, in
+    /// which case its sentinel, if any, was dropped. Without sync, only checks
+    /// that a whole frame is there.
+    pub fn align(&mut self, rx: &mut FifoConsumer) -> bool {
+        let n = self.frame_len();
+        if !self.is_synced() {
+            return rx.len() >= n;
+        }
+
+        let sentinel = self.options.sync_sentinel as usize;
+
+        loop {
+            // Markers only count once recorded, after their points
+            let available = if self.options.sync_markers { rx.recorded() } else { rx.len() };
+
+            match self.find_marker(rx, 0, available) {
+                Some(0) => {
+                    // A marker inside the frame means points were lost
+                    if let Some(next) = self.find_marker(rx, 1, n + sentinel) {
+                        self.discard(rx, next);
+                    } else if available < n + sentinel {
+                        return false;
+                    } else {
+                        self.discard(rx, sentinel);
+                        self.locked = true;
+                        self.discarded = false;
+                        return true;
+                    }
+                }
+                Some(start) => self.discard(rx, start),
+                // None of these points can be part of the next frame
+                None if available > 0 => self.discard(rx, available),
+                None => return false,
+            }
+        }
+    }
+
+    fn discard(&mut self, rx: &mut FifoConsumer, n: usize) {
+        if n == 0 {
+            return;
+        }
+
+        rx.discard(n);
+
+        if self.locked && !self.discarded {
+            self.resyncs += 1;
+            log::debug!("Resynchronizing frame {} on its marker", self.frames);
+        }
+
+        self.discarded = true;
+    }
+
+    /// Offset in `rx` of the first frame marker from `from` up to `to`.
+    fn find_marker(&self, rx: &FifoConsumer, from: usize, to: usize) -> Option<usize> {
+        let (mask, value) = (self.options.sync_mask, self.options.sync_value);
+
+        if self.options.sync_markers {
+            rx.markers()
+                .take_while(|&(offset, _)| offset < to)
+                .find(|&(offset, marker)| offset >= from && marker.flags & mask == value)
+                .map(|(offset, _)| offset)
+        } else {
+            let (a, b) = rx.as_slices();
+            a.iter()
+                .chain(b.iter())
+                .take(to)
+                .skip(from)
+                .position(|&point| point & mask == value)
+                .map(|offset| offset + from)
+        }
+    }
+
+    /// Records, corrects and averages the raw frame in `frame`. Returns the
+    /// description of the resulting frame, left in `frame`, once the average
+    /// is complete, or `None` while more frames are needed.
+    pub fn process(&mut self, frame: &mut [u32]) -> Option<AoldaqFrameInfo> {
+        self.record(frame);
+        self.correct(frame);
+
+        if !self.average(frame) {
+            return None;
+        }
+
+        let info = AoldaqFrameInfo {
+            number: self.frames,
+            phase: self.phase,
+            averaged: self.averaged,
+            resyncs: self.resyncs,
+        };
+
+        self.frames += 1;
+
+        if let Some(ref mut decoder) = self.photon {
+            decoder.take_lifetime(&mut self.lifetime);
+        }
+
+        if self.options.average != AoldaqAverage::Rolling {
+            self.averaged = 0;
+        }
+
+        Some(info)
+    }
+
+    fn record(&mut self, frame: &[u32]) {
+        if let Some(ref mut record) = self.record {
+            let bytes = frame.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<_>>();
+
+            if let Err(e) = record.write_all(&bytes) {
+                log::error!("Failed to record a frame, stopping the recording: {}", e);
+                self.record = None;
+            }
+        }
+    }
+
+    /// Reverses and shifts the backward lines of `frame`, in place.
+    fn correct(&mut self, frame: &mut [u32]) {
+        let width = self.options.width;
+
+        if self.options.bidirectional {
+            for line in frame.chunks_mut(width).skip(1).step_by(2) {
+                line.reverse();
+            }
+
+            if self.options.auto_phase {
+                if let Some(estimate) = estimate_phase(frame, width) {
+                    self.phase += AUTO_PHASE_WEIGHT * (estimate - self.phase);
+                }
+            }
+
+            if self.phase != 0.0 {
+                for line in frame.chunks_mut(width).skip(1).step_by(2) {
+                    shift_line(line, self.phase, &mut self.line);
+                }
+            }
+        }
+    }
+
+    /// Adds `frame` to the average. Once it is complete, writes it to `frame`
+    /// and returns true.
+    fn average(&mut self, frame: &mut [u32]) -> bool {
+        let count = self.options.average_count.max(1) as u32;
+        let first = self.averaged == 0;
+        self.averaged = (self.averaged + 1).min(count);
+
+        let acc = &mut self.accumulator;
+        match self.options.average {
+            AoldaqAverage::None => return true,
+            AoldaqAverage::Mean | AoldaqAverage::Sum => {
+                for (a, &p) in acc.iter_mut().zip(frame.iter()) {
+                    *a = if first { p as f64 } else { *a + p as f64 };
+                }
+            }
+            AoldaqAverage::Max => {
+                for (a, &p) in acc.iter_mut().zip(frame.iter()) {
+                    *a = if first { p as f64 } else { a.max(p as f64) };
+                }
+            }
+            AoldaqAverage::Rolling => {
+                // Plain mean until `count` frames came in, so the start isn't
+                // biased towards the first frame
+                let weight = 1.0 / self.averaged as f64;
+                for (a, &p) in acc.iter_mut().zip(frame.iter()) {
+                    *a = if first { p as f64 } else { *a + weight * (p as f64 - *a) };
+                }
+            }
+        }
+
+        let rolling = self.options.average == AoldaqAverage::Rolling;
+        if !rolling && self.averaged < count {
+            return false;
+        }
+
+        let scale = if self.options.average == AoldaqAverage::Mean { 1.0 / count as f64 } else { 1.0 };
+        for (p, &a) in frame.iter_mut().zip(acc.iter()) {
+            *p = (a * scale).round().min(u32::MAX as f64) as u32;
+        }
+
+        true
+    }
+}
+
+/// Moves `line` by `phase` pixels, interpolating linearly between pixels and
+/// repeating the edge pixels. `scratch` must be as long as `line`.
+fn shift_line(line: &mut [u32], phase: f32, scratch: &mut [u32]) {
+    let last = line.len() as f32 - 1.0;
+    scratch.copy_from_slice(line);
+
+    for (x, pixel) in line.iter_mut().enumerate() {
+        let from = (x as f32 - phase).max(0.0).min(last);
+        let left = from.floor() as usize;
+        let right = (left + 1).min(scratch.len() - 1);
+        let t = from - left as f32;
+
+        let value = scratch[left] as f32 * (1.0 - t) + scratch[right] as f32 * t;
+        *pixel = value.round() as u32;
+    }
+}
+
+/// Estimates the shift that lines up the reversed (odd) lines of `frame` with
+/// the even lines before them, from the peak of their cross-correlation,
+/// refined to a fraction of a pixel with a parabola. Returns `None` for frames
+/// without a pair of lines or without any contrast.
+pub fn estimate_phase(frame: &[u32], width: usize) -> Option<f32> {
+    let max_shift = (width / 4).min(MAX_AUTO_PHASE) as isize;
+    let lines = frame.chunks_exact(width).collect::<Vec<_>>();
+
+    if lines.len() < 2 || max_shift == 0 {
+        return None;
+    }
+
+    // Correlation of the pairs of lines for every shift of the odd ones
+    let scores = (-max_shift..=max_shift)
+        .map(|shift| {
+            lines.chunks_exact(2)
+                .map(|pair| correlation(pair[0], pair[1], shift))
+                .sum::<f64>()
+        })
+        .collect::<Vec<_>>();
+
+    let (peak, &best) = scores.iter()
+        .enumerate()
+        .max_by(|a, b| a.1.partial_cmp(b.1).unwrap())?;
+
+    if best <= 0.0 {
+        return None;
+    }
+
+    let offset = if peak > 0 && peak < scores.len() - 1 {
+        let (l, c, r) = (scores[peak - 1], scores[peak], scores[peak + 1]);
+        let curvature = l - 2.0 * c + r;
+        if curvature < 0.0 { 0.5 * (l - r) / curvature } else { 0.0 }
+    } else {
+        0.0
+    };
+
+    // even[x] looks like odd[x + shift], so the odd lines move back by `shift`
+    let shift = peak as f64 - max_shift as f64 + offset;
+    Some(-shift as f32)
+}
+
+/// Mean-removed correlation of `even[x]` with `odd[x + shift]` over their overlap,
+/// per pixel.
+fn correlation(even: &[u32], odd: &[u32], shift: isize) -> f64 {
+    let mean = |line: &[u32]| line.iter().map(|&p| p as f64).sum::<f64>() / line.len() as f64;
+    let (even_mean, odd_mean) = (mean(even), mean(odd));
+
+    let width = even.len() as isize;
+    let range = (-shift).max(0)..(width - shift).min(width);
+    let overlap = (range.end - range.start) as f64;
+
+    range
+        .map(|x| (even[x as usize] as f64 - even_mean) * (odd[(x + shift) as usize] as f64 - odd_mean))
+        .sum::<f64>() / overlap
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bidirectional frame of a bright bump, with the backward lines
+    /// acquired `lag` pixels late.
+    fn scan(width: usize, height: usize, lag: f32) -> Vec<u32> {
+        let bump = |x: f32| (1000.0 * (-(x - width as f32 / 3.0).powi(2) / 50.0).exp()) as u32 + 100;
+
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| {
+                if y % 2 == 0 {
+                    bump(x as f32)
+                } else {
+                    // Acquired right to left, late by `lag`
+                    bump((width - 1 - x) as f32 + lag)
+                }
+            }))
+            .collect()
+    }
+
+    #[test]
+    fn test_reversal_and_phase() {
+        let options = FrameOptions {
+            width: 4,
+            height: 2,
+            bidirectional: true,
+            ..FrameOptions::default()
+        };
+
+        let mut framer = Framer::new(&options, 0).unwrap();
+        let mut frame = vec![1, 2, 3, 4, 8, 7, 6, 5];
+        let info = framer.process(&mut frame).unwrap();
+        assert_eq!(frame, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(info.number, 0);
+
+        framer.set_phase(0.5);
+        let mut frame = vec![1, 2, 3, 4, 80, 60, 40, 20];
+        let info = framer.process(&mut frame).unwrap();
+        assert_eq!(frame, vec![1, 2, 3, 4, 20, 30, 50, 70]);
+        assert_eq!(info.number, 1);
+    }
+
+    #[test]
+    fn test_auto_phase() {
+        let (width, height) = (128, 16);
+
+        let frame = {
+            let mut frame = scan(width, height, 3.4);
+            for line in frame.chunks_mut(width).skip(1).step_by(2) {
+                line.reverse();
+            }
+            frame
+        };
+
+        let estimate = estimate_phase(&frame, width).unwrap();
+        assert!((estimate - 3.4).abs() < 0.3, "Estimated {}", estimate);
+
+        let options = FrameOptions {
+            width,
+            height,
+            bidirectional: true,
+            auto_phase: true,
+            ..FrameOptions::default()
+        };
+
+        let mut framer = Framer::new(&options, 0).unwrap();
+        for _ in 0..20 {
+            framer.process(&mut scan(width, height, 3.4));
+        }
+
+        assert!((framer.phase() - 3.4).abs() < 0.3, "Converged to {}", framer.phase());
+
+        // A flat frame leaves the phase alone
+        framer.process(&mut vec![7; width * height]);
+        assert!((framer.phase() - 3.4).abs() < 0.3);
+    }
+
+    #[test]
+    fn test_average() {
+        let frames = [[1, 8], [3, 2], [5, 5], [7, 1]];
+        let average = |average, count| {
+            let options = FrameOptions {
+                width: 2,
+                height: 1,
+                average,
+                average_count: count,
+                ..FrameOptions::default()
+            };
+
+            let mut framer = Framer::new(&options, 0).unwrap();
+            frames.iter()
+                .filter_map(|frame| {
+                    let mut frame = frame.to_vec();
+                    framer.process(&mut frame).map(|info| (frame, info.averaged))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(average(AoldaqAverage::None, 4).len(), 4);
+        assert_eq!(average(AoldaqAverage::Mean, 2), vec![(vec![2, 5], 2), (vec![6, 3], 2)]);
+        assert_eq!(average(AoldaqAverage::Sum, 4), vec![(vec![16, 16], 4)]);
+        assert_eq!(average(AoldaqAverage::Max, 3), vec![(vec![5, 8], 3)]);
+
+        let rolling = average(AoldaqAverage::Rolling, 2);
+        assert_eq!(rolling.len(), 4);
+        assert_eq!(rolling[1], (vec![2, 5], 2));
+        assert_eq!(rolling[3], (vec![5, 3], 2));
+    }
+
+    #[test]
+    fn test_sync() {
+        use std::sync::atomic::AtomicBool;
+
+        let options = FrameOptions {
+            width: 2,
+            height: 2,
+            sync_mask: 0x100,
+            sync_value: 0x100,
+            ..FrameOptions::default()
+        };
+
+        let (mut tx, mut rx) = crate::fifo::channel_fifo(64, 4, 1);
+        let can_acquire = AtomicBool::new(true);
+
+        // Junk, a frame, a frame missing a point, a frame, and half a frame
+        tx.push(&[7, 8, 0x100, 1, 2, 3, 0x100, 4, 5, 0x100, 6, 7, 8, 0x100, 9], &can_acquire, 0);
+
+        let mut framer = Framer::new(&options, 0).unwrap();
+        let mut frame = vec![0; 4];
+        let mut frames = Vec::new();
+
+        while framer.align(&mut rx) {
+            rx.pop_slice(&mut frame);
+            frames.push((frame.clone(), framer.process(&mut frame).unwrap().resyncs));
+        }
+
+        assert_eq!(frames, vec![
+            (vec![0x100, 1, 2, 3], 0),
+            (vec![0x100, 6, 7, 8], 1),
+        ]);
+        assert_eq!(rx.len(), 2);
+    }
+}
@@ -0,0 +1,268 @@
+//! Live intensity histograms, kept by the producer threads over a sliding
+//! window of the latest blocks, so gains can be set without reading the FIFOs.
+//! The producer never waits on a reader: the blocks it reads meanwhile are
+//! added once the reader is done.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::AoldaqError;
+
+/// Bins and window of the histogram of every channel.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AoldaqHistogram {
+    pub bins: usize,
+    /// Range of the bins. Points below `min` count in the first bin, and
+    /// points from `max` up in the last.
+    pub min: u64,
+    pub max: u64,
+    /// Points in the sliding window, rounded up to whole blocks.
+    pub window: usize,
+    /// Points from this value up are saturated.
+    pub saturation: u32,
+}
+
+impl Default for AoldaqHistogram {
+    /// 256 bins of 16 bit points, over the last 64k points.
+    fn default() -> AoldaqHistogram {
+        AoldaqHistogram {
+            bins: 256,
+            min: 0,
+            max: 1 << 16,
+            window: 1 << 16,
+            saturation: u16::MAX as u32,
+        }
+    }
+}
+
+impl AoldaqHistogram {
+    pub fn validate(&self) -> Result<(), AoldaqError> {
+        if self.bins == 0 || self.window == 0 || self.max <= self.min {
+            return Err(AoldaqError::InvalidArgument(format!(
+                "invalid histogram of {} bins from {} to {} over {} points", self.bins, self.min, self.max, self.window)));
+        }
+
+        Ok(())
+    }
+
+    fn bin(&self, point: u32) -> usize {
+        let offset = (point as u64).saturating_sub(self.min);
+        let bin = offset as u128 * self.bins as u128 / (self.max - self.min) as u128;
+        (bin as usize).min(self.bins - 1)
+    }
+}
+
+/// Statistics of the points in the window of a histogram.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct AoldaqHistogramStats {
+    /// Points in the window. The others are zero when there's none.
+    pub points: u64,
+    pub min: u32,
+    pub max: u32,
+    pub mean: f64,
+    /// Points at or above the saturation value.
+    pub saturated: u64,
+}
+
+/// What a block added to the histogram, to take it out when it leaves the window.
+struct Block {
+    counts: Vec<u64>,
+    min: u32,
+    max: u32,
+    sum: u64,
+    points: u64,
+    saturated: u64,
+}
+
+impl Block {
+    fn new(bins: usize) -> Block {
+        Block { counts: vec![0; bins], min: 0, max: 0, sum: 0, points: 0, saturated: 0 }
+    }
+
+    fn fill(&mut self, options: &AoldaqHistogram, points: &[u32]) {
+        self.counts.iter_mut().for_each(|count| *count = 0);
+        self.min = u32::MAX;
+        self.max = 0;
+        self.sum = 0;
+        self.points = points.len() as u64;
+        self.saturated = 0;
+
+        for &point in points {
+            self.counts[options.bin(point)] += 1;
+            self.min = self.min.min(point);
+            self.max = self.max.max(point);
+            self.sum += point as u64;
+            self.saturated += (point >= options.saturation) as u64;
+        }
+    }
+}
+
+struct Window {
+    blocks: VecDeque<Block>,
+    counts: Vec<u64>,
+    sum: u64,
+    points: u64,
+    saturated: u64,
+}
+
+impl Window {
+    fn push(&mut self, block: Block) {
+        for (count, new) in self.counts.iter_mut().zip(&block.counts) {
+            *count += new;
+        }
+        self.sum += block.sum;
+        self.points += block.points;
+        self.saturated += block.saturated;
+        self.blocks.push_back(block);
+    }
+
+    fn pop(&mut self) -> Option<Block> {
+        let old = self.blocks.pop_front()?;
+        for (count, old) in self.counts.iter_mut().zip(&old.counts) {
+            *count -= old;
+        }
+        self.sum -= old.sum;
+        self.points -= old.points;
+        self.saturated -= old.saturated;
+        Some(old)
+    }
+}
+
+/// Blocks added while a reader held the window, and blocks to reuse.
+struct Pending {
+    blocks: VecDeque<Block>,
+    spare: Vec<Block>,
+}
+
+/// Histogram of a channel, filled by its producer thread and read by anyone.
+pub struct ChannelHistogram {
+    options: AoldaqHistogram,
+    /// Blocks in the window.
+    blocks: usize,
+    window: Mutex<Window>,
+    /// Only locked by the producer, so it never waits on a reader.
+    pending: Mutex<Pending>,
+}
+
+impl ChannelHistogram {
+    /// Creates the histogram of a channel with `pixels` points per block.
+    pub fn new(options: &AoldaqHistogram, pixels: usize) -> ChannelHistogram {
+        ChannelHistogram {
+            options: *options,
+            blocks: options.window.div_ceil(pixels.max(1)),
+            window: Mutex::new(Window {
+                blocks: VecDeque::new(),
+                counts: vec![0; options.bins],
+                sum: 0,
+                points: 0,
+                saturated: 0,
+            }),
+            pending: Mutex::new(Pending { blocks: VecDeque::new(), spare: Vec::new() }),
+        }
+    }
+
+    /// Adds the points of a block, dropping the oldest block out of the window.
+    /// If a reader holds the window, the block waits for the next one.
+    pub fn add(&self, points: &[u32]) {
+        let mut pending = self.pending.lock().unwrap();
+
+        let mut block = pending.spare.pop().unwrap_or_else(|| Block::new(self.options.bins));
+        block.fill(&self.options, points);
+
+        // Only the latest blocks would stay in the window
+        if pending.blocks.len() >= self.blocks {
+            let old = pending.blocks.pop_front().unwrap();
+            pending.spare.push(old);
+        }
+        pending.blocks.push_back(block);
+
+        let mut window = match self.window.try_lock() {
+            Ok(window) => window,
+            Err(_) => return,
+        };
+
+        while let Some(block) = pending.blocks.pop_front() {
+            if window.blocks.len() >= self.blocks {
+                let old = window.pop().unwrap();
+                pending.spare.push(old);
+            }
+            window.push(block);
+        }
+    }
+
+    /// Copies the counts of the window to `counts`, as many as fit, and
+    /// returns its statistics.
+    pub fn snapshot(&self, counts: &mut [u64]) -> AoldaqHistogramStats {
+        let window = self.window.lock().unwrap();
+
+        let n = counts.len().min(window.counts.len());
+        counts[..n].copy_from_slice(&window.counts[..n]);
+
+        let blocks = window.blocks.iter().filter(|block| block.points > 0);
+
+        match window.points {
+            0 => AoldaqHistogramStats::default(),
+            points => AoldaqHistogramStats {
+                points,
+                min: blocks.clone().map(|block| block.min).min().unwrap_or(0),
+                max: blocks.map(|block| block.max).max().unwrap_or(0),
+                mean: window.sum as f64 / points as f64,
+                saturated: window.saturated,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let options = AoldaqHistogram { bins: 4, min: 100, max: 200, window: 6, saturation: 190 };
+        assert!(options.validate().is_ok());
+        assert!(AoldaqHistogram { max: 100, ..options }.validate().is_err());
+
+        // Blocks of 4 points, so the window holds 2 blocks
+        let histogram = ChannelHistogram::new(&options, 4);
+        let mut counts = [0; 4];
+        assert_eq!(histogram.snapshot(&mut counts), AoldaqHistogramStats::default());
+
+        histogram.add(&[0, 120, 130, 199]);
+        histogram.add(&[150, 150, 150, 1000]);
+        let stats = histogram.snapshot(&mut counts);
+        assert_eq!(counts, [2, 1, 3, 2]);
+        assert_eq!((stats.points, stats.min, stats.max, stats.saturated), (8, 0, 1000, 2));
+        assert_eq!(stats.mean, 1899.0 / 8.0);
+
+        // The first block leaves the window
+        histogram.add(&[100, 101, 102, 103]);
+        let stats = histogram.snapshot(&mut counts);
+        assert_eq!(counts, [4, 0, 3, 1]);
+        assert_eq!((stats.points, stats.min, stats.max, stats.saturated), (8, 100, 1000, 1));
+    }
+
+    #[test]
+    fn test_busy_window() {
+        let options = AoldaqHistogram { bins: 4, min: 100, max: 200, window: 8, saturation: 190 };
+        let histogram = ChannelHistogram::new(&options, 4);
+        let mut counts = [0; 4];
+        histogram.add(&[100, 100, 100, 100]);
+
+        // Blocks added while a reader holds the window wait for the next one
+        let window = histogram.window.lock().unwrap();
+        histogram.add(&[125, 125, 125, 125]);
+        histogram.add(&[150, 150, 150, 150]);
+        drop(window);
+        assert_eq!(histogram.snapshot(&mut counts).points, 4);
+        assert_eq!(counts, [4, 0, 0, 0]);
+
+        // Only the latest of them stay in the window
+        histogram.add(&[175, 175, 175, 175]);
+        let stats = histogram.snapshot(&mut counts);
+        assert_eq!(counts, [0, 0, 4, 4]);
+        assert_eq!((stats.points, stats.min, stats.max), (8, 150, 175));
+    }
+}
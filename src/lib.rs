@@ -1,9 +1,8 @@
 use std::thread::JoinHandle;
 
-use std::sync::{ Arc, Barrier };
-use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::{ Arc, Barrier, Once };
+use std::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
 
-use ringbuf::{ Consumer, RingBuffer };
 
 #[cfg(unix)]
 use simplelog::TermLogger;
@@ -19,11 +18,75 @@ use simplelog::{
 mod capi;
 pub use capi::*;
 
+mod builder;
+pub use builder::{ AoldaqBuilder, AoldaqOptions, NiFpgaOptions, DEFAULT_FIFO_CAPACITY };
+
+mod config;
+
+mod error;
+pub use error::AoldaqError;
+
 mod device;
-use device::{ Device, RandomDevice, NiFpgaDevice };
+pub use device::{ CounterDevice, Device, AoldaqDeviceVtable, UNSUPPORTED };
 
 mod nifpga;
 
+mod trigger;
+pub use trigger::{ AoldaqTriggerMode, TriggerOptions };
+use trigger::{ Trigger, TriggerGate };
+
+mod finite;
+use finite::{ Completion, FrameQuota, Quota };
+
+mod fifo;
+pub use fifo::AoldaqBlockInfo;
+use fifo::{ FifoConsumer, FifoProducer };
+
+mod stats;
+pub use stats::AoldaqStats;
+use stats::ChannelStats;
+
+mod binning;
+pub use binning::{ AoldaqBinMode, AoldaqBinning };
+
+mod unpack;
+pub use unpack::{ AoldaqField, AoldaqMarker, UnpackOptions };
+
+mod histogram;
+pub use histogram::{ AoldaqHistogram, AoldaqHistogramStats };
+use histogram::ChannelHistogram;
+
+mod preview;
+pub use preview::AoldaqPreview;
+use preview::ChannelPreview;
+
+mod reader;
+pub use reader::{ AoldaqOverflow, AoldaqReader, STALL_TIMEOUT };
+use reader::Fanout;
+
+mod shm;
+pub use shm::{ AoldaqShmHeader, AoldaqShmReader, ShmOptions, SHM_MAGIC, SHM_VERSION };
+use shm::ShmWriter;
+
+mod photon;
+pub use photon::PhotonOptions;
+
+mod frame;
+pub use frame::{ AoldaqAverage, AoldaqFrameInfo, FrameOptions };
+use frame::Framer;
+
+mod verify;
+pub use verify::{ CounterVerifier, AoldaqVerifyReport };
+
+mod callback;
+pub use callback::{ AoldaqEvent, AoldaqCallback };
+use callback::Dispatcher;
+
+#[cfg(feature = "async")]
+mod stream;
+#[cfg(feature = "async")]
+pub use stream::{ Read, BlockStream };
+
 const _BUCKET_SIZE: usize = 2000;
 
 #[repr(C)]
@@ -31,40 +94,98 @@ const _BUCKET_SIZE: usize = 2000;
 pub enum AoldaqMode {
     NiFpga,
     Random,
+    /// Device supplied by the user, through `Aoldaq::with_device` or a vtable.
+    Custom,
+    /// Running count on every channel, to check the data with `CounterVerifier`.
+    Counter,
 }
 
-#[repr(C)]
-pub struct AoldaqArgs {
-    pub block_size: usize,
-    pub n_channels: usize,
-    pub mode: AoldaqMode,
-    pub nifpga: *const NiFpgaArgs
-}
+impl std::str::FromStr for AoldaqMode {
+    type Err = AoldaqError;
 
-#[repr(C)]
-pub struct NiFpgaArgs {
-    pub bitfile: *const std::os::raw::c_char,
-    pub signature: *const std::os::raw::c_char,
-    pub resource: *const std::os::raw::c_char,
-    pub attribute: u32,
-    pub addrs: *const u32,
+    fn from_str(s: &str) -> Result<AoldaqMode, AoldaqError> {
+        match s.to_lowercase().as_str() {
+            "nifpga" => Ok(AoldaqMode::NiFpga),
+            "random" => Ok(AoldaqMode::Random),
+            "counter" => Ok(AoldaqMode::Counter),
+            _ => Err(AoldaqError::InvalidArgument(format!("unknown mode {:?}", s))),
+        }
+    }
 }
 
 pub struct Aoldaq {
     n_channels: usize,
-    mode: AoldaqMode,
+    block_size: usize,
+    /// Points per event, see `AoldaqOptions::event_points`.
+    event_points: usize,
     threads: Vec<JoinHandle<()>>,
     can_acquire: Arc<AtomicBool>,
     pause: Arc<AtomicBool>,
     run: Arc<AtomicBool>,
-    fifos: Vec<Consumer<u32>>,
+    fifos: Vec<FifoConsumer>,
     device: Arc<dyn Device>,
     pause_barrier: Arc<Barrier>,
+    trigger: Arc<Trigger>,
+    completion: Arc<Completion>,
+    dispatcher: Dispatcher,
+    stats: Vec<Arc<ChannelStats>>,
+    histograms: Vec<Arc<ChannelHistogram>>,
+    previews: Vec<Arc<ChannelPreview>>,
+    fanouts: Vec<Arc<Fanout>>,
+    framers: Vec<Framer>,
+    frame_quotas: Vec<FrameQuota>,
+    /// Device FIFO of each channel.
+    channel_fifos: Vec<usize>,
+    /// Points flushed from each device FIFO behind the back of its thread,
+    /// which still count in its position.
+    flushed: Vec<Arc<AtomicU64>>,
 }
 
-impl Aoldaq {
-    pub fn create(args: &AoldaqArgs) -> Aoldaq {
-        // Init logging
+/// A channel as seen by the producer thread of its device FIFO.
+struct Lane {
+    channel: usize,
+    field: Option<AoldaqField>,
+    binning: AoldaqBinning,
+    buf: Vec<u32>,
+    tx: FifoProducer,
+    gate: TriggerGate,
+    quota: Quota,
+    sequence: u64,
+    stream_pos: u64,
+    stats: Arc<ChannelStats>,
+    histogram: Option<Arc<ChannelHistogram>>,
+    preview: Option<Arc<ChannelPreview>>,
+    fanout: Arc<Fanout>,
+    shm: Option<ShmWriter>,
+    /// Markers of the points delivered from the current block, with their
+    /// offset in the delivered points.
+    marks: Vec<(AoldaqMarker, usize)>,
+}
+
+impl Lane {
+    /// Unpacks and bins the words of a device read into `buf`. Returns the
+    /// amount of pixels, leaving out the samples of an incomplete bin.
+    fn load(&mut self, words: &[u32]) -> usize {
+        let buf = &mut self.buf[..words.len()];
+
+        match self.field {
+            Some(field) => {
+                for (point, &word) in buf.iter_mut().zip(words) {
+                    *point = field.extract(word);
+                }
+            }
+            None => buf.copy_from_slice(words),
+        }
+
+        self.binning.apply(buf)
+    }
+}
+
+/// Sets up the global logger. Only the first instance of the process does it.
+fn init_logging() {
+    static INIT: Once = Once::new();
+
+    INIT.call_once(|| {
         #[cfg(unix)]
         TermLogger::init(LevelFilter::max(), Config::default(), TerminalMode::Mixed).unwrap_or(());
 
@@ -73,37 +194,113 @@ impl Aoldaq {
             let tmp = std::env::var("TEMP").unwrap_or("/tmp".to_string());
             let mut tmp = std::path::PathBuf::from(tmp);
             tmp.push("aoldaq.log");
-            
+
             WriteLogger::init(LevelFilter::max(), Config::default(), std::fs::File::create(tmp).unwrap()).unwrap_or(());
         }
+    });
+}
+
+impl Aoldaq {
+    /// Creates an instance that acquires from `device`, which can be any
+    /// implementation of `Device`.
+    pub fn with_device(device: Box<dyn Device>, options: AoldaqOptions) -> Result<Aoldaq, AoldaqError> {
+        init_logging();
+        builder::validate(&options)?;
 
-        let mut threads = Vec::with_capacity(args.n_channels);
-        let mut fifos = Vec::with_capacity(args.n_channels);
+        Aoldaq::new(options, Arc::from(device))
+    }
+
+    fn new(options: AoldaqOptions, device: Arc<dyn Device>) -> Result<Aoldaq, AoldaqError> {
+        let framers = match options.frame {
+            Some(ref frame) => (0..options.n_channels)
+                .map(|i| Framer::new(frame, i))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => Vec::new(),
+        };
+
+        let n_fifos = options.n_fifos();
+        let mut threads = Vec::with_capacity(n_fifos);
+        let mut fifos = Vec::with_capacity(options.n_channels);
 
         let pause = Arc::new(AtomicBool::new(true));
         let run = Arc::new(AtomicBool::new(true));
         let can_acquire = Arc::new(AtomicBool::new(true));
 
-        let barrier = Arc::new(Barrier::new(args.n_channels));
+        let barrier = Arc::new(Barrier::new(n_fifos));
 
         // One barrier for the main thread, and one for each thread
-        let pause_barrier = Arc::new(Barrier::new(args.n_channels + 1));
+        let pause_barrier = Arc::new(Barrier::new(n_fifos + 1));
 
-        let device = match args.mode {
-            AoldaqMode::Random => Arc::new(RandomDevice::new()) as Arc<dyn Device>,
-            AoldaqMode::NiFpga => Arc::new(NiFpgaDevice::new(args.nifpga, args.n_channels, false)
-                                           .expect("Failed to init NiFpga")) as Arc<dyn Device>,
-        };
+        let block_size = options.block_size;
 
-        let block_size = args.block_size;
+        let dispatcher = Dispatcher::new();
+        let trigger = Arc::new(Trigger::new(&options.trigger, dispatcher.notifier()));
+        let completion = Arc::new(Completion::new(options.n_channels, dispatcher.notifier()));
 
-        for i in 0..args.n_channels {
-            let buf = RingBuffer::new(4 * 268435456); // 4GB worth of points. If needed, can safely be increased
-            //let buf = RingBuffer::new(512*512);
-            let (mut tx, rx) = buf.split();
-            //let (tx, rx) = crossbeam_channel::unbounded();
-            //let (tx, rx) = crossbeam_channel::bounded(4 * 1024 * 1024);
-            fifos.push(rx);
+        let stats = (0..options.n_channels).map(|_| Arc::new(ChannelStats::default())).collect::<Vec<_>>();
+
+        let mut histograms = Vec::new();
+        let mut previews = Vec::new();
+        let mut fanouts = Vec::new();
+
+        let mut shms = match options.shm {
+            Some(ref shm) => (0..options.n_channels)
+                .map(|i| ShmWriter::create(shm, i).map(Some))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => (0..options.n_channels).map(|_| None).collect(),
+        };
+
+        let mut lanes = (0..options.n_channels)
+            .map(|i| {
+                let binning = options.channel_binning(i);
+                let pixels = block_size / binning.factor();
+
+                let histogram = options.histogram.map(|histogram| Arc::new(ChannelHistogram::new(&histogram, pixels)));
+                histograms.extend(histogram.clone());
+
+                let preview = Some(options.preview)
+                    .filter(|&preview| preview > 0)
+                    .map(|preview| Arc::new(ChannelPreview::new(preview)));
+                previews.extend(preview.clone());
+
+                let fanout = Arc::new(Fanout::new(pixels, options.event_points()));
+                fanouts.push(fanout.clone());
+
+                let (tx, rx) = fifo::channel_fifo(options.fifo_capacity, pixels, options.event_points());
+                //let (tx, rx) = crossbeam_channel::unbounded();
+                //let (tx, rx) = crossbeam_channel::bounded(4 * 1024 * 1024);
+                fifos.push(rx);
+
+                Some(Lane {
+                    channel: i,
+                    field: options.channel_field(i),
+                    binning,
+                    buf: vec![666; block_size],
+                    tx,
+                    // Two blocks of lag behind the channel that sees the trigger
+                    gate: TriggerGate::new(&trigger, binning.factor(), 2 * pixels),
+                    quota: Quota::new(&completion),
+                    sequence: 0,
+                    stream_pos: 0,
+                    stats: stats[i].clone(),
+                    histogram,
+                    preview,
+                    fanout,
+                    shm: shms[i].take(),
+                    marks: Vec::new(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let flushed = (0..n_fifos).map(|_| Arc::new(AtomicU64::new(0))).collect::<Vec<_>>();
+
+        for i in 0..n_fifos {
+            // Channels fed by this device FIFO
+            let mut lanes = (0..options.n_channels)
+                .filter(|&channel| options.channel_fifo(channel) == i)
+                .filter_map(|channel| lanes[channel].take())
+                .collect::<Vec<_>>();
+            let marker_mask = options.marker_mask(i);
 
             let can_acquire = can_acquire.clone();
             let device = device.clone();
@@ -111,9 +308,19 @@ impl Aoldaq {
             let pause_barrier = pause_barrier.clone();
             let run = run.clone();
             let b = barrier.clone();
+            let trigger = trigger.clone();
+            let completion = completion.clone();
+            let notifier = dispatcher.notifier();
+            let flushed = flushed[i].clone();
 
             let thread = std::thread::spawn(move || {
                 let mut buf = vec![666; block_size];
+                // Position in the device FIFO, where the trigger opens the channels
+                let mut position = 0u64;
+                // Whether the device went unread since the end of a finite acquisition
+                let mut idle = false;
+                // Index and flags of the marked words of the last read
+                let mut marked = Vec::new();
                 //tx.send((0..10).into_iter().map(|n| n*i as u32).collect()).expect("Failed to send to fifo");
                 b.wait();
 
@@ -125,22 +332,161 @@ impl Aoldaq {
                         std::thread::park();
                     }
 
+                    // Finite acquisition done for these channels, idle until stopped.
+                    // Every quota is checked, as that's when they see a new acquisition.
+                    let full = lanes.iter_mut().map(|lane| lane.quota.is_full(&completion)).filter(|&full| full).count();
+                    if full == lanes.len() {
+                        idle = true;
+                        std::thread::park_timeout(std::time::Duration::from_millis(10));
+                        continue;
+                    }
+
+                    // What the device buffered meanwhile is stale, and may have overflowed
+                    if idle {
+                        idle = false;
+
+                        match device.flush(i) {
+                            Ok(n) => position += n as u64,
+                            Err(e) => {
+                                log::error!("Failed to flush device FIFO {} after a finite acquisition: {}", i, e);
+                                for lane in &lanes {
+                                    lane.stats.error(e);
+                                    notifier.error(lane.channel, e);
+                                }
+                            }
+                        }
+                    }
+
+                    position += flushed.swap(0, Ordering::SeqCst);
+
+                    // The first device FIFO is the one that watches the hardware trigger
+                    if i == 0 && trigger.mode == AoldaqTriggerMode::Fpga && !trigger.is_fired() {
+                        match device.poll_trigger() {
+                            Ok(true) => trigger.fire_at(position),
+                            Ok(false) => (),
+                            Err(e) => {
+                                log::error!("Device trigger error: {}", e);
+                                for lane in &lanes {
+                                    lane.stats.error(e);
+                                    notifier.error(lane.channel, e);
+                                }
+                            }
+                        }
+                    }
+
                     match device.read_into(i, &mut buf[..]) {
-                        Ok(_n) => {
-                            let mut written = 0;
+                        // Nothing read, so there is no block
+                        Ok(0) => (),
+                        Ok(n) => {
+                            // Only the points read are new, the rest is from earlier reads
+                            let words = &buf[..n.min(block_size)];
+
+                            let timestamp_ns = std::time::SystemTime::now()
+                                .duration_since(std::time::UNIX_EPOCH)
+                                .map(|t| t.as_nanos() as u64)
+                                .unwrap_or(0);
+                            let fpga_ticks = device.ticks();
+
+                            if marker_mask != 0 {
+                                marked.clear();
+                                marked.extend(words.iter()
+                                    .enumerate()
+                                    .filter(|(_, &word)| word & marker_mask != 0)
+                                    .map(|(index, &word)| (index, word & marker_mask)));
+                            }
 
-                            while written < block_size && can_acquire.load(Ordering::Relaxed) {
-                                written += tx.push_slice(&buf[written..]);
+                            for lane in &mut lanes {
+                                lane.stats.block(words.len());
+                                let pixels = lane.load(words);
 
-                                if written < block_size {
-                                    log::debug!("Overflow: Full fifo for channel {}, wrote {} out of {}", i, written, block_size);
+                                if let Some(ref histogram) = lane.histogram {
+                                    histogram.add(&lane.buf[..pixels]);
                                 }
+
+                                if let Some(ref preview) = lane.preview {
+                                    preview.push(lane.stream_pos, &lane.buf[..pixels]);
+                                }
+
+                                let block_sequence = lane.sequence;
+                                let block_pos = lane.stream_pos;
+                                lane.sequence += 1;
+                                lane.stream_pos += pixels as u64;
+
+                                let start = match lane.gate.admit(&trigger, &lane.buf[..pixels], position) {
+                                    Some(start) => start,
+                                    None => continue,
+                                };
+
+                                let fifo_pos = lane.tx.pushed();
+                                let fifo_len = lane.tx.len();
+                                let mut written = 0;
+
+                                let window = lane.gate.drain_window();
+                                if !window.is_empty() {
+                                    log::debug!("Channel {} triggered with {} pre-trigger points", lane.channel, window.len());
+                                    let n = lane.quota.take(&completion, window.len());
+                                    written += lane.tx.push(&window[..n], &can_acquire, lane.channel);
+                                }
+
+                                let before = written;
+                                let n = lane.quota.take(&completion, pixels - start);
+                                written += lane.tx.push(&lane.buf[start..start + n], &can_acquire, lane.channel);
+
+                                lane.stats.delivered(written);
+                                lane.stats.overflows(lane.tx.overflows());
+
+                                if written > 0 {
+                                    // Markers of the delivered points of this block
+                                    lane.marks.clear();
+                                    for &(index, flags) in &marked {
+                                        let point = index / lane.binning.factor();
+                                        if point < start || point - start >= written - before {
+                                            continue;
+                                        }
+
+                                        let marker = AoldaqMarker { point: block_pos + point as u64, flags };
+                                        lane.marks.push((marker, before + point - start));
+                                    }
+
+                                    for &(marker, offset) in &lane.marks {
+                                        lane.tx.mark(marker, fifo_pos + offset as u64);
+                                        notifier.marker(lane.channel, marker.flags);
+                                    }
+
+                                    let info = AoldaqBlockInfo {
+                                        sequence: block_sequence,
+                                        first_point: block_pos + start as u64 - window.len() as u64,
+                                        len: written,
+                                        timestamp_ns,
+                                        fpga_ticks: fpga_ticks.unwrap_or(0),
+                                        has_fpga_ticks: fpga_ticks.is_some(),
+                                    };
+                                    lane.tx.record(info, fifo_pos);
+
+                                    let delivered = [&window[..before], &lane.buf[start..start + written - before]];
+                                    lane.fanout.deliver(delivered, &lane.marks, info, &can_acquire);
+
+                                    if let Some(ref mut shm) = lane.shm {
+                                        shm.write(delivered[0]);
+                                        shm.write(delivered[1]);
+                                    }
+
+                                    notifier.data(lane.channel, fifo_len, lane.tx.len());
+                                }
+
+                                lane.quota.settle(&completion);
                             }
 
+                            position += words.len() as u64;
+
                             //tx.send(device.read_data(i, BUCKET_SIZE)).expect("Failed to send to fifo");
                         }
                         Err(e) => {
                             log::error!("Device read error: {}", e);
+                            for lane in &lanes {
+                                lane.stats.error(e);
+                                notifier.error(lane.channel, e);
+                            }
                         }
                     };
                 }
@@ -152,9 +498,10 @@ impl Aoldaq {
 
         log::info!("AOLDAQ started.");
 
-        Aoldaq {
-            n_channels: args.n_channels,
-            mode: args.mode,
+        Ok(Aoldaq {
+            n_channels: options.n_channels,
+            block_size,
+            event_points: options.event_points(),
             threads,
             can_acquire,
             pause,
@@ -162,10 +509,83 @@ impl Aoldaq {
             fifos,
             device,
             pause_barrier,
-        }
+            trigger,
+            frame_quotas: (0..options.n_channels).map(|_| FrameQuota::new(&completion)).collect(),
+            completion,
+            dispatcher,
+            stats,
+            histograms,
+            previews,
+            fanouts,
+            framers,
+            channel_fifos: (0..options.n_channels).map(|i| options.channel_fifo(i)).collect(),
+            flushed,
+        })
     }
 
+    /// Starts the acquisition, arming the trigger. Data only reaches the FIFOs
+    /// after the trigger fires, except for the pre-trigger window.
     pub fn start(&self) {
+        self.completion.begin(0);
+        self.trigger.arm();
+        self.resume();
+        self.dispatcher.notifier().event(AoldaqEvent::Started);
+    }
+
+    /// Starts a finite acquisition, in which the threads stop on their own after
+    /// delivering exactly `n` points per channel, counted from the trigger
+    /// (pre-trigger points included). Once done, the device is no longer read,
+    /// and what it buffers until the next acquisition is flushed. In photon
+    /// counting, `n` is rounded up to whole events.
+    pub fn start_finite(&self, n: usize) {
+        self.completion.begin(n.div_ceil(self.event_points) * self.event_points);
+        self.trigger.arm();
+        self.resume();
+        self.dispatcher.notifier().event(AoldaqEvent::Started);
+    }
+
+    /// Starts a finite acquisition of `n` raw frames per channel, before
+    /// averaging. Without sync or photon counting, that is `n * width * height`
+    /// points, as in `start_finite`. Otherwise frames are only known as they
+    /// are read, so the acquisition is done once `get_frame_into` went through
+    /// `n` of them on every channel. Fails if the instance has no frame settings.
+    pub fn start_finite_frames(&self, n: usize) -> Result<(), AoldaqError> {
+        let framer = self.framers.first().ok_or(AoldaqError::MissingOptions("frame"))?;
+
+        if framer.is_fixed() {
+            self.completion.begin(n * framer.frame_len());
+        } else {
+            self.completion.begin_frames(n);
+        }
+
+        self.trigger.arm();
+        self.resume();
+        self.dispatcher.notifier().event(AoldaqEvent::Started);
+        Ok(())
+    }
+
+    /// Returns whether the current finite acquisition delivered all of its points.
+    /// Always false for continuous acquisitions.
+    pub fn is_done(&self) -> bool {
+        self.completion.is_done()
+    }
+
+    /// Blocks until the current finite acquisition is done, for at most `timeout`.
+    /// Returns whether it is done. Returns right away for continuous acquisitions.
+    pub fn wait_done(&self, timeout: std::time::Duration) -> bool {
+        self.completion.wait(timeout)
+    }
+
+    /// Fires the trigger by software. Works in every trigger mode.
+    pub fn trigger(&self) {
+        self.trigger.fire();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.trigger.is_fired()
+    }
+
+    fn resume(&self) {
         self.pause.store(false, Ordering::SeqCst);
         self.can_acquire.store(true, Ordering::SeqCst);
         for t in &self.threads {
@@ -173,7 +593,32 @@ impl Aoldaq {
         }
     }
 
+    /// Stops the acquisition, parking the threads.
     pub fn stop(&self) {
+        self.pause_threads();
+        self.dispatcher.notifier().event(AoldaqEvent::Stopped);
+    }
+
+    /// Registers `callback` to be called from a dispatcher thread whenever the
+    /// FIFO of `channel` goes from less than `threshold` points to `threshold`
+    /// points or more, on state changes, and on the errors and markers of
+    /// `channel`. Returns an id for `unregister_callback`. Callbacks can
+    /// register and unregister callbacks, including themselves, but must not
+    /// drop the instance.
+    pub fn register_callback<F>(&self, channel: usize, threshold: usize, callback: F) -> usize
+        where F: FnMut(usize, AoldaqEvent, i64) + Send + 'static
+    {
+        self.dispatcher.register(channel, threshold, Box::new(callback))
+    }
+
+    /// Unregisters a callback. Once this returns, the callback won't be called
+    /// again and isn't running, unless this is called from within a callback:
+    /// then it doesn't wait, as the running callback could be the caller.
+    pub fn unregister_callback(&self, id: usize) -> bool {
+        self.dispatcher.unregister(id)
+    }
+
+    fn pause_threads(&self) {
         self.pause.store(true, Ordering::SeqCst);
         self.can_acquire.store(false, Ordering::SeqCst);
         self.pause_barrier.wait();
@@ -225,6 +670,174 @@ impl Aoldaq {
         rx.pop_slice(buf)
     }
 
+    /// Waits for at most `timeout` until every channel has at least `n` points,
+    /// then pops exactly `n` points from each channel into `bufs[channel]`.
+    /// Returns `n`, or 0 without consuming anything if the timeout expired.
+    /// Returns 0 as well if there are fewer buffers than channels, or one is
+    /// shorter than `n`.
+    pub fn get_data_all(&mut self, bufs: &mut [&mut [u32]], n: usize, timeout: std::time::Duration) -> usize {
+        if bufs.len() < self.n_channels || bufs.iter().any(|buf| buf.len() < n) {
+            log::error!("Expected a buffer of {} points for each of the {} channels", n, self.n_channels);
+            return 0;
+        }
+
+        if !self.wait_for_all(n, timeout) {
+            return 0;
+        }
+
+        for (rx, buf) in self.fifos.iter_mut().zip(bufs.iter_mut()) {
+            rx.pop_slice(&mut buf[..n]);
+        }
+
+        n
+    }
+
+    /// Same as `get_data_all`, but the points are interleaved into `buf`, one
+    /// point of each channel after the other. `buf` must hold `n * n_channels`
+    /// points, or 0 is returned.
+    pub fn get_data_all_interleaved(&mut self, buf: &mut [u32], n: usize, timeout: std::time::Duration) -> usize {
+        let n_channels = self.n_channels;
+        if n.checked_mul(n_channels).map_or(true, |len| buf.len() < len) {
+            log::error!("Buffer of {} points too small for {} points of {} channels", buf.len(), n, n_channels);
+            return 0;
+        }
+
+        if !self.wait_for_all(n, timeout) {
+            return 0;
+        }
+
+        for (channel, rx) in self.fifos.iter_mut().enumerate() {
+            let (head, tail) = rx.as_slices();
+
+            for (i, &point) in head.iter().chain(tail.iter()).take(n).enumerate() {
+                buf[i * n_channels + channel] = point;
+            }
+
+            rx.discard(n);
+        }
+
+        n
+    }
+
+    fn wait_for_all(&self, n: usize, timeout: std::time::Duration) -> bool {
+        let ready = self.dispatcher.notifier().wait_data(timeout, || self.fifos.iter().all(|rx| rx.len() >= n));
+
+        if !ready {
+            log::debug!("Underflow: Timed out waiting for {} points on all channels: {:?}",
+                        n,
+                        self.fifos.iter().map(|rx| rx.len()).collect::<Vec<_>>());
+        }
+
+        ready
+    }
+
+    /// Pops the points of the oldest block of `channel` into `buf`, without
+    /// crossing into the next block, along with where and when they were
+    /// acquired. Returns `None` if there is no data.
+    pub fn get_block_into(&mut self, channel: usize, buf: &mut [u32]) -> Option<AoldaqBlockInfo> {
+        self.fifos.get_mut(channel)?.pop_block(buf)
+    }
+
+    /// Waits for at most `timeout` until a whole frame of `channel` is ready,
+    /// and writes it to `buf`, corrected and averaged as set in `FrameOptions`.
+    /// `buf` must hold `width * height` points. Returns `None` if the timeout
+    /// expired, or if the instance has no frame settings. Frames popped before
+    /// the timeout stay in the average, so nothing is lost. With sync, points
+    /// that don't belong to a frame starting on its marker are discarded.
+    /// Also returns `None` once the frames of `start_finite_frames` were read.
+    pub fn get_frame_into(&mut self, channel: usize, buf: &mut [u32], timeout: std::time::Duration) -> Option<AoldaqFrameInfo> {
+        let framer = self.framers.get_mut(channel)?;
+        let quota = &mut self.frame_quotas[channel];
+        let n = framer.frame_len();
+        assert!(buf.len() >= n, "Buffer too small for a frame");
+
+        let rx = &mut self.fifos[channel];
+        let wait_interval = std::time::Duration::from_millis(1);
+        let mut time_spent = std::time::Duration::from_micros(0);
+
+        loop {
+            if quota.is_full(&self.completion) {
+                return None;
+            }
+
+            while !framer.fill(rx, &mut buf[..n]) {
+                if time_spent >= timeout {
+                    return None;
+                }
+
+                std::thread::sleep(wait_interval);
+                time_spent += wait_interval;
+            }
+
+            quota.take(&self.completion);
+
+            if let Some(info) = framer.process(&mut buf[..n]) {
+                return Some(info);
+            }
+        }
+    }
+
+    /// Copies to `buf` the lifetime histograms of the last frame of `channel`
+    /// returned by `get_frame_into`, in photon counting mode: `lifetime_bins`
+    /// counts for each pixel, pixel after pixel. With averaging, they add up
+    /// every frame that went into it. Returns the amount of counts copied.
+    pub fn get_lifetime_into(&self, channel: usize, buf: &mut [u32]) -> usize {
+        let lifetime = self.framers.get(channel).map_or(&[][..], |framer| framer.lifetime());
+        let n = lifetime.len().min(buf.len());
+
+        buf[..n].copy_from_slice(&lifetime[..n]);
+        n
+    }
+
+    /// Times the frames of `channel` were re-aligned on their markers so far.
+    pub fn get_frame_resyncs(&self, channel: usize) -> u64 {
+        self.framers.get(channel).map_or(0, |framer| framer.resyncs())
+    }
+
+    /// Copies to `buf` the markers of the points of `channel` still in its FIFO,
+    /// oldest first, without removing them. Markers go away with their points.
+    /// Returns the amount of markers copied.
+    pub fn get_markers(&self, channel: usize, buf: &mut [AoldaqMarker]) -> usize {
+        self.fifos[channel].markers()
+            .zip(buf.iter_mut())
+            .map(|((_, marker), slot)| *slot = marker)
+            .count()
+    }
+
+    /// Amount of points in a frame, or zero if the instance has no frame
+    /// settings.
+    pub fn get_frame_len(&self) -> usize {
+        self.framers.first().map_or(0, |framer| framer.frame_len())
+    }
+
+    /// Bins of the lifetime histogram of each pixel, in photon counting mode,
+    /// or zero.
+    pub fn get_lifetime_bins(&self) -> usize {
+        self.framers.first().map_or(0, |framer| framer.lifetime_bins())
+    }
+
+    /// Sets the shift of the reversed lines of bidirectional frames, in pixels,
+    /// on every channel. With auto-phase on, the estimate starts from here.
+    pub fn set_phase(&mut self, phase: f32) {
+        for framer in &mut self.framers {
+            framer.set_phase(phase);
+        }
+    }
+
+    /// Returns the shift of the reversed lines of `channel`, in pixels, or zero
+    /// if the instance has no frame settings.
+    pub fn get_phase(&self, channel: usize) -> f32 {
+        self.framers.get(channel).map_or(0.0, |framer| framer.phase())
+    }
+
+    pub fn get_n_channels(&self) -> usize {
+        self.n_channels
+    }
+
+    pub fn get_block_size(&self) -> usize {
+        self.block_size
+    }
+
     pub fn get_fifo_size(&self, channel: usize) -> usize {
         self.fifos[channel].len()
     }
@@ -233,7 +846,7 @@ impl Aoldaq {
         let should_restart = !self.pause.load(Ordering::Relaxed);
         // let was_acquiring = self.can_acquire.load(Ordering::Relaxed);
 
-        if should_restart { self.stop(); }
+        if should_restart { self.pause_threads(); }
         //while let Ok(data) = self.fifos[channel].try_recv() {
             //drop(data);
         //}
@@ -260,51 +873,97 @@ impl Aoldaq {
                     .map(|i| self.get_fifo_size(i))
                     .collect::<Vec<_>>());
 
-        unsafe {
-            self.flush_hardware_fifo(channel);
+        if let Err(e) = self.flush_hardware_fifo(channel) {
+            log::error!("Failed to flush the hardware fifo of channel {}: {}", channel, e);
         }
 
         // if was_acquiring { self.can_acquire.store(true, Ordering::SeqCst); }
         self.can_acquire.store(true, Ordering::SeqCst);
-        if should_restart { self.start(); }
+        if should_restart { self.resume(); }
 
         log::debug!("flush_fifo done");
     }
 
-    pub unsafe fn flush_hardware_fifo(&self, channel: usize) {
-        match self.mode {
-            AoldaqMode::Random => (),
-            AoldaqMode::NiFpga => {
-                let n = self.device.poll(channel).unwrap_or(0);
-                log::debug!("current total points in hw fifo channel {}: {}", channel, n);
-                let mut buffer= vec![0u32; n];
-                let ptr = Arc::as_ptr(&self.device);
-                let device: *const device::NiFpgaDevice = ptr as *const _;
+    /// Discards the points in the hardware buffer of `channel`, returning how
+    /// many were discarded. Does nothing for devices without one. Channels
+    /// unpacked from the same device FIFO lose their points as well.
+    pub fn flush_hardware_fifo(&self, channel: usize) -> Result<usize, AoldaqError> {
+        let fifo = self.channel_fifos[channel];
+        let n = self.device.flush(fifo).map_err(|e| device_error(e, "flush"))?;
+        self.flushed[fifo].fetch_add(n as u64, Ordering::SeqCst);
+        log::debug!("Flushed {} points from the hardware fifo of channel {}", n, channel);
 
-                let session = ( *device ).get_nifpga_session();
+        Ok(n)
+    }
 
-                let fifo = (*device).addrs[channel];
+    /// Returns the counters of `channel`.
+    pub fn get_stats(&self, channel: usize) -> AoldaqStats {
+        self.stats[channel].snapshot(self.fifos[channel].len())
+    }
 
-                nifpga::NiFpga_ReadFifoU32(session,
-                                           fifo,
-                                           buffer.as_mut_ptr(),
-                                           n as u64,
-                                           100,
-                                           std::ptr::null_mut());
-                log::debug!("current total points in hw fifo channel {}: {}", channel, n);
-            }
-        }
+    /// Copies to `buf` the histogram of the latest points read from `channel`,
+    /// as many bins as fit, and returns their statistics. Every point read
+    /// counts, even those before the trigger, and the FIFO is left alone.
+    /// Returns `None` if the instance has no histogram settings.
+    pub fn get_histogram_into(&self, channel: usize, buf: &mut [u64]) -> Option<AoldaqHistogramStats> {
+        self.histograms.get(channel).map(|histogram| histogram.snapshot(buf))
     }
 
-    pub fn get_nifpga_session(&self) -> Option<nifpga::NiFpga_Session> {
-        match self.mode {
-            AoldaqMode::Random => None,
-            AoldaqMode::NiFpga => {
-                let ptr = Arc::as_ptr(&self.device);
-                let device: *const device::NiFpgaDevice = ptr as *const _;
-                Some(unsafe { (*device).get_nifpga_session() })
-            }
+    /// Adds a consumer of `channel`, with its own FIFO of `capacity` points,
+    /// which gets a copy of every point delivered to the channel from now on.
+    /// With `AoldaqOverflow::Drop`, a slow reader loses points instead of
+    /// stalling the acquisition. With `AoldaqOverflow::Block`, it stalls it for
+    /// at most `STALL_TIMEOUT` at a time, then loses points until it catches up. Flushing the channel leaves its readers alone.
+    pub fn add_reader(&self, channel: usize, capacity: usize, overflow: AoldaqOverflow) -> Option<AoldaqReader> {
+        self.fanouts.get(channel).map(|fanout| fanout.add(capacity, overflow))
+    }
+
+    /// Copies to `buf` the latest points read from `channel`, as many as fit,
+    /// up to the preview size, without taking them from the FIFO. Every point
+    /// read counts, even those before the trigger. The preview is lossy: blocks
+    /// read while it is being copied are skipped, and the points before the
+    /// gap are dropped. Returns zero points without preview settings.
+    pub fn get_preview_into(&self, channel: usize, buf: &mut [u32]) -> AoldaqPreview {
+        self.previews.get(channel).map_or(AoldaqPreview::default(), |preview| preview.latest(buf))
+    }
+
+    /// Copies to `buf`, which must hold `width * height` points, the latest
+    /// whole frame in the preview of `channel`, raw, and returns its number.
+    /// Frames are counted from the start of the stream, as without sync.
+    /// Returns `None` without frame settings or if the preview doesn't hold a
+    /// whole frame yet, which takes a preview size of at least twice the frame.
+    pub fn get_preview_frame_into(&self, channel: usize, buf: &mut [u32]) -> Option<u64> {
+        let n = self.get_frame_len();
+        if n == 0 {
+            return None;
         }
+
+        self.previews.get(channel).and_then(|preview| preview.latest_period(&mut buf[..n]))
+    }
+
+    pub fn read_register(&self, address: u32) -> Result<u32, AoldaqError> {
+        self.device.read_register(address).map_err(|e| device_error(e, "register access"))
+    }
+
+    pub fn write_register(&self, address: u32, value: u32) -> Result<(), AoldaqError> {
+        self.device.write_register(address, value).map_err(|e| device_error(e, "register access"))
+    }
+
+    pub fn get_nifpga_session(&self) -> Option<u32> {
+        self.device.session()
+    }
+
+    /// Returns the device of the instance. Use `Device::as_any` to downcast it.
+    pub fn device(&self) -> &dyn Device {
+        &*self.device
+    }
+}
+
+fn device_error(status: i32, operation: &'static str) -> AoldaqError {
+    if status == UNSUPPORTED {
+        AoldaqError::Unsupported(operation)
+    } else {
+        AoldaqError::Device(status)
     }
 }
 
@@ -312,7 +971,10 @@ impl Drop for Aoldaq {
     fn drop(&mut self) {
         log::info!("AOLDAQ finishing...");
         self.run.store(false, Ordering::Relaxed);
-        self.start();
+        self.resume();
+
+        // Threads stuck on a full FIFO give up the push, so they can see `run`
+        self.can_acquire.store(false, Ordering::SeqCst);
 
         for t in self.threads.drain(..) {
             t.join().unwrap();
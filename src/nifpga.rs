@@ -3,4 +3,35 @@
 #![allow(non_snake_case)]
 #![allow(dead_code)]
 
+#[cfg(not(any(test, feature = "mock-nifpga")))]
 include!(concat!(env!("OUT_DIR"), "/nifpga.rs"));
+
+// The mock functions shadow the ones from the bindings
+#[cfg(any(test, feature = "mock-nifpga"))]
+mod bindings {
+    include!(concat!(env!("OUT_DIR"), "/nifpga.rs"));
+}
+
+#[cfg(any(test, feature = "mock-nifpga"))]
+pub use bindings::*;
+
+#[cfg(any(test, feature = "mock-nifpga"))]
+pub mod mock;
+
+#[cfg(any(test, feature = "mock-nifpga"))]
+pub use mock::{
+    NiFpga_Initialize,
+    NiFpga_Finalize,
+    NiFpga_Open,
+    NiFpga_Close,
+    NiFpga_ConfigureFifo,
+    NiFpga_ReadFifoU32,
+    NiFpga_ReadFifoU64,
+    NiFpga_ReadU32,
+    NiFpga_ReadU64,
+    NiFpga_WriteU32,
+    NiFpga_ReserveIrqContext,
+    NiFpga_UnreserveIrqContext,
+    NiFpga_WaitOnIrqs,
+    NiFpga_AcknowledgeIrqs,
+};
@@ -0,0 +1,199 @@
+//! In-process stand-in for the NiFpga runtime, used by the tests and by the
+//! `mock-nifpga` feature to run without NI hardware. Only the functions used by
+//! aoldaq are provided, with the same signatures as the bindings.
+//!
+//! Every DMA FIFO of every session produces a running count, starting at zero,
+//! at about `RATE` points per second. Read as U64, each element holds two
+//! consecutive points, low half first. Registers read back what was written,
+//! and zero otherwise.
+
+use std::collections::{ BTreeMap, HashMap };
+use std::os::raw::c_char;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::bindings::*;
+
+/// Points per second produced by each FIFO.
+const RATE: u64 = 1_000_000;
+
+struct Session {
+    fifos: HashMap<u32, u32>,
+    registers: HashMap<u32, u64>,
+}
+
+struct Runtime {
+    loaded: bool,
+    next_session: NiFpga_Session,
+    sessions: BTreeMap<NiFpga_Session, Session>,
+}
+
+static RUNTIME: Mutex<Runtime> = Mutex::new(Runtime {
+    loaded: false,
+    next_session: 1,
+    sessions: BTreeMap::new(),
+});
+
+/// Runs `f` on `session`, failing like the real library does when it isn't
+/// loaded or the session isn't open.
+fn with_session<F: FnOnce(&mut Session) -> NiFpga_Status>(session: NiFpga_Session, f: F) -> NiFpga_Status {
+    let mut runtime = RUNTIME.lock().unwrap();
+
+    if !runtime.loaded {
+        return NiFpga_Status_ResourceNotInitialized;
+    }
+
+    match runtime.sessions.get_mut(&session) {
+        Some(session) => f(session),
+        None => NiFpga_Status_InvalidSession,
+    }
+}
+
+/// Returns whether the library is loaded, that is, initialized and not yet
+/// finalized.
+pub fn is_loaded() -> bool {
+    RUNTIME.lock().unwrap().loaded
+}
+
+/// Returns the amount of open sessions.
+pub fn open_sessions() -> usize {
+    RUNTIME.lock().unwrap().sessions.len()
+}
+
+pub unsafe fn NiFpga_Initialize() -> NiFpga_Status {
+    RUNTIME.lock().unwrap().loaded = true;
+    NiFpga_Status_Success
+}
+
+pub unsafe fn NiFpga_Finalize() -> NiFpga_Status {
+    RUNTIME.lock().unwrap().loaded = false;
+    NiFpga_Status_Success
+}
+
+pub unsafe fn NiFpga_Open(_bitfile: *const c_char, _signature: *const c_char, _resource: *const c_char, _attribute: u32, session: *mut NiFpga_Session) -> NiFpga_Status {
+    let mut runtime = RUNTIME.lock().unwrap();
+
+    if !runtime.loaded {
+        return NiFpga_Status_ResourceNotInitialized;
+    }
+
+    let id = runtime.next_session;
+    runtime.next_session += 1;
+    runtime.sessions.insert(id, Session { fifos: HashMap::new(), registers: HashMap::new() });
+    *session = id;
+
+    NiFpga_Status_Success
+}
+
+pub unsafe fn NiFpga_Close(session: NiFpga_Session, _attribute: u32) -> NiFpga_Status {
+    let mut runtime = RUNTIME.lock().unwrap();
+
+    if !runtime.loaded {
+        return NiFpga_Status_ResourceNotInitialized;
+    }
+
+    match runtime.sessions.remove(&session) {
+        Some(_) => NiFpga_Status_Success,
+        None => NiFpga_Status_InvalidSession,
+    }
+}
+
+pub unsafe fn NiFpga_ConfigureFifo(session: NiFpga_Session, _fifo: u32, _depth: size_t) -> NiFpga_Status {
+    with_session(session, |_| NiFpga_Status_Success)
+}
+
+pub unsafe fn NiFpga_ReadFifoU32(session: NiFpga_Session, fifo: u32, data: *mut u32, numberOfElements: size_t, _timeout: u32, elementsRemaining: *mut size_t) -> NiFpga_Status {
+    let n = numberOfElements as usize;
+
+    let status = with_session(session, |session| {
+        let next = session.fifos.entry(fifo).or_insert(0);
+
+        for i in 0..n {
+            *data.add(i) = *next;
+            *next = next.wrapping_add(1);
+        }
+
+        if !elementsRemaining.is_null() {
+            *elementsRemaining = 0;
+        }
+
+        NiFpga_Status_Success
+    });
+
+    // Pace the reads like a device would, outside of the lock
+    if status == NiFpga_Status_Success && n > 0 {
+        std::thread::sleep(Duration::from_micros(n as u64 * 1_000_000 / RATE));
+    }
+
+    status
+}
+
+pub unsafe fn NiFpga_ReadFifoU64(session: NiFpga_Session, fifo: u32, data: *mut u64, numberOfElements: size_t, _timeout: u32, elementsRemaining: *mut size_t) -> NiFpga_Status {
+    let n = numberOfElements as usize;
+
+    let status = with_session(session, |session| {
+        let next = session.fifos.entry(fifo).or_insert(0);
+
+        for i in 0..n {
+            *data.add(i) = *next as u64 | (next.wrapping_add(1) as u64) << 32;
+            *next = next.wrapping_add(2);
+        }
+
+        if !elementsRemaining.is_null() {
+            *elementsRemaining = 0;
+        }
+
+        NiFpga_Status_Success
+    });
+
+    if status == NiFpga_Status_Success && n > 0 {
+        std::thread::sleep(Duration::from_micros(2 * n as u64 * 1_000_000 / RATE));
+    }
+
+    status
+}
+
+pub unsafe fn NiFpga_ReadU32(session: NiFpga_Session, indicator: u32, value: *mut u32) -> NiFpga_Status {
+    with_session(session, |session| {
+        *value = session.registers.get(&indicator).copied().unwrap_or(0) as u32;
+        NiFpga_Status_Success
+    })
+}
+
+pub unsafe fn NiFpga_ReadU64(session: NiFpga_Session, indicator: u32, value: *mut u64) -> NiFpga_Status {
+    with_session(session, |session| {
+        *value = session.registers.get(&indicator).copied().unwrap_or(0);
+        NiFpga_Status_Success
+    })
+}
+
+pub unsafe fn NiFpga_WriteU32(session: NiFpga_Session, control: u32, value: u32) -> NiFpga_Status {
+    with_session(session, |session| {
+        session.registers.insert(control, value as u64);
+        NiFpga_Status_Success
+    })
+}
+
+pub unsafe fn NiFpga_ReserveIrqContext(session: NiFpga_Session, context: *mut NiFpga_IrqContext) -> NiFpga_Status {
+    with_session(session, |_| {
+        *context = std::ptr::NonNull::dangling().as_ptr();
+        NiFpga_Status_Success
+    })
+}
+
+pub unsafe fn NiFpga_UnreserveIrqContext(session: NiFpga_Session, _context: NiFpga_IrqContext) -> NiFpga_Status {
+    with_session(session, |_| NiFpga_Status_Success)
+}
+
+/// IRQs are never asserted.
+pub unsafe fn NiFpga_WaitOnIrqs(session: NiFpga_Session, _context: NiFpga_IrqContext, _irqs: u32, _timeout: u32, irqsAsserted: *mut u32, timedOut: *mut NiFpga_Bool) -> NiFpga_Status {
+    with_session(session, |_| {
+        *irqsAsserted = 0;
+        *timedOut = 1;
+        NiFpga_Status_Success
+    })
+}
+
+pub unsafe fn NiFpga_AcknowledgeIrqs(session: NiFpga_Session, _irqs: u32) -> NiFpga_Status {
+    with_session(session, |_| NiFpga_Status_Success)
+}
@@ -0,0 +1,295 @@
+//! Photon counting: instead of sampled intensities, the channel carries
+//! time-tagged events, each a U64 word sent as two points, low half first.
+//! Every event has a channel and a timestamp. Events on the clock channels
+//! place the others, the photons, on pixels, which are counted into frames
+//! and, optionally, into per-pixel lifetime histograms (TCSPC).
+
+use crate::AoldaqError;
+
+/// Layout of the events and how they map to pixels.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PhotonOptions {
+    /// Lowest bit and width of the channel of an event.
+    pub channel_shift: u32,
+    pub channel_bits: u32,
+    /// Lowest bit and width of the timestamp of an event. Timestamps wrap
+    /// around at `2^time_bits`.
+    pub time_shift: u32,
+    pub time_bits: u32,
+    /// Channel of the clock that starts each pixel. Without it, pixels last
+    /// `pixel_time` from the start of their line.
+    pub pixel_channel: Option<u32>,
+    pub pixel_time: u64,
+    /// Channel of the clock that starts each line.
+    pub line_channel: u32,
+    /// Channel of the clock that starts each frame. Without it, a frame ends
+    /// after `height` lines.
+    pub frame_channel: Option<u32>,
+    /// Channel of the laser sync. Needed for the lifetime histograms, which
+    /// bin the time from the last sync to each photon.
+    pub sync_channel: Option<u32>,
+    /// Bins of the lifetime histogram of each pixel. Zero doesn't make them.
+    pub lifetime_bins: usize,
+    /// Width of the lifetime bins, in timestamp units.
+    pub lifetime_bin_width: u64,
+}
+
+impl Default for PhotonOptions {
+    fn default() -> PhotonOptions {
+        PhotonOptions {
+            channel_shift: 56,
+            channel_bits: 8,
+            time_shift: 0,
+            time_bits: 56,
+            pixel_channel: None,
+            pixel_time: 1,
+            line_channel: 1,
+            frame_channel: None,
+            sync_channel: None,
+            lifetime_bins: 0,
+            lifetime_bin_width: 1,
+        }
+    }
+}
+
+impl PhotonOptions {
+    pub fn validate(&self) -> Result<(), AoldaqError> {
+        if self.channel_bits == 0 || self.channel_bits > 32 || self.channel_shift + self.channel_bits > 64 {
+            return Err(AoldaqError::InvalidArgument(format!(
+                "invalid event channel bits {}-{}", self.channel_shift, self.channel_shift + self.channel_bits)));
+        }
+
+        if self.time_bits == 0 || self.time_shift + self.time_bits > 64 {
+            return Err(AoldaqError::InvalidArgument(format!(
+                "invalid event timestamp bits {}-{}", self.time_shift, self.time_shift + self.time_bits)));
+        }
+
+        if self.lifetime_bins > 0 && self.sync_channel.is_none() {
+            return Err(AoldaqError::InvalidArgument("lifetime histograms need a sync channel".to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+fn field(word: u64, shift: u32, bits: u32) -> u64 {
+    let mask = if bits >= 64 { u64::MAX } else { (1 << bits) - 1 };
+    word.checked_shr(shift).unwrap_or(0) & mask
+}
+
+/// Builds count frames out of the events of a channel.
+pub struct PhotonDecoder {
+    options: PhotonOptions,
+    width: usize,
+    height: usize,
+    /// Timestamp of the last event, and the amount of wrap-arounds before it.
+    last_time: u64,
+    epoch: u64,
+    /// Current pixel, or `None` before the first clock of a line or frame.
+    x: Option<usize>,
+    y: Option<usize>,
+    line_start: u64,
+    last_sync: Option<u64>,
+    /// Counts of the frame being acquired.
+    counts: Vec<u32>,
+    /// Lifetime histograms of the frames since the last `take_lifetime`.
+    lifetime: Vec<u32>,
+}
+
+impl PhotonDecoder {
+    pub fn new(options: &PhotonOptions, width: usize, height: usize) -> PhotonDecoder {
+        PhotonDecoder {
+            options: options.clone(),
+            width,
+            height,
+            last_time: 0,
+            epoch: 0,
+            x: None,
+            y: None,
+            line_start: 0,
+            last_sync: None,
+            counts: vec![0; width * height],
+            lifetime: vec![0; width * height * options.lifetime_bins],
+        }
+    }
+
+    /// Feeds the events in the points of `words`, stopping right after the one
+    /// that completes a frame. Returns the amount of points used and whether
+    /// a frame is complete, in which case `take_frame` returns it.
+    pub fn feed<I: Iterator<Item = u32>>(&mut self, mut words: I) -> (usize, bool) {
+        let mut used = 0;
+
+        while let (Some(low), Some(high)) = (words.next(), words.next()) {
+            used += 2;
+
+            if self.event(low as u64 | (high as u64) << 32) {
+                return (used, true);
+            }
+        }
+
+        (used, false)
+    }
+
+    /// Moves the counts of the last complete frame to `frame`.
+    pub fn take_frame(&mut self, frame: &mut [u32]) {
+        frame.copy_from_slice(&self.counts);
+        self.counts.iter_mut().for_each(|count| *count = 0);
+    }
+
+    /// Moves the lifetime histograms of the frames since the last call to
+    /// `lifetime`, `lifetime_bins` for each pixel.
+    pub fn take_lifetime(&mut self, lifetime: &mut Vec<u32>) {
+        lifetime.clear();
+        lifetime.extend_from_slice(&self.lifetime);
+        self.lifetime.iter_mut().for_each(|count| *count = 0);
+    }
+
+    /// Handles an event, returning whether it completed a frame.
+    fn event(&mut self, word: u64) -> bool {
+        let channel = field(word, self.options.channel_shift, self.options.channel_bits) as u32;
+        let time = field(word, self.options.time_shift, self.options.time_bits);
+        let time = self.unwrap(time);
+
+        if Some(channel) == self.options.frame_channel {
+            let complete = self.y.is_some();
+            self.y = None;
+            self.x = None;
+            return complete;
+        }
+
+        if channel == self.options.line_channel {
+            let y = self.y.map_or(0, |y| y + 1);
+            self.x = None;
+            self.line_start = time;
+
+            if y < self.height {
+                self.y = Some(y);
+                return false;
+            }
+
+            // Without a frame clock, this line starts the next frame
+            if self.options.frame_channel.is_none() {
+                self.y = Some(0);
+                return true;
+            }
+
+            self.y = Some(y);
+            return false;
+        }
+
+        if Some(channel) == self.options.pixel_channel {
+            self.x = Some(self.x.map_or(0, |x| x + 1));
+            return false;
+        }
+
+        if Some(channel) == self.options.sync_channel {
+            self.last_sync = Some(time);
+            return false;
+        }
+
+        // A photon
+        let x = match self.options.pixel_channel {
+            Some(_) => self.x,
+            None if self.y.is_some() => Some((time.saturating_sub(self.line_start) / self.options.pixel_time.max(1)) as usize),
+            None => None,
+        };
+
+        if let (Some(x), Some(y)) = (x, self.y) {
+            if x < self.width && y < self.height {
+                let pixel = y * self.width + x;
+                self.counts[pixel] = self.counts[pixel].saturating_add(1);
+
+                let bins = self.options.lifetime_bins;
+                if let Some(sync) = self.last_sync.filter(|_| bins > 0) {
+                    let bin = (time.saturating_sub(sync) / self.options.lifetime_bin_width.max(1)) as usize;
+                    if bin < bins {
+                        let count = &mut self.lifetime[pixel * bins + bin];
+                        *count = count.saturating_add(1);
+                    }
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Extends a timestamp that wraps around at `2^time_bits` to 64 bits,
+    /// assuming events come in order.
+    fn unwrap(&mut self, time: u64) -> u64 {
+        let bits = self.options.time_bits;
+
+        if time < self.last_time && bits < 64 {
+            self.epoch += 1;
+        }
+
+        self.last_time = time;
+
+        if bits >= 64 { time } else { (self.epoch << bits) | time }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(channel: u64, time: u64) -> [u32; 2] {
+        let word = channel << 56 | time;
+        [word as u32, (word >> 32) as u32]
+    }
+
+    #[test]
+    fn test_photon_frames() {
+        // 2x2 pixels of 10 time units, on lines every 100
+        let options = PhotonOptions {
+            pixel_time: 10,
+            line_channel: 1,
+            frame_channel: Some(2),
+            sync_channel: Some(3),
+            lifetime_bins: 5,
+            ..PhotonOptions::default()
+        };
+
+        let events = [
+            event(0, 1), // Before the frame, ignored
+            event(2, 100),
+            event(1, 100),
+            event(3, 100),
+            event(0, 102),
+            event(0, 104),
+            event(3, 110),
+            event(0, 111),
+            event(0, 130), // Past the end of the line
+            event(1, 200),
+            event(3, 215),
+            event(0, 218),
+            event(2, 300),
+            event(1, 300),
+        ];
+        let words = events.iter().flatten().copied().collect::<Vec<_>>();
+
+        let mut decoder = PhotonDecoder::new(&options, 2, 2);
+        let (used, complete) = decoder.feed(words.iter().copied());
+        assert!(complete);
+        assert_eq!(used, 2 * 13);
+
+        let mut frame = vec![0; 4];
+        decoder.take_frame(&mut frame);
+        assert_eq!(frame, vec![2, 1, 0, 1]);
+
+        let mut lifetime = Vec::new();
+        decoder.take_lifetime(&mut lifetime);
+        assert_eq!(&lifetime[..5], &[0, 0, 1, 0, 1]);
+        assert_eq!(&lifetime[5..10], &[0, 1, 0, 0, 0]);
+        assert_eq!(&lifetime[15..20], &[0, 0, 0, 1, 0]);
+
+        // Timestamps that wrap around
+        let options = PhotonOptions { time_bits: 8, ..options };
+        let mut decoder = PhotonDecoder::new(&options, 2, 2);
+        let events = [event(2, 250), event(1, 250), event(0, 255), event(0, 3), event(2, 10)];
+        let (_, complete) = decoder.feed(events.iter().flatten().copied());
+        assert!(complete);
+
+        decoder.take_frame(&mut frame);
+        assert_eq!(frame, vec![2, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,141 @@
+//! Preview tap: the latest points of each channel, kept aside by the producer
+//! threads for a live view. It is lossy and never blocks the producer, which
+//! skips a block instead of waiting for a reader, nor takes anything from the
+//! FIFOs.
+
+use std::sync::Mutex;
+
+/// Points copied out of the preview of a channel.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct AoldaqPreview {
+    /// Index of the first point copied in the stream of the channel, like
+    /// `AoldaqBlockInfo::first_point`.
+    pub first_point: u64,
+    pub len: usize,
+}
+
+struct Ring {
+    points: Vec<u32>,
+    /// Stream position right after the latest point.
+    end: u64,
+    /// Latest points that are contiguous in the stream.
+    valid: usize,
+}
+
+impl Ring {
+    /// Copies to `buf` the points ending at stream position `end`, which must
+    /// be in the ring.
+    fn copy(&self, end: u64, buf: &mut [u32]) {
+        let capacity = self.points.len();
+        let start = ((end - buf.len() as u64) % capacity as u64) as usize;
+        let first = buf.len().min(capacity - start);
+
+        buf[..first].copy_from_slice(&self.points[start..start + first]);
+        let rest = buf.len() - first;
+        buf[first..].copy_from_slice(&self.points[..rest]);
+    }
+}
+
+pub struct ChannelPreview {
+    ring: Mutex<Ring>,
+}
+
+impl ChannelPreview {
+    pub fn new(capacity: usize) -> ChannelPreview {
+        ChannelPreview {
+            ring: Mutex::new(Ring { points: vec![0; capacity], end: 0, valid: 0 }),
+        }
+    }
+
+    /// Keeps the points of a block starting at stream position `first_point`.
+    /// Gives up if a reader holds the preview.
+    pub fn push(&self, first_point: u64, points: &[u32]) {
+        let mut ring = match self.ring.try_lock() {
+            Ok(ring) => ring,
+            Err(_) => return,
+        };
+
+        let capacity = ring.points.len();
+
+        // A skipped block leaves a gap
+        if first_point != ring.end {
+            ring.valid = 0;
+        }
+
+        let end = first_point + points.len() as u64;
+        let points = &points[points.len().saturating_sub(capacity)..];
+
+        let pos = ((end - points.len() as u64) % capacity as u64) as usize;
+        let first = points.len().min(capacity - pos);
+        ring.points[pos..pos + first].copy_from_slice(&points[..first]);
+        let rest = points.len() - first;
+        ring.points[..rest].copy_from_slice(&points[first..]);
+
+        ring.end = end;
+        ring.valid = (ring.valid + points.len()).min(capacity);
+    }
+
+    /// Copies the latest points to `buf`, as many as fit.
+    pub fn latest(&self, buf: &mut [u32]) -> AoldaqPreview {
+        let ring = self.ring.lock().unwrap();
+        let len = buf.len().min(ring.valid);
+
+        ring.copy(ring.end, &mut buf[..len]);
+        AoldaqPreview { first_point: ring.end - len as u64, len }
+    }
+
+    /// Copies to `buf` the latest whole period of `buf.len()` points, counted
+    /// from the start of the stream, and returns its number.
+    pub fn latest_period(&self, buf: &mut [u32]) -> Option<u64> {
+        let ring = self.ring.lock().unwrap();
+        let period = buf.len() as u64;
+
+        if period == 0 {
+            return None;
+        }
+
+        let end = ring.end - ring.end % period;
+        if end < period || end - period < ring.end - ring.valid as u64 {
+            return None;
+        }
+
+        ring.copy(end, buf);
+        Some(end / period - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preview() {
+        let preview = ChannelPreview::new(8);
+        let mut buf = [0; 8];
+        assert_eq!(preview.latest(&mut buf).len, 0);
+
+        let points = (0..20).collect::<Vec<u32>>();
+        preview.push(0, &points[..5]);
+        preview.push(5, &points[5..11]);
+
+        let info = preview.latest(&mut buf);
+        assert_eq!(info, AoldaqPreview { first_point: 3, len: 8 });
+        assert_eq!(buf, [3, 4, 5, 6, 7, 8, 9, 10]);
+
+        // Latest whole period of 4 points: 4..8
+        assert_eq!(preview.latest_period(&mut buf[..4]), Some(1));
+        assert_eq!(&buf[..4], &[4, 5, 6, 7]);
+
+        // After a gap, only the points since count
+        preview.push(15, &points[15..18]);
+        assert_eq!(preview.latest(&mut buf), AoldaqPreview { first_point: 15, len: 3 });
+        assert_eq!(&buf[..3], &[15, 16, 17]);
+        assert_eq!(preview.latest_period(&mut buf[..4]), None);
+
+        // Blocks larger than the ring keep their end
+        preview.push(18, &(18..30).collect::<Vec<u32>>());
+        assert_eq!(preview.latest(&mut buf[..2]), AoldaqPreview { first_point: 28, len: 2 });
+        assert_eq!(&buf[..2], &[28, 29]);
+    }
+}
@@ -0,0 +1,195 @@
+//! Extra consumers of a channel. Every reader gets its own FIFO, holding a
+//! copy of the points delivered to the channel, so several consumers in the
+//! process, like a recorder and a display, can each go through the whole
+//! stream at their own pace.
+
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
+use std::time::{ Duration, Instant };
+
+use crate::{ AoldaqBlockInfo, AoldaqError, AoldaqMarker };
+use crate::fifo::{ self, FifoConsumer, FifoProducer };
+
+/// What the producer does when the FIFO of a reader is full.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum AoldaqOverflow {
+    /// Waits for the reader, like for the FIFO of the channel, but for at most
+    /// 100 ms (`STALL_TIMEOUT`) at a time, so a stuck reader can't overflow the
+    /// device FIFOs. Past that, the reader drops points until a whole block fits
+    /// again. A slow reader still stalls the acquisition, so keep it for the
+    /// readers that can't lose data.
+    Block,
+    /// Drops the points that don't fit, and goes on. The reader sees the gap
+    /// in `AoldaqBlockInfo::first_point`.
+    #[default]
+    Drop,
+}
+
+impl std::str::FromStr for AoldaqOverflow {
+    type Err = AoldaqError;
+
+    fn from_str(s: &str) -> Result<AoldaqOverflow, AoldaqError> {
+        match s.to_lowercase().as_str() {
+            "block" => Ok(AoldaqOverflow::Block),
+            "drop" => Ok(AoldaqOverflow::Drop),
+            _ => Err(AoldaqError::InvalidArgument(format!("unknown overflow policy {:?}", s))),
+        }
+    }
+}
+
+/// Longest the producer waits on a reader with `AoldaqOverflow::Block` before
+/// dropping its points.
+pub const STALL_TIMEOUT: Duration = Duration::from_millis(100);
+
+struct Tap {
+    tx: FifoProducer,
+    overflow: AoldaqOverflow,
+    /// Set when the producer gave up waiting on the reader, which then drops
+    /// points until it catches up.
+    stalled: bool,
+    /// Cleared when the reader is dropped.
+    attached: Arc<AtomicBool>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// Readers of a channel, fed by its producer thread.
+pub struct Fanout {
+    /// Points per block of the channel.
+    pixels: usize,
+    /// Points per event of the channel.
+    event_points: usize,
+    /// Readers added since the last block, picked up by the producer.
+    added: Mutex<Vec<Tap>>,
+    /// Readers being fed. Only the producer locks it, so it can wait on a
+    /// reader without holding up `add`.
+    taps: Mutex<Vec<Tap>>,
+}
+
+impl Fanout {
+    pub fn new(pixels: usize, event_points: usize) -> Fanout {
+        Fanout { pixels, event_points, added: Mutex::new(Vec::new()), taps: Mutex::new(Vec::new()) }
+    }
+
+    /// Adds a reader whose FIFO holds `capacity` points.
+    pub fn add(&self, capacity: usize, overflow: AoldaqOverflow) -> AoldaqReader {
+        let (tx, rx) = fifo::channel_fifo(capacity, self.pixels, self.event_points);
+        let attached = Arc::new(AtomicBool::new(true));
+        let dropped = Arc::new(AtomicU64::new(0));
+
+        self.added.lock().unwrap().push(Tap { tx, overflow, stalled: false, attached: attached.clone(), dropped: dropped.clone() });
+
+        AoldaqReader { rx, attached, dropped }
+    }
+
+    /// Copies to every reader the points delivered from a block, which are
+    /// `slices` one after the other, with their markers, at their offset in
+    /// the delivered points. `info` is what was recorded for the channel.
+    pub fn deliver(&self, slices: [&[u32]; 2], marks: &[(AoldaqMarker, usize)], info: AoldaqBlockInfo, can_acquire: &AtomicBool) {
+        let mut taps = self.taps.lock().unwrap();
+        taps.append(&mut self.added.lock().unwrap());
+        taps.retain(|tap| tap.attached.load(Ordering::Relaxed));
+
+        for tap in taps.iter_mut() {
+            let fifo_pos = tap.tx.pushed();
+            let deadline = Instant::now() + STALL_TIMEOUT;
+            let mut written = 0;
+            let mut overflowed = false;
+
+            // Once a slice doesn't fit, the rest is dropped, so what is kept is contiguous
+            for slice in &slices {
+                let mut n = tap.tx.try_push(slice);
+                overflowed |= n < slice.len();
+
+                // Gives up when the acquisition stops, the reader is gone or
+                // the wait times out
+                if tap.overflow == AoldaqOverflow::Block && !tap.stalled {
+                    while n < slice.len() && can_acquire.load(Ordering::Relaxed) && tap.attached.load(Ordering::Relaxed) {
+                        if Instant::now() >= deadline {
+                            log::warn!("Reader stalled for {:?}, dropping its points until it catches up", STALL_TIMEOUT);
+                            tap.stalled = true;
+                            break;
+                        }
+
+                        n += tap.tx.try_push(&slice[n..]);
+                    }
+                }
+
+                written += n;
+                if n < slice.len() {
+                    break;
+                }
+            }
+
+            let total = slices.iter().map(|slice| slice.len()).sum::<usize>();
+            if written < total {
+                tap.dropped.fetch_add((total - written) as u64, Ordering::Relaxed);
+            } else {
+                tap.stalled = false;
+            }
+
+            if overflowed {
+                tap.tx.overflowed();
+            }
+
+            if written > 0 {
+                for &(marker, offset) in marks.iter().filter(|&&(_, offset)| offset < written) {
+                    tap.tx.mark(marker, fifo_pos + offset as u64);
+                }
+
+                tap.tx.record(AoldaqBlockInfo { len: written, ..info }, fifo_pos);
+            }
+        }
+    }
+}
+
+/// Extra consumer of a channel, created with `Aoldaq::add_reader`. It has its
+/// own FIFO, so it can be read from any thread, and the channel stops feeding
+/// it once dropped.
+pub struct AoldaqReader {
+    rx: FifoConsumer,
+    attached: Arc<AtomicBool>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl AoldaqReader {
+    /// Pops as many points as available, up to `buf.len()`, into `buf`.
+    pub fn get_data_into(&mut self, buf: &mut [u32]) -> usize {
+        self.rx.pop_slice(buf)
+    }
+
+    /// Waits for at most `timeout` until `buf` can be filled, then pops as many
+    /// points as available into it.
+    pub fn get_data_into_blocking(&mut self, buf: &mut [u32], timeout: std::time::Duration) -> usize {
+        let mut time_spent = std::time::Duration::from_micros(0);
+        let wait_interval = std::time::Duration::from_millis(1);
+
+        while time_spent < timeout && buf.len() > self.rx.len() {
+            std::thread::sleep(wait_interval);
+            time_spent += wait_interval;
+        }
+
+        self.rx.pop_slice(buf)
+    }
+
+    /// Pops the points of the oldest block in the FIFO, like
+    /// `Aoldaq::get_block_into`.
+    pub fn get_block_into(&mut self, buf: &mut [u32]) -> Option<AoldaqBlockInfo> {
+        self.rx.pop_block(buf)
+    }
+
+    pub fn get_fifo_size(&self) -> usize {
+        self.rx.len()
+    }
+
+    /// Points dropped because the FIFO of the reader was full.
+    pub fn get_dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for AoldaqReader {
+    fn drop(&mut self) {
+        self.attached.store(false, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,461 @@
+//! Shared-memory rings, to let other processes see the data of a channel. The
+//! acquisition writes every point delivered to a channel into a named POSIX
+//! shared-memory object, `<name>.<channel>`, and never waits for the readers:
+//! a reader that falls behind by more than the capacity of the ring loses the
+//! oldest points. Readers map the ring read-only, so a crashing reader can't
+//! disturb the acquisition.
+//!
+//! The object starts with an `AoldaqShmHeader`, of `header_size` bytes, and
+//! is followed by `capacity` native `uint32_t` points. `written`, `reserved`
+//! and `closed` are atomics. Point `n` of the stream, counted from zero, is at
+//! index `n % capacity`. The writer:
+//!
+//! 1. stores `n + k` in `reserved` before writing `k` more points after
+//!    point `n`,
+//! 2. writes the points,
+//! 3. stores `n + k` in `written`.
+//!
+//! A reader loads `written`, copies the points from its cursor up to there,
+//! then loads `reserved`: the copied points from before `reserved - capacity`
+//! may have been overwritten while being copied, and must be discarded.
+//! `aoldaq_shm_*` in the C API implements this.
+
+use std::convert::TryFrom;
+use std::sync::atomic::{ fence, AtomicU32, AtomicU64, Ordering };
+
+use crate::AoldaqError;
+
+/// "AOLD", little endian.
+pub const SHM_MAGIC: u32 = 0x444c_4f41;
+pub const SHM_VERSION: u32 = 1;
+
+/// Default capacity of the rings, in points.
+const DEFAULT_SHM_CAPACITY: usize = 1 << 22;
+
+/// Header at the start of a shared-memory ring.
+#[repr(C)]
+pub struct AoldaqShmHeader {
+    /// `SHM_MAGIC`, written last.
+    pub magic: u32,
+    /// `SHM_VERSION`.
+    pub version: u32,
+    /// Bytes before the first point.
+    pub header_size: u32,
+    pub channel: u32,
+    /// Points in the ring.
+    pub capacity: u64,
+    /// Points written so far.
+    pub written: u64,
+    /// Points being written, from `written` up to here.
+    pub reserved: u64,
+    /// Non-zero once the acquisition is gone.
+    pub closed: u32,
+}
+
+impl AoldaqShmHeader {
+    fn written(&self) -> &AtomicU64 {
+        unsafe { &*(&self.written as *const u64 as *const AtomicU64) }
+    }
+
+    fn reserved(&self) -> &AtomicU64 {
+        unsafe { &*(&self.reserved as *const u64 as *const AtomicU64) }
+    }
+
+    fn closed(&self) -> &AtomicU32 {
+        unsafe { &*(&self.closed as *const u32 as *const AtomicU32) }
+    }
+}
+
+/// Bytes before the first point, keeping the points on their own cache line.
+const HEADER_SIZE: usize = 64;
+
+/// Bytes of a ring of `capacity` points, if it can be mapped at all.
+fn ring_len(capacity: u64) -> Option<usize> {
+    let len = capacity.checked_mul(4)?.checked_add(HEADER_SIZE as u64)?;
+    usize::try_from(len).ok()
+}
+
+/// Name and size of the rings of the channels.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShmOptions {
+    /// Name of the shared-memory objects, like `/aoldaq`. Channel `i` goes to
+    /// `<name>.<i>`.
+    pub name: String,
+    /// Points in the ring of each channel.
+    pub capacity: usize,
+}
+
+impl Default for ShmOptions {
+    fn default() -> ShmOptions {
+        ShmOptions { name: "/aoldaq".to_string(), capacity: DEFAULT_SHM_CAPACITY }
+    }
+}
+
+impl ShmOptions {
+    pub fn channel_name(&self, channel: usize) -> String {
+        format!("{}.{}", self.name, channel)
+    }
+
+    pub fn validate(&self) -> Result<(), AoldaqError> {
+        if !self.name.starts_with('/') || self.name[1..].contains('/') || self.name.len() < 2 {
+            return Err(AoldaqError::InvalidArgument(format!(
+                "invalid shared memory name {:?}, expected a single leading slash, like /aoldaq", self.name)));
+        }
+
+        if self.capacity == 0 || ring_len(self.capacity as u64).is_none() {
+            return Err(AoldaqError::InvalidArgument(format!("invalid shared memory capacity of {} points", self.capacity)));
+        }
+
+        Ok(())
+    }
+}
+
+/// Shared-memory object mapped in this process.
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// The mapping is only touched through atomics and plain copies
+unsafe impl Send for Mapping {}
+
+#[cfg(unix)]
+impl Mapping {
+    /// Maps the object `name` read-only, or creates it with `len` bytes and
+    /// maps it writable. Creating fails with `AlreadyExists` if it's there.
+    fn open(name: &str, len: Option<usize>) -> Result<Mapping, std::io::Error> {
+        use std::ffi::CString;
+        use std::io::{ Error, ErrorKind };
+
+        let error = |what: &str| {
+            let e = Error::last_os_error();
+            Error::new(e.kind(), format!("cannot {}: {}", what, e))
+        };
+
+        let c_name = CString::new(name).map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid name"))?;
+        let writable = len.is_some();
+        let flags = if writable { libc::O_RDWR | libc::O_CREAT | libc::O_EXCL } else { libc::O_RDONLY };
+
+        unsafe {
+            let fd = libc::shm_open(c_name.as_ptr(), flags, 0o644);
+            if fd < 0 {
+                return Err(error("open"));
+            }
+
+            let len = match len {
+                Some(len) => {
+                    if libc::ftruncate(fd, len as libc::off_t) != 0 {
+                        let e = error("size");
+                        libc::close(fd);
+                        libc::shm_unlink(c_name.as_ptr());
+                        return Err(e);
+                    }
+                    len
+                }
+                None => {
+                    let mut stat = std::mem::zeroed::<libc::stat>();
+                    if libc::fstat(fd, &mut stat) != 0 {
+                        let e = error("inspect");
+                        libc::close(fd);
+                        return Err(e);
+                    }
+                    stat.st_size as usize
+                }
+            };
+
+            let prot = if writable { libc::PROT_READ | libc::PROT_WRITE } else { libc::PROT_READ };
+            let ptr = libc::mmap(std::ptr::null_mut(), len, prot, libc::MAP_SHARED, fd, 0);
+            let e = error("map");
+            libc::close(fd);
+
+            if ptr == libc::MAP_FAILED {
+                if writable {
+                    libc::shm_unlink(c_name.as_ptr());
+                }
+                return Err(e);
+            }
+
+            Ok(Mapping { ptr: ptr as *mut u8, len })
+        }
+    }
+
+    fn unlink(name: &str) {
+        if let Ok(c_name) = std::ffi::CString::new(name) {
+            unsafe { libc::shm_unlink(c_name.as_ptr()) };
+        }
+    }
+}
+
+#[cfg(not(unix))]
+impl Mapping {
+    fn open(_name: &str, _len: Option<usize>) -> Result<Mapping, std::io::Error> {
+        Err(std::io::Error::from(std::io::ErrorKind::Unsupported))
+    }
+
+    fn unlink(_name: &str) {}
+}
+
+impl Mapping {
+    /// Error of `open` on `name`.
+    fn error(name: &str, e: std::io::Error) -> AoldaqError {
+        match e.kind() {
+            std::io::ErrorKind::Unsupported => AoldaqError::Unsupported("POSIX shared memory"),
+            _ => AoldaqError::InvalidArgument(format!("shared memory {}: {}", name, e)),
+        }
+    }
+
+    fn header(&self) -> &AoldaqShmHeader {
+        unsafe { &*(self.ptr as *const AoldaqShmHeader) }
+    }
+
+    fn points(&self) -> *mut u32 {
+        unsafe { self.ptr.add(HEADER_SIZE) as *mut u32 }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// Writing end of the ring of a channel, owned by its producer thread.
+pub struct ShmWriter {
+    name: String,
+    map: Mapping,
+    capacity: u64,
+}
+
+impl ShmWriter {
+    pub fn create(options: &ShmOptions, channel: usize) -> Result<ShmWriter, AoldaqError> {
+        let name = options.channel_name(channel);
+        let len = ring_len(options.capacity as u64)
+            .ok_or_else(|| AoldaqError::InvalidArgument(format!("invalid shared memory capacity of {} points", options.capacity)))?;
+
+        let map = match Mapping::open(&name, Some(len)) {
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                // Only the ring of an instance that is gone can be replaced;
+                // its readers keep their mapping
+                let old = AoldaqShmReader::open(&name)?;
+                if !old.is_closed() {
+                    return Err(AoldaqError::InvalidArgument(format!(
+                        "shared memory {} is in use by another instance, or was left by one that crashed; remove it if so", name)));
+                }
+
+                Mapping::unlink(&name);
+                Mapping::open(&name, Some(len))
+            }
+            map => map,
+        }.map_err(|e| Mapping::error(&name, e))?;
+
+        unsafe {
+            std::ptr::write(map.ptr as *mut AoldaqShmHeader, AoldaqShmHeader {
+                magic: 0,
+                version: SHM_VERSION,
+                header_size: HEADER_SIZE as u32,
+                channel: channel as u32,
+                capacity: options.capacity as u64,
+                written: 0,
+                reserved: 0,
+                closed: 0,
+            });
+        }
+
+        // Readers only accept the ring once the rest of the header is there
+        fence(Ordering::Release);
+        unsafe { std::ptr::write_volatile(map.ptr as *mut u32, SHM_MAGIC) };
+
+        Ok(ShmWriter { name, map, capacity: options.capacity as u64 })
+    }
+
+    /// Writes `points` after the last ones, overwriting the oldest.
+    pub fn write(&mut self, points: &[u32]) {
+        let header = self.map.header();
+        let written = header.written().load(Ordering::Relaxed);
+
+        // Only the last `capacity` points would survive anyway
+        let skipped = points.len().saturating_sub(self.capacity as usize);
+        let points = &points[skipped..];
+        let start = written + skipped as u64;
+        let end = start + points.len() as u64;
+
+        header.reserved().store(end, Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        let index = (start % self.capacity) as usize;
+        let first = points.len().min(self.capacity as usize - index);
+        unsafe {
+            std::ptr::copy_nonoverlapping(points.as_ptr(), self.map.points().add(index), first);
+            std::ptr::copy_nonoverlapping(points[first..].as_ptr(), self.map.points(), points.len() - first);
+        }
+
+        header.written().store(end, Ordering::Release);
+    }
+}
+
+impl Drop for ShmWriter {
+    fn drop(&mut self) {
+        // Readers attached already keep their mapping
+        self.map.header().closed().store(1, Ordering::Release);
+        Mapping::unlink(&self.name);
+    }
+}
+
+/// Reading end of a shared-memory ring, attached from any process with
+/// `AoldaqShmReader::open` or `aoldaq_shm_open`.
+pub struct AoldaqShmReader {
+    map: Mapping,
+    capacity: u64,
+    /// Next point to read.
+    cursor: u64,
+    lost: u64,
+}
+
+impl AoldaqShmReader {
+    /// Attaches to the ring named `name`, like `/aoldaq.0`. Reading starts at
+    /// the oldest point still in the ring.
+    pub fn open(name: &str) -> Result<AoldaqShmReader, AoldaqError> {
+        let map = Mapping::open(name, None).map_err(|e| Mapping::error(name, e))?;
+
+        let invalid = || AoldaqError::InvalidArgument(format!("{} is not an aoldaq ring", name));
+
+        if map.len < HEADER_SIZE {
+            return Err(invalid());
+        }
+
+        let magic = unsafe { std::ptr::read_volatile(map.ptr as *const u32) };
+        fence(Ordering::Acquire);
+        let header = map.header();
+
+        if magic != SHM_MAGIC || header.version != SHM_VERSION || header.header_size as usize != HEADER_SIZE
+            || header.capacity == 0 || ring_len(header.capacity).map_or(true, |len| map.len < len) {
+            return Err(invalid());
+        }
+
+        let capacity = header.capacity;
+        let cursor = header.written().load(Ordering::Acquire).saturating_sub(capacity);
+
+        Ok(AoldaqShmReader { map, capacity, cursor, lost: 0 })
+    }
+
+    /// Copies the next points to `buf`, as many as available and fit. Points
+    /// overwritten before being read are skipped and counted as lost.
+    pub fn read(&mut self, buf: &mut [u32]) -> usize {
+        loop {
+            let written = self.map.header().written().load(Ordering::Acquire);
+
+            if written - self.cursor > self.capacity {
+                self.skip(written - self.capacity);
+            }
+
+            let n = buf.len().min((written - self.cursor) as usize);
+            let index = (self.cursor % self.capacity) as usize;
+            let first = n.min(self.capacity as usize - index);
+
+            unsafe {
+                std::ptr::copy_nonoverlapping(self.map.points().add(index), buf.as_mut_ptr(), first);
+                std::ptr::copy_nonoverlapping(self.map.points(), buf[first..].as_mut_ptr(), n - first);
+            }
+
+            // Whatever the writer started to overwrite meanwhile is not valid
+            fence(Ordering::Acquire);
+            let reserved = self.map.header().reserved().load(Ordering::Relaxed);
+
+            if reserved > self.cursor + self.capacity {
+                self.skip(reserved - self.capacity);
+                continue;
+            }
+
+            self.cursor += n as u64;
+            return n;
+        }
+    }
+
+    fn skip(&mut self, to: u64) {
+        self.lost += to - self.cursor;
+        self.cursor = to;
+    }
+
+    /// Points waiting to be read.
+    pub fn available(&self) -> usize {
+        let written = self.map.header().written().load(Ordering::Acquire);
+        (written - self.cursor).min(self.capacity) as usize
+    }
+
+    /// Points overwritten before being read.
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+
+    /// Index of the next point to read in the stream of the ring.
+    pub fn position(&self) -> u64 {
+        self.cursor
+    }
+
+    /// Whether the acquisition that wrote the ring is gone. The points still
+    /// in the ring can be read.
+    pub fn is_closed(&self) -> bool {
+        self.map.header().closed().load(Ordering::Acquire) != 0
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shm_ring() {
+        let options = ShmOptions { name: format!("/aoldaq-test-{}", std::process::id()), capacity: 8 };
+        assert!(options.validate().is_ok());
+        assert!(ShmOptions { name: "aoldaq".to_string(), ..ShmOptions::default() }.validate().is_err());
+
+        let mut writer = ShmWriter::create(&options, 0).unwrap();
+        let mut reader = AoldaqShmReader::open(&options.channel_name(0)).unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(reader.read(&mut buf), 0);
+
+        writer.write(&[0, 1, 2, 3, 4]);
+        assert_eq!(reader.read(&mut buf[..3]), 3);
+        assert_eq!(&buf[..3], &[0, 1, 2]);
+
+        // Wraps around, overwriting 3 and 4 before they are read
+        writer.write(&(5..15).collect::<Vec<u32>>());
+        assert_eq!(reader.available(), 8);
+        assert_eq!(reader.read(&mut buf), 8);
+        assert_eq!(buf, [7, 8, 9, 10, 11, 12, 13, 14]);
+        assert_eq!((reader.lost(), reader.position()), (4, 15));
+
+        // The ring of a live instance isn't taken over
+        assert!(ShmWriter::create(&options, 0).is_err());
+        assert_eq!(reader.read(&mut buf), 0);
+
+        drop(writer);
+        assert!(reader.is_closed());
+        assert!(AoldaqShmReader::open(&options.channel_name(0)).is_err());
+
+        // Nor a foreign object, or one claiming a ring too large to map
+        let name = options.channel_name(1);
+        let stale = Mapping::open(&name, Some(ring_len(8).unwrap())).unwrap();
+        assert!(ShmWriter::create(&options, 1).is_err());
+
+        let header = |capacity| AoldaqShmHeader {
+            magic: SHM_MAGIC,
+            version: SHM_VERSION,
+            header_size: HEADER_SIZE as u32,
+            channel: 1,
+            capacity,
+            written: 0,
+            reserved: 0,
+            closed: 1,
+        };
+        unsafe { std::ptr::write(stale.ptr as *mut AoldaqShmHeader, header(u64::MAX / 2)) };
+        assert!(AoldaqShmReader::open(&name).is_err());
+
+        // But a ring left closed is replaced
+        unsafe { std::ptr::write(stale.ptr as *mut AoldaqShmHeader, header(8)) };
+        drop(ShmWriter::create(&options, 1).unwrap());
+    }
+}
@@ -0,0 +1,66 @@
+use std::sync::atomic::{ AtomicI32, AtomicU64, Ordering };
+
+/// Counters of a channel since the instance was created.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct AoldaqStats {
+    /// Points read from the device.
+    pub acquired: u64,
+    /// Points pushed to the software FIFO. Points read before the trigger or
+    /// past the end of a finite acquisition are not delivered.
+    pub delivered: u64,
+    /// Device reads.
+    pub blocks: u64,
+    /// Times the software FIFO was full and the producer had to wait for the
+    /// consumer. While it waits, the hardware buffer fills up.
+    pub overflows: u64,
+    /// Failed device operations.
+    pub errors: u64,
+    /// Status of the last failed device operation.
+    pub last_error: i32,
+    /// Points currently in the software FIFO.
+    pub fifo_fill: usize,
+}
+
+/// Live counters of a channel, updated by its producer thread.
+#[derive(Default)]
+pub struct ChannelStats {
+    acquired: AtomicU64,
+    delivered: AtomicU64,
+    blocks: AtomicU64,
+    overflows: AtomicU64,
+    errors: AtomicU64,
+    last_error: AtomicI32,
+}
+
+impl ChannelStats {
+    pub fn block(&self, n: usize) {
+        self.blocks.fetch_add(1, Ordering::Relaxed);
+        self.acquired.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn delivered(&self, n: usize) {
+        self.delivered.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn overflows(&self, overflows: u64) {
+        self.overflows.store(overflows, Ordering::Relaxed);
+    }
+
+    pub fn error(&self, status: i32) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        self.last_error.store(status, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self, fifo_fill: usize) -> AoldaqStats {
+        AoldaqStats {
+            acquired: self.acquired.load(Ordering::Relaxed),
+            delivered: self.delivered.load(Ordering::Relaxed),
+            blocks: self.blocks.load(Ordering::Relaxed),
+            overflows: self.overflows.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            last_error: self.last_error.load(Ordering::Relaxed),
+            fifo_fill,
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ Context, Poll };
+
+use futures_core::Stream;
+
+use crate::{ Aoldaq, AoldaqBlockInfo };
+
+/// Future returned by `Aoldaq::read`.
+pub struct Read<'a> {
+    aoldaq: &'a mut Aoldaq,
+    channel: usize,
+    n: usize,
+}
+
+impl Future for Read<'_> {
+    type Output = Vec<u32>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Vec<u32>> {
+        let this = self.get_mut();
+
+        if !this.aoldaq.wait_for(this.channel, this.n, cx) {
+            return Poll::Pending;
+        }
+
+        let n = this.n.min(this.aoldaq.get_fifo_size(this.channel));
+        let mut buf = vec![0; n];
+        this.aoldaq.get_data_into(this.channel, &mut buf[..]);
+
+        Poll::Ready(buf)
+    }
+}
+
+/// Stream of the blocks of a channel, returned by `Aoldaq::blocks`.
+pub struct BlockStream<'a> {
+    aoldaq: &'a mut Aoldaq,
+    channel: usize,
+    buf: Vec<u32>,
+}
+
+impl Stream for BlockStream<'_> {
+    type Item = (AoldaqBlockInfo, Vec<u32>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if !this.aoldaq.wait_for(this.channel, 1, cx) {
+            return Poll::Pending;
+        }
+
+        let mut info = this.aoldaq.get_block_into(this.channel, &mut this.buf[..]);
+
+        if info.is_none() && !this.aoldaq.is_done() {
+            // Points pushed, but their block information isn't there yet. The
+            // producer notifies once it is recorded, so wait for that
+            this.aoldaq.dispatcher.notifier().register_waker(this.channel, cx.waker());
+            info = this.aoldaq.get_block_into(this.channel, &mut this.buf[..]);
+        }
+
+        match info {
+            Some(info) => Poll::Ready(Some((info, this.buf[..info.len].to_vec()))),
+            None if this.aoldaq.is_done() => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Aoldaq {
+    /// Reads exactly `n` points of `channel`, waiting for them without
+    /// blocking the thread. If the finite acquisition finishes first, resolves
+    /// to the points that are left. As the FIFO can't hold more, `n` is capped
+    /// to its capacity.
+    pub fn read(&mut self, channel: usize, n: usize) -> Read<'_> {
+        let capacity = self.fifos.get(channel).map_or(0, |rx| rx.capacity());
+
+        Read {
+            aoldaq: self,
+            channel,
+            n: n.min(capacity),
+        }
+    }
+
+    /// Returns the blocks of `channel` as they are pushed by the producer
+    /// threads. Ends once a finite acquisition is done and its data consumed.
+    pub fn blocks(&mut self, channel: usize) -> BlockStream<'_> {
+        let buf = vec![0; self.block_size];
+
+        BlockStream {
+            aoldaq: self,
+            channel,
+            buf,
+        }
+    }
+
+    /// Returns whether `channel` has `n` points, or will never get them. If
+    /// not, registers the task to be woken by the producer thread.
+    fn wait_for(&self, channel: usize, n: usize, cx: &mut Context<'_>) -> bool {
+        let ready = || self.get_fifo_size(channel) >= n || self.is_done();
+
+        if ready() {
+            return true;
+        }
+
+        self.dispatcher.notifier().register_waker(channel, cx.waker());
+
+        // The data may have arrived before the waker was registered
+        ready()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    use super::*;
+    use crate::{ AoldaqBuilder, AoldaqMode };
+
+    struct ThreadWaker(std::thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Arc::new(ThreadWaker(std::thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => std::thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_and_blocks() {
+        let mut aoldaq = AoldaqBuilder::new(AoldaqMode::Random)
+            .block_size(100)
+            .fifo_capacity(100_000)
+            .build()
+            .unwrap();
+        aoldaq.start_finite(1000);
+
+        assert_eq!(block_on(aoldaq.read(0, 250)).len(), 250);
+
+        let mut blocks = aoldaq.blocks(0);
+        let mut total = 250;
+        while let Some((info, data)) = block_on(std::future::poll_fn(|cx| Pin::new(&mut blocks).poll_next(cx))) {
+            assert_eq!(info.len, data.len());
+            assert_eq!(info.first_point, total as u64);
+            total += data.len();
+        }
+
+        assert_eq!(total, 1000);
+        aoldaq.stop();
+    }
+
+    #[test]
+    fn test_read_past_capacity() {
+        let mut aoldaq = AoldaqBuilder::new(AoldaqMode::Counter)
+            .block_size(100)
+            .fifo_capacity(1000)
+            .build()
+            .unwrap();
+        aoldaq.start();
+
+        // Resolves with a full FIFO instead of waiting forever
+        let data = block_on(aoldaq.read(0, 5000));
+        assert_eq!(data.len(), 1000);
+        assert!(data.iter().enumerate().all(|(i, &point)| point == i as u32));
+        aoldaq.stop();
+    }
+}
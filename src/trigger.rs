@@ -0,0 +1,328 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{ AtomicBool, AtomicU64, AtomicUsize, Ordering };
+
+use crate::AoldaqError;
+use crate::callback::{ AoldaqEvent, Notifier };
+
+/// How an acquisition started with `aoldaq_start` decides where its data begins.
+/// Except with markers, every channel begins at the same word of the device
+/// FIFOs, which are assumed to be sampled together.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AoldaqTriggerMode {
+    /// Data flows to the FIFOs as soon as the acquisition is started.
+    Immediate,
+    /// Data flows after `aoldaq_trigger` is called.
+    Software,
+    /// Data flows after the device reports its hardware trigger (register or IRQ).
+    /// The trigger is polled between reads of the first device FIFO, so data
+    /// starts on the block boundary after it fired, up to a block late. Use
+    /// `pre_trigger` to keep the points before.
+    Fpga,
+    /// Each channel starts on its first sample for which `sample & mask == value`.
+    Marker,
+}
+
+impl std::str::FromStr for AoldaqTriggerMode {
+    type Err = AoldaqError;
+
+    fn from_str(s: &str) -> Result<AoldaqTriggerMode, AoldaqError> {
+        match s.to_lowercase().as_str() {
+            "immediate" => Ok(AoldaqTriggerMode::Immediate),
+            "software" => Ok(AoldaqTriggerMode::Software),
+            "fpga" => Ok(AoldaqTriggerMode::Fpga),
+            "marker" => Ok(AoldaqTriggerMode::Marker),
+            _ => Err(AoldaqError::InvalidArgument(format!("unknown trigger mode {:?}", s))),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TriggerOptions {
+    pub mode: AoldaqTriggerMode,
+    /// Amount of points per channel from before the trigger that are kept and
+    /// delivered ahead of the triggered data. Zero discards everything.
+    pub pre_trigger: usize,
+    pub marker_value: u32,
+    pub marker_mask: u32,
+}
+
+impl Default for TriggerOptions {
+    fn default() -> TriggerOptions {
+        TriggerOptions {
+            mode: AoldaqTriggerMode::Immediate,
+            pre_trigger: 0,
+            marker_value: 0,
+            marker_mask: 0,
+        }
+    }
+}
+
+/// Trigger state shared between the `Aoldaq` instance and the producer threads.
+pub struct Trigger {
+    pub mode: AoldaqTriggerMode,
+    pre_trigger: usize,
+    marker_value: u32,
+    marker_mask: u32,
+    fired: AtomicBool,
+    forced: AtomicBool,
+    /// Word of the device FIFOs at which every channel opens, once known.
+    position: AtomicU64,
+    epoch: AtomicUsize,
+    notifier: Notifier,
+}
+
+/// Value of `Trigger::position` before the first channel sees the trigger.
+const UNKNOWN: u64 = u64::MAX;
+
+impl Trigger {
+    pub fn new(options: &TriggerOptions, notifier: Notifier) -> Trigger {
+        Trigger {
+            mode: options.mode,
+            pre_trigger: options.pre_trigger,
+            marker_value: options.marker_value,
+            marker_mask: options.marker_mask,
+            fired: AtomicBool::new(options.mode == AoldaqTriggerMode::Immediate),
+            forced: AtomicBool::new(false),
+            position: AtomicU64::new(UNKNOWN),
+            epoch: AtomicUsize::new(0),
+            notifier,
+        }
+    }
+
+    /// Re-arms the trigger for a new acquisition. The producer threads notice
+    /// the new epoch and drop whatever they had in their pre-trigger windows.
+    pub fn arm(&self) {
+        self.fired.store(self.mode == AoldaqTriggerMode::Immediate, Ordering::SeqCst);
+        self.forced.store(false, Ordering::SeqCst);
+        self.position.store(UNKNOWN, Ordering::SeqCst);
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Fires the trigger for every channel. In marker mode, this opens the
+    /// channels that haven't seen their marker yet.
+    pub fn fire(&self) {
+        self.forced.store(true, Ordering::SeqCst);
+        self.mark_fired();
+    }
+
+    /// Fires the trigger from the device, opening every channel at `position`,
+    /// the word of the device FIFOs read next by the thread that polled it.
+    pub fn fire_at(&self, position: u64) {
+        let _ = self.position.compare_exchange(UNKNOWN, position, Ordering::SeqCst, Ordering::SeqCst);
+        self.mark_fired();
+    }
+
+    /// Returns where the channels open, making it `position` if not set yet.
+    fn open_at(&self, position: u64) -> u64 {
+        match self.position.compare_exchange(UNKNOWN, position, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => position,
+            Err(set) => set,
+        }
+    }
+
+    fn mark_fired(&self) {
+        if !self.fired.swap(true, Ordering::SeqCst) {
+            log::info!("Trigger fired");
+            self.notifier.event(AoldaqEvent::Triggered);
+        }
+    }
+
+    pub fn is_fired(&self) -> bool {
+        self.fired.load(Ordering::SeqCst)
+    }
+
+    fn is_marker(&self, sample: u32) -> bool {
+        sample & self.marker_mask == self.marker_value
+    }
+}
+
+/// Per-channel view of the trigger, owned by a producer thread.
+pub struct TriggerGate {
+    open: bool,
+    epoch: usize,
+    /// Device words per point of the channel, after binning.
+    factor: usize,
+    /// Points kept beyond the pre-trigger window, for channels that see the
+    /// trigger after reading past where it opens them.
+    lookback: usize,
+    window: VecDeque<u32>,
+}
+
+impl TriggerGate {
+    /// Creates the gate of a channel with `factor` device words per point,
+    /// which can lag up to `lookback` points behind the other channels.
+    pub fn new(trigger: &Trigger, factor: usize, lookback: usize) -> TriggerGate {
+        let lookback = match trigger.mode {
+            AoldaqTriggerMode::Software | AoldaqTriggerMode::Fpga => lookback,
+            AoldaqTriggerMode::Immediate | AoldaqTriggerMode::Marker => 0,
+        };
+
+        TriggerGate {
+            open: false,
+            epoch: trigger.epoch.load(Ordering::SeqCst),
+            factor: factor.max(1),
+            lookback,
+            window: VecDeque::with_capacity(trigger.pre_trigger + lookback),
+        }
+    }
+
+    /// Decides what part of `block`, read at word `position` of its device
+    /// FIFO, may go to the software FIFO. Returns the index of the first
+    /// sample to deliver, or `None` if the whole block was taken by the
+    /// pre-trigger window. When the gate opens, the window must be flushed
+    /// with `drain_window` before pushing the block.
+    ///
+    /// Except for markers, every channel opens at the same word, set by the
+    /// first channel that sees the trigger, or by the device that fired it.
+    pub fn admit(&mut self, trigger: &Trigger, block: &[u32], position: u64) -> Option<usize> {
+        let epoch = trigger.epoch.load(Ordering::SeqCst);
+        if epoch != self.epoch {
+            self.epoch = epoch;
+            self.open = false;
+            self.window.clear();
+        }
+
+        if self.open {
+            return Some(0);
+        }
+
+        if trigger.mode == AoldaqTriggerMode::Immediate {
+            self.open = true;
+            return Some(0);
+        }
+
+        // In marker mode, each channel waits for its own marker, unless forced
+        let by_marker = trigger.mode == AoldaqTriggerMode::Marker && !trigger.forced.load(Ordering::SeqCst);
+        let marker = if by_marker { block.iter().position(|&s| trigger.is_marker(s)) } else { None };
+
+        let start = match marker {
+            Some(start) => start,
+            None if !by_marker && trigger.is_fired() => {
+                let opening = trigger.open_at(position);
+
+                if opening < position {
+                    // Past the opening already, the points since are in the window
+                    let late = ((position - opening) / self.factor as u64) as usize;
+                    if late > self.window.len() {
+                        log::warn!("Opened {} points after the trigger, but only {} were kept", late, self.window.len());
+                    }
+
+                    self.trim(trigger.pre_trigger + late);
+                    self.open = true;
+                    return Some(0);
+                }
+
+                let start = (opening - position).div_ceil(self.factor as u64);
+                if start >= block.len() as u64 {
+                    self.keep(trigger.pre_trigger, block);
+                    return None;
+                }
+
+                start as usize
+            }
+            None => {
+                self.keep(trigger.pre_trigger, block);
+                return None;
+            }
+        };
+
+        self.keep(trigger.pre_trigger, &block[..start]);
+        self.trim(trigger.pre_trigger);
+        self.open = true;
+        trigger.mark_fired();
+        Some(start)
+    }
+
+    pub fn drain_window(&mut self) -> Vec<u32> {
+        self.window.drain(..).collect()
+    }
+
+    /// Adds `samples` to the window, keeping the pre-trigger and lookback points.
+    fn keep(&mut self, pre_trigger: usize, samples: &[u32]) {
+        let kept = pre_trigger + self.lookback;
+        if kept == 0 {
+            return;
+        }
+
+        let samples = &samples[samples.len().saturating_sub(kept)..];
+        self.window.extend(samples.iter());
+        self.trim(kept);
+    }
+
+    /// Drops the oldest points of the window past the latest `n`.
+    fn trim(&mut self, n: usize) {
+        let overflow = self.window.len().saturating_sub(n);
+        self.window.drain(..overflow);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::callback::Dispatcher;
+
+    #[test]
+    fn test_marker_with_pre_trigger() {
+        let options = TriggerOptions {
+            mode: AoldaqTriggerMode::Marker,
+            pre_trigger: 3,
+            marker_value: 0x8000_0000,
+            marker_mask: 0x8000_0000,
+        };
+        let dispatcher = Dispatcher::new();
+        let trigger = Trigger::new(&options, dispatcher.notifier());
+        trigger.arm();
+
+        let mut gate = TriggerGate::new(&trigger, 1, 0);
+
+        assert_eq!(gate.admit(&trigger, &[1, 2, 3, 4], 0), None);
+
+        let block = [5, 6, 0x8000_0007, 8];
+        assert_eq!(gate.admit(&trigger, &block, 4), Some(2));
+        assert_eq!(gate.drain_window(), vec![4, 5, 6]);
+        assert!(trigger.is_fired());
+
+        assert_eq!(gate.admit(&trigger, &[9, 10], 8), Some(0));
+
+        // Another channel still waits for its own marker
+        let mut other = TriggerGate::new(&trigger, 1, 0);
+        assert_eq!(other.admit(&trigger, &[1, 2], 0), None);
+        trigger.fire();
+        assert_eq!(other.admit(&trigger, &[3, 4], 2), Some(0));
+
+        trigger.arm();
+        assert_eq!(gate.admit(&trigger, &[11, 12], 10), None);
+        assert_eq!(gate.drain_window(), vec![11, 12]);
+    }
+
+    #[test]
+    fn test_aligned_opening() {
+        let options = TriggerOptions { mode: AoldaqTriggerMode::Software, pre_trigger: 1, ..TriggerOptions::default() };
+        let dispatcher = Dispatcher::new();
+        let trigger = Trigger::new(&options, dispatcher.notifier());
+        trigger.arm();
+
+        // The second channel is a block ahead when the first sees the trigger
+        let mut first = TriggerGate::new(&trigger, 1, 4);
+        let mut second = TriggerGate::new(&trigger, 1, 4);
+        assert_eq!(first.admit(&trigger, &[1, 2, 3, 4], 0), None);
+        assert_eq!(second.admit(&trigger, &[1, 2, 3, 4], 0), None);
+        assert_eq!(second.admit(&trigger, &[5, 6, 7, 8], 4), None);
+
+        trigger.fire();
+        assert_eq!(first.admit(&trigger, &[5, 6, 7, 8], 4), Some(0));
+        assert_eq!(first.drain_window(), vec![4]);
+        assert_eq!(second.admit(&trigger, &[9, 10, 11, 12], 8), Some(0));
+        assert_eq!(second.drain_window(), vec![4, 5, 6, 7, 8]);
+
+        // The device knows where it fired, even within a block, counted in
+        // device words
+        trigger.arm();
+        let mut binned = TriggerGate::new(&trigger, 2, 4);
+        assert_eq!(binned.admit(&trigger, &[1, 2], 0), None);
+        trigger.fire_at(6);
+        assert_eq!(binned.admit(&trigger, &[3, 4], 4), Some(1));
+        assert_eq!(binned.drain_window(), vec![3]);
+    }
+}
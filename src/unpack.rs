@@ -0,0 +1,148 @@
+//! Unpacking of device FIFOs that pack several channels, or flags, in each
+//! word. Every logical channel is a bit field of the words of one device FIFO,
+//! so a single DMA FIFO can feed several channels. Marker bits are taken out
+//! of the words as flags, recorded with the position of their point.
+
+use crate::AoldaqError;
+
+/// Bit field of the words of a device FIFO, delivered as a logical channel.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AoldaqField {
+    /// Device FIFO the words come from.
+    pub fifo: usize,
+    /// Lowest bit of the field.
+    pub shift: u32,
+    /// Width of the field, from 1 to 32 bits.
+    pub bits: u32,
+}
+
+impl AoldaqField {
+    /// Checks that the field lies within the 32 bits of a word.
+    pub fn validate(&self) -> Result<(), AoldaqError> {
+        if self.shift >= 32 || self.bits == 0 || self.bits > 32 - self.shift {
+            return Err(AoldaqError::InvalidArgument(format!(
+                "invalid field of {} bits from bit {}", self.bits, self.shift)));
+        }
+
+        Ok(())
+    }
+
+    pub fn extract(&self, word: u32) -> u32 {
+        let mask = if self.bits >= 32 { u32::MAX } else { (1 << self.bits) - 1 };
+        (word >> self.shift) & mask
+    }
+}
+
+impl std::str::FromStr for AoldaqField {
+    type Err = AoldaqError;
+
+    /// Parses `fifo:low-high`, like `0:16-31` for the upper half of the words
+    /// of FIFO 0, or `fifo:bit` for a single bit.
+    fn from_str(s: &str) -> Result<AoldaqField, AoldaqError> {
+        let invalid = || AoldaqError::InvalidArgument(format!("invalid field {:?}, expected fifo:low-high", s));
+
+        let mut parts = s.trim().splitn(2, ':');
+        let fifo = parts.next().ok_or_else(invalid)?.trim().parse().map_err(|_| invalid())?;
+        let bits = parts.next().ok_or_else(invalid)?;
+
+        let mut range = bits.splitn(2, '-');
+        let low: u32 = range.next().ok_or_else(invalid)?.trim().parse().map_err(|_| invalid())?;
+        let high: u32 = match range.next() {
+            Some(high) => high.trim().parse().map_err(|_| invalid())?,
+            None => low,
+        };
+
+        if high < low || high > 31 {
+            return Err(invalid());
+        }
+
+        Ok(AoldaqField { fifo, shift: low, bits: high - low + 1 })
+    }
+}
+
+/// Marker found in the points of a channel.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct AoldaqMarker {
+    /// Index of the point in the stream of the channel, like
+    /// `AoldaqBlockInfo::first_point`.
+    pub point: u64,
+    /// Marker bits set in the word of the point.
+    pub flags: u32,
+}
+
+/// How the device FIFOs are unpacked into logical channels.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UnpackOptions {
+    /// Field of each logical channel, in order.
+    pub fields: Vec<AoldaqField>,
+    /// Mask of the marker bits of each device FIFO. FIFOs past the end of the
+    /// list have no markers.
+    pub markers: Vec<u32>,
+}
+
+impl UnpackOptions {
+    /// Amount of device FIFOs read.
+    pub fn n_fifos(&self) -> usize {
+        self.fields.iter().map(|field| field.fifo + 1).max().unwrap_or(0)
+    }
+
+    pub fn validate(&self, n_channels: usize) -> Result<(), AoldaqError> {
+        if self.fields.len() != n_channels {
+            return Err(AoldaqError::InvalidArgument(format!(
+                "{} unpacked fields for {} channels", self.fields.len(), n_channels)));
+        }
+
+        for field in &self.fields {
+            field.validate()?;
+        }
+
+        // Every FIFO that is read must feed a channel
+        if let Some(fifo) = (0..self.n_fifos()).find(|&fifo| self.fields.iter().all(|field| field.fifo != fifo)) {
+            return Err(AoldaqError::InvalidArgument(format!("device FIFO {} feeds no channel", fifo)));
+        }
+
+        if self.markers.len() > self.n_fifos() {
+            return Err(AoldaqError::InvalidArgument(format!(
+                "marker masks for {} FIFOs, but only {} are read", self.markers.len(), self.n_fifos())));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fields() {
+        let low = "0:0-15".parse::<AoldaqField>().unwrap();
+        let high = "0:16-31".parse::<AoldaqField>().unwrap();
+        let flag = "1:31".parse::<AoldaqField>().unwrap();
+
+        assert_eq!(low.extract(0x1234_abcd), 0xabcd);
+        assert_eq!(high.extract(0x1234_abcd), 0x1234);
+        assert_eq!(flag, AoldaqField { fifo: 1, shift: 31, bits: 1 });
+        assert_eq!("0:0-31".parse::<AoldaqField>().unwrap().extract(u32::MAX), u32::MAX);
+
+        assert!("0:16-32".parse::<AoldaqField>().is_err());
+        assert!("0:8-4".parse::<AoldaqField>().is_err());
+        assert!("16-31".parse::<AoldaqField>().is_err());
+
+        let options = UnpackOptions { fields: vec![low, high, flag], markers: vec![] };
+        assert_eq!(options.n_fifos(), 2);
+        assert!(options.validate(3).is_ok());
+        assert!(options.validate(2).is_err());
+
+        let options = UnpackOptions { fields: vec![flag], markers: vec![] };
+        assert!(options.validate(1).is_err());
+
+        // Fields given as structs, like from C, are checked too
+        for field in [AoldaqField { shift: 32, ..low }, AoldaqField { bits: 0, ..low }, AoldaqField { shift: 16, bits: 17, ..low }] {
+            let options = UnpackOptions { fields: vec![field], markers: vec![] };
+            assert!(options.validate(1).is_err());
+        }
+    }
+}
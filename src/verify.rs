@@ -0,0 +1,155 @@
+//! Checks of the data of `AoldaqMode::Counter`, or of any other source that
+//! counts up by one on every point, like a counter in the FPGA.
+
+use std::collections::VecDeque;
+
+/// Ranges of missing points remembered, to take out the ones that turn up late.
+const MAX_HOLES: usize = 1024;
+
+/// What `CounterVerifier` found in the points it checked.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct AoldaqVerifyReport {
+    /// Points checked.
+    pub checked: u64,
+    /// Times the count jumped forward.
+    pub gaps: u64,
+    /// Points skipped by the forward jumps, less the ones that turned up late.
+    pub missing: u64,
+    /// Points that came behind the highest one so far, late or repeated.
+    pub reorders: u64,
+    /// Whether a gap or reordering was found, in which case the `first_` fields
+    /// describe the first one.
+    pub has_gap: bool,
+    /// Index, among the checked points, of the first unexpected point.
+    pub first_gap: u64,
+    /// Value expected at `first_gap`.
+    pub first_gap_expected: u32,
+    /// Value found at `first_gap`.
+    pub first_gap_found: u32,
+}
+
+impl AoldaqVerifyReport {
+    /// Whether the points came in order, without any missing.
+    pub fn is_ok(&self) -> bool {
+        !self.has_gap
+    }
+}
+
+/// Checks that a stream of points counts up by one, wrapping around at
+/// `u32::MAX`. The stream may be fed in chunks of any size. The first point
+/// sets where the count starts.
+#[derive(Clone, Debug, Default)]
+pub struct CounterVerifier {
+    /// One past the highest point so far.
+    next: Option<u32>,
+    /// Points skipped and not seen since, as `start..end` ranges, oldest first.
+    holes: VecDeque<(u32, u32)>,
+    report: AoldaqVerifyReport,
+}
+
+impl CounterVerifier {
+    pub fn new() -> CounterVerifier {
+        CounterVerifier::default()
+    }
+
+    /// Checks the next points of the stream.
+    pub fn check(&mut self, data: &[u32]) {
+        for &point in data {
+            if let Some(expected) = self.next {
+                if point != expected {
+                    // Jumps of less than half the range forward are gaps
+                    let jump = point.wrapping_sub(expected) as i32;
+                    if jump > 0 {
+                        self.report.gaps += 1;
+                        self.report.missing += jump as u64;
+
+                        if self.holes.len() == MAX_HOLES {
+                            self.holes.pop_front();
+                        }
+                        self.holes.push_back((expected, point));
+                    } else {
+                        self.report.reorders += 1;
+                        self.fill(point);
+                    }
+
+                    if !self.report.has_gap {
+                        self.report.has_gap = true;
+                        self.report.first_gap = self.report.checked;
+                        self.report.first_gap_expected = expected;
+                        self.report.first_gap_found = point;
+                    }
+                }
+            }
+
+            // Late points don't move the count back
+            if self.next.map_or(true, |expected| point.wrapping_sub(expected) as i32 >= 0) {
+                self.next = Some(point.wrapping_add(1));
+            }
+            self.report.checked += 1;
+        }
+    }
+
+    /// Takes a late point out of the missing ones, if it was. Repeats aren't.
+    fn fill(&mut self, point: u32) {
+        let hole = self.holes.iter().position(|&(start, end)| point.wrapping_sub(start) < end.wrapping_sub(start));
+
+        if let Some(i) = hole {
+            let (start, end) = self.holes[i];
+            self.report.missing -= 1;
+
+            match (point == start, point.wrapping_add(1) == end) {
+                (true, true) => { self.holes.remove(i); },
+                (true, false) => self.holes[i].0 = point.wrapping_add(1),
+                (false, true) => self.holes[i].1 = point,
+                (false, false) => {
+                    self.holes[i].1 = point;
+                    self.holes.insert(i + 1, (point.wrapping_add(1), end));
+                },
+            }
+        }
+    }
+
+    pub fn report(&self) -> AoldaqVerifyReport {
+        self.report
+    }
+
+    /// Forgets everything checked so far, so the next point starts a new count.
+    pub fn reset(&mut self) {
+        *self = CounterVerifier::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CounterVerifier;
+
+    #[test]
+    fn test_verifier() {
+        let mut verifier = CounterVerifier::new();
+        verifier.check(&[u32::MAX - 1, u32::MAX]);
+        verifier.check(&[0, 1, 2]);
+        assert!(verifier.report().is_ok());
+        assert_eq!(verifier.report().checked, 5);
+
+        // 3 and 4 missing, then 6 and 7 swapped
+        verifier.check(&[5, 7, 6, 8]);
+        let report = verifier.report();
+        assert_eq!(report.gaps, 2);
+        assert_eq!(report.missing, 2);
+        assert_eq!(report.reorders, 1);
+        assert!(report.has_gap);
+        assert_eq!(report.first_gap, 5);
+        assert_eq!(report.first_gap_expected, 3);
+        assert_eq!(report.first_gap_found, 5);
+
+        // 4 turns up late, then 6 again
+        verifier.check(&[4, 6, 9]);
+        let report = verifier.report();
+        assert_eq!((report.gaps, report.missing, report.reorders), (2, 1, 3));
+
+        verifier.reset();
+        verifier.check(&[100, 101]);
+        assert!(verifier.report().is_ok());
+    }
+}
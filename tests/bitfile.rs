@@ -0,0 +1,41 @@
+//! Tests the bitfile reader of the `aoldaq` tool, which can't have unit tests
+//! of its own, see `Cargo.toml`.
+
+#[path = "../src/bin/aoldaq/bitfile.rs"]
+#[allow(dead_code)]
+mod bitfile;
+
+use bitfile::Bitfile;
+
+#[test]
+fn test_parse() {
+    let xml = r#"
+        <Bitfile>
+          <VI><RegisterList>
+            <Register><Name>Count</Name><Hidden>false</Hidden><Indicator>true</Indicator>
+              <Datatype><U32/></Datatype><Offset>98308</Offset></Register>
+            <Register><Name>Stop</Name><Hidden>false</Hidden><Indicator>false</Indicator>
+              <Datatype><Boolean></Boolean></Datatype><Offset>98314</Offset></Register>
+            <Register><Name>Internal</Name><Hidden>true</Hidden><Offset>1</Offset></Register>
+          </RegisterList></VI>
+          <DmaChannelAllocationList>
+            <Channel Name="PMT A"><DataType><SubType>U32</SubType></DataType>
+              <Direction>TargetToHost</Direction><Number>1</Number></Channel>
+          </DmaChannelAllocationList>
+        </Bitfile>"#;
+
+    let bitfile = Bitfile::parse(xml);
+
+    assert_eq!(bitfile.fifos.len(), 1);
+    assert_eq!(bitfile.fifos[0].name, "PMT A");
+    assert_eq!(bitfile.fifos[0].number, 1);
+    assert_eq!(bitfile.fifos[0].direction, "TargetToHost");
+    assert_eq!(bitfile.fifos[0].datatype, "U32");
+
+    assert_eq!(bitfile.registers.len(), 2);
+    assert_eq!(bitfile.registers[0].name, "Count");
+    assert_eq!(bitfile.registers[0].offset, 98308);
+    assert!(bitfile.registers[0].indicator);
+    assert_eq!(bitfile.registers[0].datatype, "U32");
+    assert_eq!(bitfile.registers[1].datatype, "Boolean");
+}
@@ -0,0 +1,953 @@
+//! Lifecycle and data integrity tests, acquiring in counter mode so every
+//! point says where it belongs in the stream of its channel.
+
+use std::any::Any;
+use std::sync::atomic::{ AtomicU32, AtomicUsize, Ordering };
+use std::time::{ Duration, Instant };
+
+use aoldaq::{ Aoldaq, AoldaqBuilder, AoldaqMode, CounterDevice, Device };
+
+const BLOCK_SIZE: usize = 1000;
+
+/// Creates an instance in counter mode, where the point at stream position
+/// `n` of a channel is `n`.
+fn counting(n_channels: usize, fifo_capacity: usize) -> Aoldaq {
+    AoldaqBuilder::new(AoldaqMode::Counter)
+        .channels(n_channels)
+        .block_size(BLOCK_SIZE)
+        .fifo_capacity(fifo_capacity)
+        .build()
+        .unwrap()
+}
+
+/// Reads every block waiting in `channel`, checking each point against the
+/// stream position reported for its block, and that blocks only go forward.
+/// Returns the amount of points read.
+fn check_blocks(aoldaq: &mut Aoldaq, channel: usize, next: &mut u64) -> usize {
+    let mut buf = vec![0u32; BLOCK_SIZE];
+    let mut read = 0;
+
+    while let Some(info) = aoldaq.get_block_into(channel, &mut buf[..]) {
+        assert_ne!(info.sequence, u64::MAX, "Lost the information of a block");
+        assert!(info.first_point >= *next, "Block at {} after {}", info.first_point, next);
+
+        for (i, &point) in buf[..info.len].iter().enumerate() {
+            assert_eq!(point as u64, info.first_point + i as u64);
+        }
+
+        *next = info.first_point + info.len as u64;
+        read += info.len;
+    }
+
+    read
+}
+
+#[test]
+fn test_create_destroy() {
+    for _ in 0..10 {
+        drop(counting(2, 1 << 16));
+    }
+
+    for _ in 0..10 {
+        let aoldaq = counting(2, 1 << 16);
+        aoldaq.start();
+        std::thread::sleep(Duration::from_millis(5));
+        aoldaq.stop();
+    }
+
+    // Dropped while acquiring, with full FIFOs
+    for _ in 0..10 {
+        let aoldaq = counting(2, BLOCK_SIZE);
+        aoldaq.start();
+        std::thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[test]
+fn test_callbacks() {
+    use aoldaq::AoldaqEvent;
+
+    const N: usize = 10 * BLOCK_SIZE;
+
+    let aoldaq = counting(2, N);
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    for channel in 0..2 {
+        let tx = tx.clone();
+        aoldaq.register_callback(channel, 5 * BLOCK_SIZE, move |channel, event, value| {
+            tx.send((channel, event, value)).unwrap();
+        });
+    }
+
+    aoldaq.start_finite(N);
+    assert!(aoldaq.wait_done(Duration::from_secs(10)));
+
+    // Nothing is read, so each FIFO crosses the threshold once
+    let mut events = Vec::new();
+    while events.iter().filter(|&&(_, event, _)| event == AoldaqEvent::Done).count() < 2 {
+        events.push(rx.recv_timeout(Duration::from_secs(1)).expect("Missing events"));
+    }
+
+    for channel in 0..2 {
+        let ready = events.iter()
+            .filter(|&&(c, event, _)| c == channel && event == AoldaqEvent::DataReady)
+            .collect::<Vec<_>>();
+
+        assert_eq!(ready.len(), 1, "{:?}", events);
+        assert!(ready[0].2 >= 5 * BLOCK_SIZE as i64);
+    }
+
+    assert_eq!(events.iter().filter(|&&(_, event, _)| event == AoldaqEvent::Started).count(), 2);
+}
+
+#[test]
+fn test_repeated_start_stop() {
+    let mut aoldaq = counting(2, 1 << 16);
+    let mut next = [0u64; 2];
+    let mut read = [0usize; 2];
+
+    for _ in 0..20 {
+        aoldaq.start();
+        std::thread::sleep(Duration::from_millis(5));
+        aoldaq.stop();
+
+        for channel in 0..2 {
+            read[channel] += check_blocks(&mut aoldaq, channel, &mut next[channel]);
+        }
+    }
+
+    assert!(read.iter().all(|&n| n > 0), "Read {:?}", read);
+
+    // Nothing comes in while stopped
+    std::thread::sleep(Duration::from_millis(20));
+    assert_eq!(aoldaq.get_fifo_size(0), 0);
+    assert_eq!(aoldaq.get_fifo_size(1), 0);
+}
+
+#[test]
+fn test_no_loss_or_reorder() {
+    const N: usize = 200_000;
+
+    // Read while acquiring, in sizes that don't line up with the blocks
+    let mut aoldaq = counting(3, N);
+    let mut data = (0..3).map(|_| Vec::with_capacity(N)).collect::<Vec<_>>();
+    let mut buf = vec![0u32; 777];
+
+    aoldaq.start_finite(N);
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while data.iter().any(|d| d.len() < N) {
+        assert!(Instant::now() < deadline, "Timed out with {:?} points", data.iter().map(|d| d.len()).collect::<Vec<_>>());
+
+        for (channel, data) in data.iter_mut().enumerate() {
+            let n = aoldaq.get_data_into_blocking(channel, &mut buf[..], Duration::from_millis(1));
+            data.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    assert!(aoldaq.wait_done(Duration::from_secs(1)));
+
+    for (channel, data) in data.iter().enumerate() {
+        assert_eq!(data.len(), N);
+        assert!(data.iter().enumerate().all(|(i, &point)| point == i as u32), "Channel {} out of order", channel);
+
+        let stats = aoldaq.get_stats(channel);
+        assert_eq!(stats.delivered, N as u64);
+        assert_eq!(stats.overflows, 0);
+        assert_eq!(stats.errors, 0);
+    }
+
+    // A finite acquisition delivers exactly N points
+    std::thread::sleep(Duration::from_millis(20));
+    assert_eq!(aoldaq.get_fifo_size(0), 0);
+}
+
+/// Counts like `CounterDevice`, but fills only part of the buffer, or none of
+/// it, on most reads.
+struct ShortDevice {
+    counter: AtomicU32,
+    reads: AtomicUsize,
+}
+
+impl Device for ShortDevice {
+    fn read_into(&self, _channel: usize, buf: &mut [u32]) -> Result<usize, i32> {
+        let n = [buf.len(), 300, 0, 701][self.reads.fetch_add(1, Ordering::SeqCst) % 4];
+        let start = self.counter.fetch_add(n as u32, Ordering::SeqCst);
+
+        for (i, point) in buf[..n].iter_mut().enumerate() {
+            *point = start + i as u32;
+        }
+
+        std::thread::sleep(Duration::from_micros(50));
+        Ok(n)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[test]
+fn test_short_reads() {
+    const N: usize = 20_000;
+
+    let mut aoldaq = AoldaqBuilder::new(AoldaqMode::Custom)
+        .block_size(BLOCK_SIZE)
+        .fifo_capacity(1 << 16)
+        .preview(BLOCK_SIZE)
+        .device(Box::new(ShortDevice { counter: AtomicU32::new(0), reads: AtomicUsize::new(0) }))
+        .build()
+        .unwrap();
+
+    aoldaq.start_finite(N);
+    assert!(aoldaq.wait_done(Duration::from_secs(10)));
+
+    // Nothing past the points read reaches the channel
+    let mut next = 0;
+    assert_eq!(check_blocks(&mut aoldaq, 0, &mut next), N);
+    assert_eq!(next, N as u64);
+    assert_eq!(aoldaq.get_stats(0).delivered, N as u64);
+
+    let mut preview = vec![0u32; BLOCK_SIZE];
+    let info = aoldaq.get_preview_into(0, &mut preview);
+    assert!(info.len > 0);
+    assert!(preview[..info.len].iter().enumerate().all(|(i, &point)| point as u64 == info.first_point + i as u64));
+}
+
+/// Counts on two device FIFOs, the second one a block ahead of the first, and
+/// fires its hardware trigger once the first one reached `at`.
+struct TriggeredDevice {
+    counters: [AtomicU32; 2],
+    at: u32,
+}
+
+impl Device for TriggeredDevice {
+    fn read_into(&self, channel: usize, buf: &mut [u32]) -> Result<usize, i32> {
+        let start = self.counters[channel].fetch_add(buf.len() as u32, Ordering::SeqCst);
+
+        for (i, point) in buf.iter_mut().enumerate() {
+            *point = start + i as u32;
+        }
+
+        if channel == 0 || start > 0 {
+            std::thread::sleep(Duration::from_micros(500));
+        }
+
+        Ok(buf.len())
+    }
+
+    fn poll_trigger(&self) -> Result<bool, i32> {
+        Ok(self.counters[0].load(Ordering::SeqCst) >= self.at)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[test]
+fn test_fpga_trigger_alignment() {
+    use aoldaq::AoldaqTriggerMode;
+
+    let mut aoldaq = AoldaqBuilder::new(AoldaqMode::Custom)
+        .channels(2)
+        .block_size(BLOCK_SIZE)
+        .fifo_capacity(1 << 16)
+        .trigger(AoldaqTriggerMode::Fpga)
+        .pre_trigger(100)
+        .device(Box::new(TriggeredDevice { counters: [AtomicU32::new(0), AtomicU32::new(0)], at: 5 * BLOCK_SIZE as u32 }))
+        .build()
+        .unwrap();
+
+    aoldaq.start_finite(BLOCK_SIZE);
+    assert!(aoldaq.wait_done(Duration::from_secs(10)));
+
+    // Both channels start at the same sample, whichever block they were reading
+    let mut first = [[0u32; BLOCK_SIZE]; 2];
+    for (channel, first) in first.iter_mut().enumerate() {
+        assert_eq!(aoldaq.get_data_into(channel, &mut first[..]), BLOCK_SIZE);
+        assert!(first.iter().enumerate().all(|(i, &point)| point == first[0] + i as u32));
+    }
+
+    assert_eq!(first[0][0], first[1][0]);
+    assert!(first[0][100] >= 5 * BLOCK_SIZE as u32);
+}
+
+#[test]
+fn test_back_to_back_finite() {
+    const N: usize = 5000;
+
+    let mut aoldaq = counting(2, 1 << 16);
+    let mut next = [0u64; 2];
+
+    for run in 1..=2 {
+        aoldaq.start_finite(N);
+        assert!(aoldaq.wait_done(Duration::from_secs(10)));
+
+        for (channel, next) in next.iter_mut().enumerate() {
+            assert_eq!(check_blocks(&mut aoldaq, channel, next), N);
+        }
+
+        // The device idles until the next acquisition, which flushes it first
+        std::thread::sleep(Duration::from_millis(30));
+        let device = aoldaq.device().as_any().downcast_ref::<CounterDevice>().unwrap();
+        assert_eq!(device.flushes(), 2 * (run - 1));
+    }
+}
+
+#[test]
+fn test_flush_during_acquisition() {
+    let mut aoldaq = counting(2, 1 << 16);
+    let mut next = [0u64; 2];
+
+    aoldaq.start();
+
+    for _ in 0..5 {
+        std::thread::sleep(Duration::from_millis(5));
+        aoldaq.flush_fifo(0);
+
+        // The flushed points are gone, but what comes after is still in order
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut read = [0; 2];
+        while read.contains(&0) {
+            assert!(Instant::now() < deadline, "No data after the flush");
+            std::thread::sleep(Duration::from_millis(1));
+
+            for (channel, read) in read.iter_mut().enumerate() {
+                *read += check_blocks(&mut aoldaq, channel, &mut next[channel]);
+            }
+        }
+    }
+
+    aoldaq.stop();
+
+    let device = aoldaq.device().as_any().downcast_ref::<CounterDevice>().unwrap();
+    assert_eq!(device.flushes(), 5);
+
+    // Flushing while stopped doesn't start the acquisition
+    check_blocks(&mut aoldaq, 0, &mut next[0]);
+    aoldaq.flush_fifo(0);
+    std::thread::sleep(Duration::from_millis(20));
+    assert_eq!(aoldaq.get_fifo_size(0), 0);
+}
+
+#[test]
+fn test_blocking_read_timeout() {
+    let mut aoldaq = counting(2, 1 << 16);
+    let mut buf = vec![0u32; 2 * BLOCK_SIZE];
+
+    // Not started, so the reads time out empty handed
+    let start = Instant::now();
+    assert_eq!(aoldaq.get_data_into_blocking(0, &mut buf[..], Duration::from_millis(50)), 0);
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    let (a, b) = buf.split_at_mut(BLOCK_SIZE);
+    let start = Instant::now();
+    assert_eq!(aoldaq.get_data_all(&mut [a, b], BLOCK_SIZE, Duration::from_millis(50)), 0);
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    aoldaq.start();
+
+    assert_eq!(aoldaq.get_data_into_blocking(0, &mut buf[..], Duration::from_secs(5)), buf.len());
+    assert!(buf.iter().enumerate().all(|(i, &point)| point == i as u32));
+
+    let (a, b) = buf.split_at_mut(BLOCK_SIZE);
+    assert_eq!(aoldaq.get_data_all(&mut [a, b], BLOCK_SIZE, Duration::from_secs(5)), BLOCK_SIZE);
+    assert_eq!(buf[0], 2 * BLOCK_SIZE as u32);
+    assert_eq!(buf[BLOCK_SIZE], 0);
+
+    // A timed out get_data_all consumes nothing
+    aoldaq.stop();
+    let left = [aoldaq.get_fifo_size(0), aoldaq.get_fifo_size(1)];
+    let n = left[0].max(left[1]) + 1;
+    let (mut a, mut b) = (vec![0u32; n], vec![0u32; n]);
+    assert_eq!(aoldaq.get_data_all(&mut [&mut a[..], &mut b[..]], n, Duration::from_millis(10)), 0);
+    assert_eq!([aoldaq.get_fifo_size(0), aoldaq.get_fifo_size(1)], left);
+
+    // So do reads into buffers that are too short
+    assert_eq!(aoldaq.get_data_all(&mut [&mut a[..]], 1, Duration::from_millis(10)), 0);
+    assert_eq!(aoldaq.get_data_all(&mut [&mut a[..1], &mut b[..]], 2, Duration::from_millis(10)), 0);
+    assert_eq!(aoldaq.get_data_all_interleaved(&mut a[..3], 2, Duration::from_millis(10)), 0);
+    assert_eq!(aoldaq.get_data_all_interleaved(&mut a[..], usize::MAX, Duration::from_millis(10)), 0);
+    assert_eq!([aoldaq.get_fifo_size(0), aoldaq.get_fifo_size(1)], left);
+}
+
+#[test]
+fn test_counter_mode() {
+    use aoldaq::CounterVerifier;
+
+    const N: usize = 100_000;
+
+    let mut aoldaq = AoldaqBuilder::new(AoldaqMode::Counter)
+        .channels(2)
+        .block_size(BLOCK_SIZE)
+        .fifo_capacity(N)
+        .build()
+        .unwrap();
+
+    aoldaq.start_finite(N);
+    assert!(aoldaq.wait_done(Duration::from_secs(10)));
+
+    let mut buf = vec![0u32; N];
+    for channel in 0..2 {
+        let mut verifier = CounterVerifier::new();
+        let n = aoldaq.get_data_into(channel, &mut buf[..]);
+        verifier.check(&buf[..n]);
+
+        let report = verifier.report();
+        assert!(report.is_ok(), "{:?}", report);
+        assert_eq!(report.checked, N as u64);
+        assert_eq!(buf[0], 0);
+    }
+}
+
+#[test]
+fn test_binning() {
+    const N: usize = 10_000;
+
+    let mut aoldaq = AoldaqBuilder::new(AoldaqMode::Counter)
+        .channels(2)
+        .block_size(BLOCK_SIZE)
+        .fifo_capacity(N)
+        .set("binning", "decimate:4, sum:10")
+        .unwrap()
+        .build()
+        .unwrap();
+
+    // Finite acquisitions count pixels
+    aoldaq.start_finite(N);
+    assert!(aoldaq.wait_done(Duration::from_secs(10)));
+
+    let mut buf = vec![0u32; N];
+    assert_eq!(aoldaq.get_data_into(0, &mut buf[..]), N);
+    assert!(buf.iter().enumerate().all(|(i, &pixel)| pixel == 4 * i as u32));
+
+    let info = aoldaq.get_block_into(1, &mut buf[..]).unwrap();
+    assert_eq!((info.first_point, info.len), (0, BLOCK_SIZE / 10));
+    assert_eq!(buf[1], (10..20).sum::<u32>());
+
+    assert!(AoldaqBuilder::new(AoldaqMode::Counter).set("binning", "mean:3").unwrap().build().is_err());
+}
+
+#[test]
+fn test_histogram() {
+    use aoldaq::AoldaqHistogram;
+
+    const N: usize = 10_000;
+
+    let aoldaq = AoldaqBuilder::new(AoldaqMode::Counter)
+        .block_size(BLOCK_SIZE)
+        .fifo_capacity(N)
+        .histogram(AoldaqHistogram { bins: 10, min: 0, max: N as u64, window: 2 * BLOCK_SIZE, saturation: 9500 })
+        .build()
+        .unwrap();
+
+    aoldaq.start_finite(N);
+    assert!(aoldaq.wait_done(Duration::from_secs(10)));
+
+    // Only the last two blocks are in the window, and the FIFO is untouched
+    let mut bins = [0u64; 10];
+    let stats = aoldaq.get_histogram_into(0, &mut bins).unwrap();
+    assert_eq!(bins, [0, 0, 0, 0, 0, 0, 0, 0, 1000, 1000]);
+    assert_eq!((stats.points, stats.min, stats.max, stats.saturated), (2000, 8000, 9999, 500));
+    assert_eq!(stats.mean, 8999.5);
+    assert_eq!(aoldaq.get_fifo_size(0), N);
+}
+
+#[test]
+fn test_preview() {
+    use aoldaq::{ AoldaqPreview, FrameOptions };
+
+    let mut aoldaq = AoldaqBuilder::new(AoldaqMode::Counter)
+        .block_size(BLOCK_SIZE)
+        .fifo_capacity(1 << 16)
+        .preview(1500)
+        .frame(FrameOptions { width: 25, height: 20, ..FrameOptions::default() })
+        .build()
+        .unwrap();
+
+    aoldaq.start_finite(4500);
+    assert!(aoldaq.wait_done(Duration::from_secs(10)));
+
+    // The last block is read whole, even if only half of it is delivered
+    let mut buf = vec![0u32; 2000];
+    assert_eq!(aoldaq.get_preview_into(0, &mut buf), AoldaqPreview { first_point: 3500, len: 1500 });
+    assert!(buf[..1500].iter().enumerate().all(|(i, &point)| point == 3500 + i as u32));
+
+    // Frames of 500 points, the last of which ended at point 5000
+    assert_eq!(aoldaq.get_preview_frame_into(0, &mut buf), Some(9));
+    assert_eq!(buf[0], 4500);
+
+    // The consumer still gets everything
+    assert_eq!(aoldaq.get_fifo_size(0), 4500);
+    assert_eq!(aoldaq.get_frame_into(0, &mut buf, Duration::from_secs(1)).unwrap().number, 0);
+    assert_eq!(buf[0], 0);
+}
+
+#[test]
+fn test_readers() {
+    use aoldaq::AoldaqOverflow;
+
+    const N: usize = 20_000;
+
+    let mut aoldaq = counting(1, N);
+    let mut recorder = aoldaq.add_reader(0, N, AoldaqOverflow::Block).unwrap();
+    let mut display = aoldaq.add_reader(0, 2 * BLOCK_SIZE, AoldaqOverflow::Drop).unwrap();
+    let gone = aoldaq.add_reader(0, BLOCK_SIZE, AoldaqOverflow::Block).unwrap();
+    assert!(aoldaq.add_reader(1, N, AoldaqOverflow::Drop).is_none());
+
+    // A blocking reader that goes away doesn't stall the others
+    drop(gone);
+
+    aoldaq.start_finite(N);
+    assert!(aoldaq.wait_done(Duration::from_secs(10)));
+
+    // The channel and the recorder both get everything
+    let mut next = 0;
+    assert_eq!(check_blocks(&mut aoldaq, 0, &mut next), N);
+
+    let mut buf = vec![0u32; N];
+    assert_eq!(recorder.get_data_into_blocking(&mut buf, Duration::from_secs(1)), N);
+    assert!(buf.iter().enumerate().all(|(i, &point)| point == i as u32));
+
+    // The display, never read, kept the first two blocks and dropped the rest
+    assert_eq!(display.get_dropped(), (N - 2 * BLOCK_SIZE) as u64);
+    let info = display.get_block_into(&mut buf).unwrap();
+    assert_eq!((info.first_point, info.len), (0, BLOCK_SIZE));
+    assert_eq!(display.get_data_into(&mut buf), BLOCK_SIZE);
+    assert_eq!(buf[0], BLOCK_SIZE as u32);
+    // Readers can be added while the producer waits on a stalled one
+    let aoldaq = counting(1, N);
+    let stalled = aoldaq.add_reader(0, BLOCK_SIZE, AoldaqOverflow::Block).unwrap();
+    aoldaq.start();
+    std::thread::sleep(Duration::from_millis(20));
+
+    let start = Instant::now();
+    let late = aoldaq.add_reader(0, N, AoldaqOverflow::Drop).unwrap();
+    assert!(start.elapsed() < Duration::from_millis(100));
+
+    drop(stalled);
+    std::thread::sleep(Duration::from_millis(20));
+    aoldaq.stop();
+    assert!(late.get_fifo_size() > 0);
+}
+
+#[test]
+fn test_stalled_reader() {
+    use aoldaq::{ AoldaqOverflow, STALL_TIMEOUT };
+
+    const N: usize = 20_000;
+
+    // A blocking reader that is never read only stalls the acquisition once
+    let mut aoldaq = counting(1, N);
+    let stuck = aoldaq.add_reader(0, 2 * BLOCK_SIZE, AoldaqOverflow::Block).unwrap();
+
+    let start = Instant::now();
+    aoldaq.start_finite(N);
+    assert!(aoldaq.wait_done(Duration::from_secs(1)));
+    assert!(start.elapsed() >= STALL_TIMEOUT);
+
+    let mut next = 0;
+    assert_eq!(check_blocks(&mut aoldaq, 0, &mut next), N);
+    assert_eq!(stuck.get_fifo_size(), 2 * BLOCK_SIZE);
+    assert_eq!(stuck.get_dropped(), (N - 2 * BLOCK_SIZE) as u64);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_shm() {
+    use aoldaq::{ AoldaqShmReader, ShmOptions };
+
+    const N: usize = 20_000;
+
+    let name = format!("/aoldaq-test-{}", std::process::id());
+    let mut aoldaq = AoldaqBuilder::new(AoldaqMode::Counter)
+        .channels(2)
+        .block_size(BLOCK_SIZE)
+        .fifo_capacity(N)
+        .shm(ShmOptions { name: name.clone(), capacity: N / 2 })
+        .build()
+        .unwrap();
+
+    let mut early = AoldaqShmReader::open(&format!("{}.1", name)).unwrap();
+    aoldaq.start_finite(N);
+    assert!(aoldaq.wait_done(Duration::from_secs(10)));
+
+    // A reader attached after the acquisition starts from the oldest point left
+    let mut late = AoldaqShmReader::open(&format!("{}.1", name)).unwrap();
+    let mut buf = vec![0u32; N];
+    assert_eq!(late.read(&mut buf), N / 2);
+    assert!(buf[..N / 2].iter().enumerate().all(|(i, &point)| point == (N / 2 + i) as u32));
+
+    // One that didn't keep up lost the points overwritten
+    assert_eq!(early.read(&mut buf), N / 2);
+    assert_eq!(early.lost(), (N / 2) as u64);
+    assert_eq!(early.position(), N as u64);
+    assert!(!early.is_closed());
+
+    // The channel itself still gets everything
+    let mut next = 0;
+    assert_eq!(check_blocks(&mut aoldaq, 1, &mut next), N);
+
+    drop(aoldaq);
+    assert!(early.is_closed());
+    assert!(AoldaqShmReader::open(&format!("{}.0", name)).is_err());
+}
+
+/// Packs two counts in each word, like two 16 bit PMT channels, with a flag
+/// on the top bit of the lower half every 100 words.
+struct PackedDevice {
+    counter: AtomicU32,
+}
+
+impl Device for PackedDevice {
+    fn read_into(&self, _channel: usize, buf: &mut [u32]) -> Result<usize, i32> {
+        let start = self.counter.fetch_add(buf.len() as u32, Ordering::SeqCst);
+
+        for (i, word) in buf.iter_mut().enumerate() {
+            let n = start + i as u32;
+            let flag = if n % 100 == 0 { 0x8000 } else { 0 };
+            *word = (n & 0x7fff) | flag | (((3 * n) & 0xffff) << 16);
+        }
+
+        std::thread::sleep(Duration::from_micros(50));
+        Ok(buf.len())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[test]
+fn test_unpacking() {
+    use aoldaq::{ AoldaqEvent, AoldaqMarker };
+
+    const N: usize = 10_000;
+
+    let mut aoldaq = AoldaqBuilder::new(AoldaqMode::Custom)
+        .channels(2)
+        .block_size(BLOCK_SIZE)
+        .fifo_capacity(N)
+        .set("unpack.fields", "0:0-14, 0:16-31").unwrap()
+        .set("unpack.markers", "0x8000").unwrap()
+        .device(Box::new(PackedDevice { counter: AtomicU32::new(0) }))
+        .build()
+        .unwrap();
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    for channel in 0..2 {
+        let tx = tx.clone();
+        aoldaq.register_callback(channel, N, move |_, event, _| {
+            tx.send((channel, event)).unwrap();
+        });
+    }
+
+    aoldaq.start_finite(N);
+    assert!(aoldaq.wait_done(Duration::from_secs(10)));
+
+    // Both channels come from the marked FIFO, but each callback only gets
+    // the markers of its own channel
+    let mut events = Vec::new();
+    while events.iter().filter(|&&(_, event)| event == AoldaqEvent::Done).count() < 2 {
+        events.push(rx.recv_timeout(Duration::from_secs(1)).expect("Missing events"));
+    }
+    let marked = |channel| events.iter().filter(|&&(c, event)| c == channel && event == AoldaqEvent::Marker).count();
+    assert_eq!((marked(0), marked(1)), (N / 100, N / 100));
+
+    let mut markers = vec![AoldaqMarker::default(); N];
+    assert_eq!(aoldaq.get_markers(1, &mut markers), N / 100);
+    assert!(markers[..N / 100].iter().enumerate().all(|(i, m)| m.point == 100 * i as u64 && m.flags == 0x8000));
+
+    let mut buf = vec![0u32; N];
+    assert_eq!(aoldaq.get_data_into(0, &mut buf[..]), N);
+    assert!(buf.iter().enumerate().all(|(i, &point)| point == i as u32));
+    assert_eq!(aoldaq.get_markers(0, &mut markers), 0);
+
+    assert_eq!(aoldaq.get_data_into(1, &mut buf[..]), N);
+    assert!(buf.iter().enumerate().all(|(i, &point)| point == (3 * i as u32) & 0xffff));
+
+    // One device FIFO read for both channels
+    assert_eq!(aoldaq.get_stats(0).acquired, aoldaq.get_stats(1).acquired);
+}
+
+#[test]
+fn test_marker_sync() {
+    use aoldaq::FrameOptions;
+
+    // Starts in the middle of a frame
+    let device = PackedDevice { counter: AtomicU32::new(37) };
+
+    let mut aoldaq = AoldaqBuilder::new(AoldaqMode::Custom)
+        .block_size(BLOCK_SIZE)
+        .fifo_capacity(1 << 16)
+        .set("unpack.fields", "0:0-14").unwrap()
+        .set("unpack.markers", "0x8000").unwrap()
+        .frame(FrameOptions {
+            width: 10,
+            height: 10,
+            sync_mask: 0x8000,
+            sync_value: 0x8000,
+            sync_markers: true,
+            ..FrameOptions::default()
+        })
+        .device(Box::new(device))
+        .build()
+        .unwrap();
+
+    aoldaq.start_finite(2000);
+    assert!(aoldaq.wait_done(Duration::from_secs(10)));
+
+    let mut frame = vec![0u32; 100];
+    for number in 0..19 {
+        let info = aoldaq.get_frame_into(0, &mut frame, Duration::from_secs(1)).unwrap();
+        assert_eq!((info.number, info.resyncs), (number, 0));
+        assert_eq!(frame[0], 100 * (number as u32 + 1));
+    }
+
+    // The points after the last marker don't make a whole frame
+    assert!(aoldaq.get_frame_into(0, &mut frame, Duration::from_millis(10)).is_none());
+}
+
+#[test]
+fn test_finite_frames() {
+    use aoldaq::FrameOptions;
+
+    // Without markers, the frames are counted in points
+    let mut aoldaq = AoldaqBuilder::new(AoldaqMode::Counter)
+        .block_size(BLOCK_SIZE)
+        .fifo_capacity(1 << 16)
+        .frame(FrameOptions { width: 10, height: 10, ..FrameOptions::default() })
+        .build()
+        .unwrap();
+
+    aoldaq.start_finite_frames(3).unwrap();
+    assert!(aoldaq.wait_done(Duration::from_secs(10)));
+
+    let mut frame = vec![0u32; 100];
+    for number in 0..3 {
+        aoldaq.get_frame_into(0, &mut frame, Duration::from_secs(1)).unwrap();
+        assert_eq!(frame[0], 100 * number);
+    }
+    assert!(aoldaq.get_frame_into(0, &mut frame, Duration::from_millis(10)).is_none());
+
+    // With markers, once they are read
+    let mut aoldaq = AoldaqBuilder::new(AoldaqMode::Custom)
+        .block_size(BLOCK_SIZE)
+        .fifo_capacity(1 << 16)
+        .set("unpack.fields", "0:0-14").unwrap()
+        .set("unpack.markers", "0x8000").unwrap()
+        .frame(FrameOptions {
+            width: 10,
+            height: 10,
+            sync_mask: 0x8000,
+            sync_value: 0x8000,
+            sync_markers: true,
+            ..FrameOptions::default()
+        })
+        .device(Box::new(PackedDevice { counter: AtomicU32::new(37) }))
+        .build()
+        .unwrap();
+
+    aoldaq.start_finite_frames(5).unwrap();
+
+    for number in 0..5 {
+        assert!(!aoldaq.is_done());
+        let info = aoldaq.get_frame_into(0, &mut frame, Duration::from_secs(1)).unwrap();
+        assert_eq!(info.number, number);
+    }
+
+    assert!(aoldaq.is_done());
+    assert!(aoldaq.get_frame_into(0, &mut frame, Duration::from_millis(10)).is_none());
+
+    // Without frame settings, there are no frames to count
+    assert!(counting(1, 1 << 16).start_finite_frames(1).is_err());
+}
+
+#[test]
+fn test_averaged_frames() {
+    use aoldaq::{ AoldaqAverage, FrameOptions };
+
+    let record = std::env::temp_dir().join(format!("aoldaq-frames-{}", std::process::id()));
+    std::fs::create_dir_all(&record).unwrap();
+
+    let mut aoldaq = AoldaqBuilder::new(AoldaqMode::Counter)
+        .block_size(BLOCK_SIZE)
+        .fifo_capacity(1 << 16)
+        .frame(FrameOptions {
+            width: 100,
+            height: 20,
+            average: AoldaqAverage::Mean,
+            average_count: 4,
+            record: Some(record.clone()),
+            ..FrameOptions::default()
+        })
+        .build()
+        .unwrap();
+
+    aoldaq.start_finite(8 * 2000);
+    assert!(aoldaq.wait_done(Duration::from_secs(10)));
+
+    // The mean of frames 0 to 3 is frame 1.5
+    let mut frame = vec![0u32; 2000];
+    let info = aoldaq.get_frame_into(0, &mut frame, Duration::from_secs(1)).unwrap();
+    assert_eq!((info.number, info.averaged), (0, 4));
+    assert!(frame.iter().enumerate().all(|(i, &p)| p == (3000 + i) as u32));
+
+    aoldaq.get_frame_into(0, &mut frame, Duration::from_secs(1)).unwrap();
+    assert!(aoldaq.get_frame_into(0, &mut frame, Duration::from_millis(10)).is_none());
+
+    // Every raw frame was recorded once the instance is gone
+    drop(aoldaq);
+    let raw = std::fs::read(record.join("channel0.bin")).unwrap();
+    std::fs::remove_dir_all(&record).unwrap();
+    assert_eq!(raw.len(), 8 * 2000 * 4);
+    assert_eq!(&raw[raw.len() - 4..], &(8 * 2000 - 1u32).to_le_bytes());
+}
+
+/// Sends the time-tagged events of a 4x2 pixel scan, each a U64 word as two
+/// points, low half first. Pixel `(x, y)` of every frame gets `x + 1` photons,
+/// each `1 + y` time units after a laser sync.
+struct ScanDevice {
+    points: AtomicU32,
+    frame: Vec<u64>,
+}
+
+impl ScanDevice {
+    const FRAME_TIME: u64 = 300;
+
+    fn new() -> ScanDevice {
+        let event = |channel: u64, time: u64| channel << 56 | time;
+        let mut frame = vec![event(2, 0)];
+
+        for y in 0..2 {
+            frame.push(event(1, 100 * y));
+
+            for x in 0..4 {
+                // Events come in order, within the 10 time units of the pixel
+                for k in 0..=x {
+                    let time = 100 * y + 10 * x + 2 * k;
+                    frame.push(event(3, time));
+                    frame.push(event(0, time + 1 + y));
+                }
+            }
+        }
+
+        ScanDevice { points: AtomicU32::new(0), frame }
+    }
+}
+
+impl Device for ScanDevice {
+    fn read_into(&self, _channel: usize, buf: &mut [u32]) -> Result<usize, i32> {
+        let start = self.points.fetch_add(buf.len() as u32, Ordering::SeqCst) as usize;
+
+        for (i, point) in buf.iter_mut().enumerate() {
+            let n = (start + i) / 2;
+            let (frame, event) = (n / self.frame.len(), n % self.frame.len());
+            let word = self.frame[event] + frame as u64 * Self::FRAME_TIME;
+            *point = if (start + i) % 2 == 0 { word as u32 } else { (word >> 32) as u32 };
+        }
+
+        std::thread::sleep(Duration::from_micros(50));
+        Ok(buf.len())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[test]
+fn test_photon_frames() {
+    use aoldaq::{ AoldaqOverflow, AoldaqTriggerMode, FrameOptions, PhotonOptions };
+
+    let builder = || AoldaqBuilder::new(AoldaqMode::Custom)
+        .block_size(BLOCK_SIZE)
+        .fifo_capacity(1 << 16)
+        .frame(FrameOptions {
+            width: 4,
+            height: 2,
+            photon: Some(PhotonOptions {
+                pixel_time: 10,
+                line_channel: 1,
+                frame_channel: Some(2),
+                sync_channel: Some(3),
+                lifetime_bins: 4,
+                ..PhotonOptions::default()
+            }),
+            ..FrameOptions::default()
+        })
+        .device(Box::new(ScanDevice::new()));
+
+    let mut aoldaq = builder().build().unwrap();
+    aoldaq.start_finite_frames(3).unwrap();
+
+    let mut frame = vec![0u32; 8];
+    let mut lifetime = vec![0u32; 8 * 4];
+
+    for number in 0..3 {
+        let info = aoldaq.get_frame_into(0, &mut frame, Duration::from_secs(1)).unwrap();
+        assert_eq!(info.number, number);
+        assert_eq!(frame, vec![1, 2, 3, 4, 1, 2, 3, 4]);
+
+        assert_eq!(aoldaq.get_lifetime_into(0, &mut lifetime), 8 * 4);
+        for (pixel, histogram) in lifetime.chunks(4).enumerate() {
+            let (x, y) = (pixel % 4, pixel / 4);
+            let expected = (0..4).map(|bin| if bin == 1 + y { x as u32 + 1 } else { 0 }).collect::<Vec<_>>();
+            assert_eq!(histogram, &expected[..]);
+        }
+    }
+
+    assert!(aoldaq.is_done());
+    assert!(aoldaq.get_frame_into(0, &mut frame, Duration::from_millis(10)).is_none());
+
+    // The points must stay halves of event words, and the lifetimes aren't
+    // corrected for the scan direction
+    assert!(builder().set("binning", "mean:2").unwrap().build().is_err());
+    assert!(builder().set("unpack.fields", "0:0-15").unwrap().build().is_err());
+    assert!(builder().set("frame.bidirectional", "true").unwrap().build().is_err());
+
+    // Nothing cuts an event in two
+    assert!(builder().block_size(BLOCK_SIZE + 1).build().is_err());
+    assert!(builder().pre_trigger(3).build().is_err());
+    assert!(builder().trigger(AoldaqTriggerMode::Marker).build().is_err());
+
+    let aoldaq = builder().build().unwrap();
+    let reader = aoldaq.add_reader(0, 1001, AoldaqOverflow::Drop).unwrap();
+    aoldaq.start_finite(1001);
+    assert!(aoldaq.wait_done(Duration::from_secs(1)));
+    assert_eq!(aoldaq.get_fifo_size(0), 1002);
+    assert_eq!((reader.get_fifo_size(), reader.get_dropped()), (1000, 2));
+}
+
+/// The mock NiFpga FIFOs count as well, so the same checks apply to the whole
+/// NiFpga device.
+#[cfg(feature = "mock-nifpga")]
+#[test]
+fn test_nifpga_start_stop() {
+    use aoldaq::NiFpgaOptions;
+
+    let mut aoldaq = AoldaqBuilder::new(AoldaqMode::NiFpga)
+        .channels(2)
+        .block_size(BLOCK_SIZE)
+        .fifo_capacity(1 << 16)
+        .nifpga(NiFpgaOptions::default())
+        .build()
+        .unwrap();
+
+    let mut next = [0u64; 2];
+    let mut read = [0usize; 2];
+
+    for _ in 0..5 {
+        aoldaq.start();
+        std::thread::sleep(Duration::from_millis(10));
+        aoldaq.stop();
+
+        for channel in 0..2 {
+            read[channel] += check_blocks(&mut aoldaq, channel, &mut next[channel]);
+        }
+    }
+
+    assert!(read.iter().all(|&n| n > 0), "Read {:?}", read);
+    assert!(aoldaq.get_nifpga_session().is_some());
+}